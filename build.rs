@@ -2,27 +2,43 @@ use std::env;
 use std::path::Path;
 use std::process::Command;
 use std::fs;
+use std::time::SystemTime;
 
 fn main() {
     let out_dir = env::var("OUT_DIR").unwrap();
     let frontend_path = Path::new("frontend");
     let dist_path = frontend_path.join("dist");
+
+    // Tell cargo to rerun this script if frontend files change, directories are scanned recursively
+    for source in SOURCES {
+        println!("cargo:rerun-if-changed=frontend/{}", source);
+    }
+    println!("cargo:rerun-if-changed=frontend/dist");
     
     // Check if frontend directory exists
     if frontend_path.exists() {
         // Create dist directory in OUT_DIR if it doesn't exist
         fs::create_dir_all(Path::new(&out_dir).join("frontend/dist")).expect("Failed to create dist directory");
         
-        // Only build if dist doesn't exist or is empty
-        if !dist_path.exists() || fs::read_dir(&dist_path).unwrap().next().is_none() {
+        let missing = !dist_path.exists() || fs::read_dir(&dist_path).unwrap().next().is_none();
+        // dist is committed, so it has to be rebuilt whenever the sources are changed after it
+        let stale = !missing && SOURCES.iter()
+            .filter_map(|source| newest(&frontend_path.join(source)))
+            .max() > newest(&dist_path);
+
+        if missing || stale {
             println!("cargo:warning=Building frontend...");
             
             // Install dependencies
-            let status = Command::new("yarn")
-                .current_dir(frontend_path)
-                .arg("install")
-                .status()
-                .expect("Failed to run yarn install");
+            let status = match Command::new("yarn").current_dir(frontend_path).arg("install").status() {
+                Ok(status) => status,
+                // A stale build still works, only a missing one can't be embedded
+                Err(e) if stale => {
+                    println!("cargo:warning=frontend/dist is older than its sources and yarn couldn't be run to rebuild it: {}", e);
+                    return;
+                }
+                Err(e) => panic!("Failed to run yarn install: {}", e),
+            };
             
             if !status.success() {
                 panic!("Yarn install failed");
@@ -40,10 +56,20 @@ fn main() {
             }
         }
     }
-    
-    // Tell cargo to rerun this script if frontend files change
-    println!("cargo:rerun-if-changed=frontend/src/*");
-    println!("cargo:rerun-if-changed=frontend/public/*");
-    println!("cargo:rerun-if-changed=frontend/index.html");
-    println!("cargo:rerun-if-changed=frontend/package.json");
+
+}
+
+// Inputs of the frontend build, relative to frontend/
+const SOURCES: [&str; 4] = ["src", "public", "index.html", "package.json"];
+
+// Latest modification time of a file or anything in a directory
+fn newest(path: &Path) -> Option<SystemTime> {
+    let metadata = fs::metadata(path).ok()?;
+    if !metadata.is_dir() {
+        return metadata.modified().ok();
+    }
+    fs::read_dir(path).ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| newest(&entry.path()))
+        .max()
 }
\ No newline at end of file
@@ -33,26 +33,128 @@ interface ToolResult {
   content: string,
 }
 
+interface Thinking {
+  type: "thinking",
+  thinking: string,
+  signature?: string,
+}
+
+interface RedactedThinking {
+  type: "redacted_thinking",
+  data: string,
+}
+
+interface Reasoning {
+  type: "reasoning",
+  id: string,
+  summary: string,
+}
+
+interface Image {
+  type: "image",
+  source: {
+    type: "base64",
+    media_type: string,
+    data: string,
+  },
+}
+
 interface Message {
   role: "user" | "assistant | tool",
-  content: (Text | ToolUse | ToolResult)[],
+  content: (Text | ToolUse | ToolResult | Thinking | RedactedThinking | Reasoning | Image)[],
+  model?: string,
+}
+
+interface RetryStatus {
+  attempt: number;
+  max_attempts: number;
+  delay_ms: number;
+  error: string;
+}
+
+interface ApiError {
+  error: string;
+  code: string;
+  retryable: boolean;
+  retry_after_ms?: number;
+}
+
+// Turns an error response from the server into a message for the user
+const describeError = (data: ApiError): string => {
+  switch (data.code) {
+    case "missing_api_key":
+    case "provider_unauthorized":
+      return `${data.error}\n\nCheck api_key in pprog.toml or the provider's API key environment variable.`;
+    case "context_budget_exceeded":
+      return `${data.error}\n\nShorten the message or raise max_context in pprog.toml.`;
+    default:
+      if (data.retryable) {
+        const wait = data.retry_after_ms ? ` in ${Math.ceil(data.retry_after_ms / 1000)}s` : "";
+        return `${data.error}\n\nThis is temporary, try sending the message again${wait}.`;
+      }
+      return data.error;
+  }
+};
+
+interface DiffLine {
+  type: 'context' | 'added' | 'removed';
+  content: string;
+  old_line: number | null;
+  new_line: number | null;
+}
+
+interface Hunk {
+  old_start: number;
+  old_lines: number;
+  new_start: number;
+  new_lines: number;
+  header: string;
+  lines: DiffLine[];
+}
+
+interface FileDiff {
+  path: string;
+  old_path?: string;
+  status: 'added' | 'modified' | 'deleted' | 'renamed';
+  untracked: boolean;
+  binary: boolean;
+  hunks: Hunk[];
+}
+
+interface ModelSpec {
+  name: string;
+  provider: string;
+  context_window: number;
+  max_output_tokens: number;
+  pricing?: {
+    input: number;
+    output: number;
+  };
 }
 
-interface FileChange {
-  filename: string;
-  changes: {
-    type: 'added' | 'removed';
-    content: string;
-    lineNumber?: number;
-  }[];
+function readImageFile(file: File): Promise<Image> {
+  return new Promise((resolve, reject) => {
+    const reader = new FileReader();
+    reader.onload = () => {
+      // Data URL is "data:<media type>;base64,<data>"
+      const [header, data] = (reader.result as string).split(',');
+      resolve({
+        type: "image",
+        source: {
+          type: "base64",
+          media_type: header.replace('data:', '').replace(';base64', ''),
+          data,
+        },
+      });
+    };
+    reader.onerror = () => reject(reader.error);
+    reader.readAsDataURL(file);
+  });
 }
 
-// Language Model options
-const LANGUAGE_MODELS = [
-  { value: 'gpt-4o', label: 'gpt-4o' },
-  { value: 'claude-3-5-sonnet-latest', label: 'claude-3-5-sonnet-latest' },
-  { value: 'claude-3-5-haiku-latest', label: 'claude-3-5-haiku-latest' },
-];
+function imageUrl(image: Image): string {
+  return `data:${image.source.media_type};base64,${image.source.data}`;
+}
 
 function renderTextWithCodeBlocks(text: string) {
   const codeBlockRegex = /```(\w+)?\n([\s\S]*?)```/g;
@@ -107,60 +209,23 @@ function renderTextWithCodeBlocks(text: string) {
   });
 }
 
-function parseDiff(diffContent: string): FileChange[] {
-  const files: FileChange[] = [];
-  let currentFile: FileChange | null = null;
-  
-  const lines = diffContent.split('\n');
-  let lineNumber = 0;
-
-  for (const line of lines) {
-    if (line.startsWith('diff --git')) {
-      if (currentFile) {
-        files.push(currentFile);
-      }
-      const filename = line.split(' b/')[1];
-      currentFile = {
-        filename,
-        changes: []
-      };
-    }
-    else if (line.startsWith('+') && !line.startsWith('+++')) {
-      currentFile?.changes.push({
-        type: 'added',
-        content: line.substring(1),
-        lineNumber: ++lineNumber
-      });
-    }
-    else if (line.startsWith('-') && !line.startsWith('---')) {
-      currentFile?.changes.push({
-        type: 'removed',
-        content: line.substring(1),
-        lineNumber: ++lineNumber
-      });
-    }
-  }
-
-  if (currentFile) {
-    files.push(currentFile);
-  }
-
-  return files;
-}
-
 const App: React.FC = () => {
   const [messages, setMessages] = useState<Message[]>([]);
   const [inputMessage, setInputMessage] = useState('');
+  const [pendingImages, setPendingImages] = useState<Image[]>([]);
   const [isProcessing, setIsProcessing] = useState(false);
   const [showFab, setShowFab] = useState(false);
   const [showModal, setShowModal] = useState(false);
-  const [diffFiles, setDiffFiles] = useState<FileChange[]>([]);
+  const [diffFiles, setDiffFiles] = useState<FileDiff[]>([]);
   const [recursiveCallCount, setRecursiveCallCount] = useState(0);
   const [initialLoadComplete, setInitialLoadComplete] = useState(false);
   const [showSettings, setShowSettings] = useState(false);
-  const [selectedModel, setSelectedModel] = useState(LANGUAGE_MODELS[0].value);
+  const [models, setModels] = useState<ModelSpec[]>([]);
+  const [selectedModel, setSelectedModel] = useState('');
+  const [retryStatus, setRetryStatus] = useState<RetryStatus | null>(null);
   const messagesEndRef = useRef<null | HTMLDivElement>(null);
   const textareaRef = useRef<HTMLTextAreaElement>(null);
+  const fileInputRef = useRef<HTMLInputElement>(null);
 
   const scrollToBottom = () => {
     messagesEndRef.current?.scrollIntoView({ behavior: "smooth" });
@@ -183,28 +248,61 @@ const App: React.FC = () => {
     scrollToBottom();
   }, [messages]);
 
+  const fetchDiff = async () => {
+    try {
+      const response = await fetch(`${window.SERVER_URL}/diff?staged=false`);
+      if (!response.ok) {
+        throw new Error('Failed to fetch diff');
+      }
+      const data = await response.json();
+      setDiffFiles(data.files);
+    } catch (error) {
+      console.error('Error fetching diff:', error);
+    }
+  };
+
   useEffect(() => {
     if (showFab) {
-      const fetchAndParseDiff = async () => {
-        try {
-          const response = await fetch(`${window.SERVER_URL}/diff`);
-          if (!response.ok) {
-            throw new Error('Failed to fetch diff');
-          }
-          const data = await response.json();
-          if (data.diff) {
-            const parsedFiles = parseDiff(data.diff);
-            setDiffFiles(parsedFiles);
-          }
-        } catch (error) {
-          console.error('Error fetching diff:', error);
-        }
-      };
-
-      fetchAndParseDiff();
+      fetchDiff();
     }
   }, [showFab]);
 
+  // Accept stages the file or hunk so it drops out of the review, reject reverts it
+  const reviewChange = async (action: 'accept' | 'reject', path: string, hunks: [number, Hunk][] = []) => {
+    try {
+      const response = await fetch(`${window.SERVER_URL}/diff/${action}`, {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        // The start lines let the server refuse hunks that moved since the diff was fetched
+        body: JSON.stringify({
+          path,
+          hunks: hunks.map(([index, hunk]) => ({ index, old_start: hunk.old_start, new_start: hunk.new_start })),
+        }),
+      });
+      if (!response.ok) {
+        const error = await response.json();
+        throw new Error(error.error);
+      }
+    } catch (error) {
+      console.error(`Error trying to ${action} ${path}:`, error);
+    }
+    fetchDiff();
+  };
+
+  const fetchModels = async () => {
+    try {
+      const response = await fetch(`${window.SERVER_URL}/models`);
+      if (!response.ok) {
+        throw new Error('Failed to fetch models');
+      }
+      const data = await response.json();
+      setModels(data.models);
+      setSelectedModel(data.current);
+    } catch (error) {
+      console.error('Error fetching models:', error);
+    }
+  };
+
   useEffect(() => {
     const fetchMessages = async () => {
       try {
@@ -226,9 +324,33 @@ const App: React.FC = () => {
     }
 
     fetchMessages();
+    fetchModels();
   }, []);
 
+  // Poll for retry progress while waiting on the model
+  useEffect(() => {
+    if (!isProcessing) {
+      setRetryStatus(null);
+      return;
+    }
+
+    const interval = setInterval(async () => {
+      try {
+        const response = await fetch(`${window.SERVER_URL}/status`);
+        if (response.ok) {
+          const data = await response.json();
+          setRetryStatus(data.retry);
+        }
+      } catch (error) {
+        console.error('Error fetching status:', error);
+      }
+    }, 1000);
+
+    return () => clearInterval(interval);
+  }, [isProcessing]);
+
   const handleDiffCheck = () => {
+    fetchDiff();
     setShowModal(true);
   };
 
@@ -242,6 +364,7 @@ const App: React.FC = () => {
 
   const handleModelChange = async (e: React.ChangeEvent<HTMLSelectElement>) => {
     const newModel = e.target.value;
+    const previousModel = selectedModel;
     setSelectedModel(newModel);
     
     try {
@@ -257,14 +380,43 @@ const App: React.FC = () => {
         throw new Error('Failed to update model configuration');
       }
 
-      // Optionally, handle successful configuration update
+      const data = await response.json();
+      if (!data.acknowledged) {
+        throw new Error(`Unable to switch to ${newModel}`);
+      }
+
       console.log('Model configuration updated successfully');
     } catch (error) {
       console.error('Error updating model configuration:', error);
-      // Optionally, show an error message to the user
+      setSelectedModel(previousModel);
+      alert(error);
+    }
+  };
+
+  const addImageFiles = async (files: File[]) => {
+    const images = await Promise.all(
+      files.filter(file => file.type.startsWith('image/')).map(readImageFile)
+    );
+    setPendingImages(prev => [...prev, ...images]);
+  };
+
+  const handlePaste = (e: React.ClipboardEvent<HTMLTextAreaElement>) => {
+    const files = Array.from(e.clipboardData.files);
+    if (files.some(file => file.type.startsWith('image/'))) {
+      e.preventDefault();
+      addImageFiles(files);
     }
   };
 
+  const handleFileSelect = (e: React.ChangeEvent<HTMLInputElement>) => {
+    addImageFiles(Array.from(e.target.files ?? []));
+    e.target.value = '';
+  };
+
+  const removePendingImage = (index: number) => {
+    setPendingImages(prev => prev.filter((_, i) => i !== index));
+  };
+
   const handleKeyPress = (e: React.KeyboardEvent<HTMLTextAreaElement>) => {
     if (e.key === 'Enter' && !e.shiftKey) {
       e.preventDefault();
@@ -276,18 +428,20 @@ const App: React.FC = () => {
 
   const handleEnterMessage = async (_e: any) => {
     try {
-      if (inputMessage.trim() === '') return;
+      if (inputMessage.trim() === '' && pendingImages.length === 0) return;
       setShowFab(false);
       setIsProcessing(true);
       
       const userMessage: Message = {
         role: "user",
         content: [
-          { type: "text", "text": inputMessage.trim() }
+          { type: "text", "text": inputMessage.trim() || "See attached image." },
+          ...pendingImages,
         ]
       };
       setMessages(prevMessages => [...prevMessages, userMessage]);
       setInputMessage('');
+      setPendingImages([]);
 
       await handleSendMessage(userMessage);
     } catch (error: any) {
@@ -317,9 +471,9 @@ const App: React.FC = () => {
       });
 
       if (!response.ok) {
-        const data = await response.json();
+        const data: ApiError = await response.json();
         setIsProcessing(false);
-        throw new Error(data.error);
+        throw new Error(describeError(data));
       }
 
       const data = await response.json();
@@ -328,44 +482,44 @@ const App: React.FC = () => {
         ...prev,
         data.message
       ]);
+      // The server may have fallen back to another model
+      fetchModels();
+      // All tool uses go in one request, the server runs independent reads concurrently
+      const tool_uses = data.message.content.filter((contentItem: any) => contentItem.type === "tool_use");
       let tool_result_content_items: ToolResult[] = [];
-      for (let contentItem of data.message.content) {
-        switch(contentItem.type) {
-          case "text":
-            break;
-          case "tool_use":
-            const response = await fetch(`${window.SERVER_URL}/tools`, {
-              method: 'POST',
-              headers: {
-                'Content-Type': 'application/json',
-              },
-              body: JSON.stringify({ ...contentItem })
-            });
-
-            if (!response.ok) {
-              const data = await response.json();
-              setIsProcessing(false);
-              throw new Error(data.error);
-            }
-
-            const data = await response.json();
-            tool_result_content_items.push({
-              type: "tool_result",
-              tool_use_id: data.tool_use_id,
-              content: data.content,
-            });
-            
-            break;
-          case "tool_result":
-            break;
-          default:
-            break;
+      // Images returned by read_image go after all tool results in the same message
+      let tool_images: Image[] = [];
+      if (tool_uses.length > 0) {
+        const response = await fetch(`${window.SERVER_URL}/tools/batch`, {
+          method: 'POST',
+          headers: {
+            'Content-Type': 'application/json',
+          },
+          body: JSON.stringify({ tool_uses })
+        });
+
+        if (!response.ok) {
+          const data: ApiError = await response.json();
+          setIsProcessing(false);
+          throw new Error(describeError(data));
+        }
+
+        const data = await response.json();
+        for (let result of data.results) {
+          tool_result_content_items.push({
+            type: "tool_result",
+            tool_use_id: result.tool_use_id,
+            content: result.content,
+          });
+          if (result.image) {
+            tool_images.push({ type: "image", source: result.image });
+          }
         }
       }
       if (tool_result_content_items.length > 0) {
         await handleSendMessage({
           role: "user",
-          content: tool_result_content_items
+          content: [...tool_result_content_items, ...tool_images]
         });
       }
 
@@ -425,12 +579,45 @@ const App: React.FC = () => {
                     return <div
                       key={`${index}-${contentIndex}`}
                       className={`message ${message.role === "user" ? "user-msg" : "bot-msg"}`}
+                      title={message.model}
                     >
                       {renderTextWithCodeBlocks(contentItem.text)}
                     </div>
                   } else {
                     return null;
                   }
+                case "image":
+                  return <img
+                    key={`${index}-${contentIndex}`}
+                    className={`message image-msg ${message.role === "user" ? "user-msg" : "bot-msg"}`}
+                    src={imageUrl(contentItem)}
+                  />
+                case "thinking":
+                  return <details
+                    key={`${index}-${contentIndex}`}
+                    className="message thinking-msg"
+                  >
+                    <summary>Reasoning</summary>
+                    {contentItem.thinking}
+                  </details>
+                case "redacted_thinking":
+                  return <div
+                    key={`${index}-${contentIndex}`}
+                    className="message thinking-msg"
+                  >
+                    Reasoning redacted by provider
+                  </div>
+                case "reasoning":
+                  if (!contentItem.summary) {
+                    return null;
+                  }
+                  return <details
+                    key={`${index}-${contentIndex}`}
+                    className="message thinking-msg"
+                  >
+                    <summary>Reasoning</summary>
+                    {contentItem.summary}
+                  </details>
                 case "tool_use":
                   return <div
                     key={`${index}-${contentIndex}`}
@@ -457,6 +644,11 @@ const App: React.FC = () => {
               }
             })
         })}
+        {retryStatus && (
+          <div className="message retry-msg">
+            {`Request failed (${retryStatus.error}), retrying in ${Math.ceil(retryStatus.delay_ms / 1000)}s (attempt ${retryStatus.attempt + 1} of ${retryStatus.max_attempts})`}
+          </div>
+        )}
         <div ref={messagesEndRef} />
       </div>
       {showFab && (
@@ -472,21 +664,43 @@ const App: React.FC = () => {
         <div className="modal-overlay" onClick={handleModalClose}>
           <div className="modal-content" onClick={e => e.stopPropagation()}>
             <button className="modal-close" onClick={handleModalClose}>×</button>
-            <h2>Current diff</h2>
+            <h2>Review changes</h2>
             <div className="diff-content">
               {diffFiles && diffFiles.length > 0 ? (
                 diffFiles.map((file, fileIndex) => (
                   <div key={fileIndex} className="file-changes">
-                    <h3 className="file-name">{file.filename}</h3>
+                    <h3 className="file-name">
+                      {file.old_path ? `${file.old_path} → ${file.path}` : file.path}
+                      <span className="file-status">{file.untracked ? 'untracked' : file.status}</span>
+                      <span className="review-actions">
+                        <button onClick={() => reviewChange('accept', file.path)}>Accept</button>
+                        <button onClick={() => reviewChange('reject', file.path)}>Reject</button>
+                      </span>
+                    </h3>
                     <div className="changes-list">
-                      {file.changes.map((change, changeIndex) => (
-                        <div 
-                          key={changeIndex} 
-                          className={`change-line ${change.type}`}
-                        >
-                          <span className="line-number">{change.lineNumber}</span>
-                          <span className="line-content">{change.content}</span>
-                        </div>
+                      {file.binary && <div className="hunk-header">Binary file</div>}
+                      {file.hunks.map((hunk, hunkIndex) => (
+                        <React.Fragment key={hunkIndex}>
+                          <div className="hunk-header">
+                            @@ -{hunk.old_start},{hunk.old_lines} +{hunk.new_start},{hunk.new_lines} @@ {hunk.header}
+                            {file.hunks.length > 1 && (
+                              <span className="review-actions">
+                                <button onClick={() => reviewChange('accept', file.path, [[hunkIndex, hunk]])}>Accept</button>
+                                <button onClick={() => reviewChange('reject', file.path, [[hunkIndex, hunk]])}>Reject</button>
+                              </span>
+                            )}
+                          </div>
+                          {hunk.lines.map((line, lineIndex) => (
+                            <div
+                              key={lineIndex}
+                              className={`change-line ${line.type}`}
+                            >
+                              <span className="line-number">{line.old_line ?? ''}</span>
+                              <span className="line-number">{line.new_line ?? ''}</span>
+                              <span className="line-content">{line.content}</span>
+                            </div>
+                          ))}
+                        </React.Fragment>
                       ))}
                     </div>
                   </div>
@@ -498,22 +712,50 @@ const App: React.FC = () => {
           </div>
         </div>
       )}
+      {pendingImages.length > 0 && (
+        <div className="pending-images">
+          {pendingImages.map((image, index) => (
+            <img
+              key={index}
+              src={imageUrl(image)}
+              title="Click to remove"
+              onClick={() => removePendingImage(index)}
+            />
+          ))}
+        </div>
+      )}
       <div className="chat-input">
         <textarea 
           ref={textareaRef}
           value={inputMessage}
           onChange={(e) => setInputMessage(e.target.value)}
           onKeyDown={handleKeyPress}
+          onPaste={handlePaste}
           placeholder="Type your message..."
           rows={1}
         />
         <button 
           onClick={handleEnterMessage} 
-          disabled={isProcessing || inputMessage.trim() === ''}
+          disabled={isProcessing || (inputMessage.trim() === '' && pendingImages.length === 0)}
           className={`send-button ${isProcessing ? 'processing' : ''}`}
         >
           Send
         </button>
+        <input
+          ref={fileInputRef}
+          type="file"
+          accept="image/png,image/jpeg,image/gif,image/webp"
+          multiple
+          hidden
+          onChange={handleFileSelect}
+        />
+        <button 
+          onClick={() => fileInputRef.current?.click()}
+          disabled={isProcessing}
+          title="Attach image"
+        >
+          📎
+        </button>
         <button 
           onClick={handleClearChat}
           disabled={isProcessing}
@@ -537,9 +779,11 @@ const App: React.FC = () => {
               onChange={handleModelChange}
               className="model-dropdown"
             >
-              {LANGUAGE_MODELS.map((model) => (
-                <option key={model.value} value={model.value}>
-                  {model.label}
+              {models.map((model) => (
+                <option key={model.name} value={model.name}>
+                  {model.pricing
+                    ? `${model.name} ($${model.pricing.input}/$${model.pricing.output} per Mtok)`
+                    : model.name}
                 </option>
               ))}
             </select>
@@ -1,10 +1,20 @@
 use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use log::{info, warn};
 
-use crate::inference::{AnthropicInference, OpenAIInference};
-use crate::{config::ProjectConfig, tree::GitTree};
+use crate::inference::{AnthropicInference, GeminiInference, MockInference, OpenAIInference, OpenAIResponsesInference, PromptedToolInference};
+use crate::checkpoint::Checkpoints;
+use crate::worktree::Worktree;
+use crate::commit::TurnCommit;
+use crate::tools::Tools;
+use crate::{config::{ProjectConfig, Provider, ValidationError}, error::ApiError, models::{ModelRegistry, ModelSpec}, tree::{GitTree, TreeCache}};
 use crate::inference::inference::Inference;
+use crate::inference::http::HttpClient;
+use crate::inference::retry::{with_retry, SharedRetryStatus};
+use crate::inference::types::{InferenceError, InferenceOptions, ModelResponse, SamplingParams};
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 #[serde(tag = "type")]
@@ -18,18 +28,105 @@ pub enum ContentItem {
         id: String,
         name: String,
         input: serde_json::Value,
+        // Gemini's thought signature, has to be sent back with the function call
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<String>,
     },
     #[serde(rename = "tool_result")]
     ToolResult {
         tool_use_id: String,
         content: String,
     },
+    // Signature has to be sent back unchanged with tool results for Anthropic models
+    #[serde(rename = "thinking")]
+    Thinking {
+        thinking: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<String>,
+    },
+    #[serde(rename = "redacted_thinking")]
+    RedactedThinking {
+        data: String,
+    },
+    // OpenAI Responses API reasoning item, sent back by id so the model keeps its reasoning across tool calls
+    #[serde(rename = "reasoning")]
+    Reasoning {
+        id: String,
+        #[serde(default)]
+        summary: String,
+    },
+    #[serde(rename = "image")]
+    Image {
+        source: ImageSource,
+    },
+}
+
+/// Base64 encoded image in the same shape as Anthropic's image source.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(tag = "type", rename = "base64")]
+pub struct ImageSource {
+    pub media_type: String,
+    pub data: String,
+}
+
+impl ImageSource {
+    pub const MEDIA_TYPES: [&'static str; 4] = ["image/png", "image/jpeg", "image/gif", "image/webp"];
+    // Anthropic's per image limit
+    pub const MAX_BYTES: usize = 5 * 1024 * 1024;
+
+    pub fn from_bytes(media_type: &str, bytes: &[u8]) -> Result<Self, anyhow::Error> {
+        let source = ImageSource {
+            media_type: media_type.to_string(),
+            data: BASE64.encode(bytes),
+        };
+        source.validate()?;
+        Ok(source)
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if !Self::MEDIA_TYPES.contains(&self.media_type.as_str()) {
+            return Err(anyhow::anyhow!(
+                "Unsupported image type '{}', expected one of {}",
+                self.media_type,
+                Self::MEDIA_TYPES.join(", ")
+            ));
+        }
+        let bytes = BASE64.decode(&self.data)
+            .map_err(|e| anyhow::anyhow!("Invalid base64 image data: {}", e))?;
+        if bytes.len() > Self::MAX_BYTES {
+            return Err(anyhow::anyhow!(
+                "Image is {} bytes, the limit is {} bytes",
+                bytes.len(),
+                Self::MAX_BYTES
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.media_type, self.data)
+    }
+}
+
+const COMMIT_MESSAGE_PROMPT: &str = "Write a git commit message for the following change. \
+Start with a summary line of at most 72 characters in the imperative mood, followed by a blank line \
+and a short explanation if the change needs one. Reply with the commit message only.";
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WorktreeAction {
+    Merge,
+    Squash,
+    Discard,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub struct CommonMessage {
     pub role: Role,
     pub content: Vec<ContentItem>,
+    /// Model that produced an assistant message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
@@ -59,98 +156,357 @@ pub struct Chat {
     inference: Box<dyn Inference>,
     max_tokens: usize,
     check_enabled: bool,
-    model: String,
+    model: ModelSpec,
+    registry: ModelRegistry,
+    config: ProjectConfig,
+    retry_status: SharedRetryStatus,
+    fallback_index: usize,
+    /// Model and key the session fell back from, tried again at the start of the next turn
+    primary: Option<(ModelSpec, String)>,
+    api_key: String,
+    client: HttpClient,
+    /// Session overrides of the sampling parameters in pprog.toml
+    sampling: SamplingParams,
+    /// Time the session started, names its checkpoints and worktree
+    session: String,
+    repo_root: Option<PathBuf>,
+    /// Directory tools run in, the session's worktree or the project root
+    root: PathBuf,
+    /// Project tree for the system prompt, refreshed at the start of each turn
+    tree: TreeCache,
+    worktree: Option<Worktree>,
+    /// None when checkpoints are disabled or the project isn't a git repository
+    checkpoints: Option<Checkpoints>,
+    /// Message of the turn in progress until its first checkpoint is taken
+    pending_checkpoint: Option<String>,
 }
 
 impl Chat {
-    pub fn new() -> Self {
-        let config = ProjectConfig::load().unwrap_or_default();
-        let inference: Box<dyn Inference> = match config.provider.as_str() {
-            "anthropic" => Box::new(
-                AnthropicInference::new(
-                    config.model.clone(),
-                    config.api_url,
-                    config.api_key,
-                    config.max_output_tokens,
-                )
-            ),
-            "openai" => Box::new(
-                OpenAIInference::new(
-                    config.model.clone(),
-                    config.api_url,
-                    config.api_key,
-                    config.max_output_tokens,
-                )
-            ),
-            _ => Box::new(
-                AnthropicInference::new(
-                    config.model.clone(),
-                    config.api_url,
-                    config.api_key,
-                    config.max_output_tokens,
-                )
-            ),
+    // Backends refuse to send without a key, replayed requests never reach the provider
+    const REPLAY_API_KEY: &'static str = "replay";
+
+    /// Fails when the HTTP client can't be built, for example an unreadable certificate or cassette.
+    pub fn new(config: ProjectConfig) -> Result<Self, String> {
+        let model = config.model_spec();
+        let client = HttpClient::new(&config.http, config.cassette.as_ref())?;
+        let api_key = if config.is_replay() && config.api_key.is_empty() {
+            Self::REPLAY_API_KEY.to_string()
+        } else {
+            config.api_key.clone()
+        };
+        let repo_root = GitTree::get_git_root().ok();
+        let recovered = match &repo_root {
+            Some(repo_root) if config.git.worktree => Worktree::recover(repo_root)
+                .map_err(|e| format!("Unable to clean up the worktrees of earlier sessions: {}", e))?,
+            _ => None,
         };
+        let session = recovered.as_ref().map_or_else(Self::new_session_id, |worktree| worktree.session().to_string());
+        let (root, worktree, checkpoints) = Self::workspace(&config, repo_root.as_deref(), &session, recovered)
+            .map_err(|e| e.to_string())?;
+        let tree = TreeCache::new(&root, &config.tree);
+        let inference = Self::create_inference(
+            model.clone(),
+            api_key.clone(),
+            Self::inference_options(&config, &SamplingParams::default(), &client, &model, config.max_output_tokens),
+        );
 
-        Self {
+        Ok(Self {
             messages: Vec::new(),
             inference,
             max_tokens: config.max_context,
             check_enabled: config.check_enabled,
-            model: config.model,
+            model,
+            registry: config.registry(),
+            api_key,
+            client,
+            config,
+            retry_status: SharedRetryStatus::default(),
+            fallback_index: 0,
+            primary: None,
+            sampling: SamplingParams::default(),
+            session,
+            repo_root,
+            root,
+            tree,
+            worktree,
+            checkpoints,
+            pending_checkpoint: None,
+        })
+    }
+
+    /// Start time in milliseconds, then a random suffix for sessions started in the same one.
+    fn new_session_id() -> String {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        format!("{}-{:04x}", millis, rand::random::<u16>())
+    }
+
+    /// When a session started, for ordering them.  Older sessions are in seconds rather than milliseconds.
+    pub fn session_start(session: &str) -> u128 {
+        session.split('-').next().and_then(|time| time.parse().ok()).unwrap_or_default()
+    }
+
+    /// Root, worktree and checkpoints of a session, carrying on in `recovered` if there is one.
+    /// With worktrees enabled tools never fall back to the project root.
+    fn workspace(config: &ProjectConfig, repo_root: Option<&Path>, session: &str, recovered: Option<Worktree>) -> anyhow::Result<(PathBuf, Option<Worktree>, Option<Checkpoints>)> {
+        let Some(repo_root) = repo_root else {
+            if config.git.worktree {
+                anyhow::bail!("Worktrees are enabled but {} is not in a git repository", std::env::current_dir()?.display());
+            }
+            return Ok((PathBuf::from("."), None, None));
+        };
+        let worktree = match recovered {
+            Some(worktree) => Some(worktree),
+            None if config.git.worktree => Some(Worktree::create(repo_root, session)?),
+            None => None,
+        };
+        let root = worktree.as_ref().map_or_else(|| repo_root.to_path_buf(), |w| w.path().to_path_buf());
+        let checkpoints = config.git.checkpoints.then(|| Checkpoints::new(root.clone(), session.to_string()));
+        Ok((root, worktree, checkpoints))
+    }
+
+    fn create_inference(model: ModelSpec, api_key: String, options: InferenceOptions) -> Box<dyn Inference> {
+        let prompted = model.quirks.prompted_tools;
+        let responses = model.quirks.responses_api;
+        match model.provider {
+            Provider::OpenAI if responses && prompted => Box::new(PromptedToolInference::<OpenAIResponsesInference>::new(model, api_key, options)),
+            Provider::OpenAI if responses => Box::new(OpenAIResponsesInference::new(model, api_key, options)),
+            Provider::Anthropic if prompted => Box::new(PromptedToolInference::<AnthropicInference>::new(model, api_key, options)),
+            Provider::OpenAI if prompted => Box::new(PromptedToolInference::<OpenAIInference>::new(model, api_key, options)),
+            Provider::Gemini if prompted => Box::new(PromptedToolInference::<GeminiInference>::new(model, api_key, options)),
+            Provider::Anthropic => Box::new(AnthropicInference::new(model, api_key, options)),
+            Provider::OpenAI => Box::new(OpenAIInference::new(model, api_key, options)),
+            Provider::Gemini => Box::new(GeminiInference::new(model, api_key, options)),
+            Provider::Mock => Box::new(MockInference::new(model, api_key, options)),
+        }
+    }
+
+    /// Backend for one-off requests such as commit messages.  No tools are offered, it has its own
+    /// Responses API state and doesn't move down the fallback chain.
+    fn create_plain_inference(mut model: ModelSpec, api_key: String, options: InferenceOptions) -> Box<dyn Inference> {
+        // Backends leave native tools out for prompted tool models, without the wrapper there are none
+        model.quirks.prompted_tools = true;
+        match model.provider {
+            Provider::OpenAI if model.quirks.responses_api => Box::new(OpenAIResponsesInference::new(model, api_key, options)),
+            Provider::Anthropic => Box::new(AnthropicInference::new(model, api_key, options)),
+            Provider::OpenAI => Box::new(OpenAIInference::new(model, api_key, options)),
+            Provider::Gemini => Box::new(GeminiInference::new(model, api_key, options)),
+            Provider::Mock => Box::new(MockInference::new(model, api_key, options)),
+        }
+    }
+
+    fn inference_options(
+        config: &ProjectConfig,
+        sampling: &SamplingParams,
+        client: &HttpClient,
+        model: &ModelSpec,
+        max_output_tokens: u32,
+    ) -> InferenceOptions {
+        // Thinking counts towards the output limit so the budget has to leave room for the answer.
+        // Models switched to can have a lower limit than the one validated in pprog.toml.
+        let thinking_budget = Some(config.thinking_budget)
+            .filter(|budget| model.quirks.thinking && *budget > 0);
+        let thinking_budget = match thinking_budget {
+            Some(budget) if budget < ValidationError::MIN_THINKING_BUDGET || budget >= max_output_tokens => {
+                warn!("Thinking disabled for {}, budget {} doesn't fit in {} output tokens", model.name, budget, max_output_tokens);
+                None
+            }
+            budget => budget,
+        };
+        InferenceOptions {
+            max_output_tokens,
+            thinking_budget,
+            sampling: config.sampling.merge(sampling).supported_by(model, thinking_budget.is_some()),
+            client: client.clone(),
+        }
+    }
+
+    pub fn get_model(&self) -> &ModelSpec {
+        &self.model
+    }
+
+    pub fn get_registry(&self) -> &ModelRegistry {
+        &self.registry
+    }
+
+    pub fn retry_status(&self) -> SharedRetryStatus {
+        self.retry_status.clone()
+    }
+
+    /// Model and key to switch to, None when the model is unknown or its key isn't set.
+    pub fn resolve_model(&self, model: &str) -> Option<(ModelSpec, String)> {
+        if self.model.name == model {
+            return Some((self.model.clone(), self.api_key.clone()));
+        }
+        // Configured model keeps the key and limits from pprog.toml
+        if model == self.config.model {
+            return Some((self.config.model_spec(), self.config.api_key.clone()));
+        }
+
+        let Some(spec) = self.registry.get(model) else {
+            warn!("Unknown model requested: {}", model);
+            return None;
+        };
+        match self.resolve_api_key(spec) {
+            Ok(api_key) => Some((spec.clone(), api_key)),
+            Err(e) => {
+                warn!("{}, cannot switch to {}", e, model);
+                None
+            }
         }
     }
 
-    pub fn update_config(&mut self, model: &str) -> bool {
-        if self.model.as_str() == model {
-            return true
+    /// Switches model and replaces the session's sampling overrides.  The overrides are checked
+    /// against the new model first and nothing changes unless they are valid.
+    pub fn update_config(
+        &mut self,
+        model: Option<(ModelSpec, String)>,
+        overrides: Option<SamplingParams>,
+    ) -> Result<SamplingParams, Vec<ValidationError>> {
+        let model = model.filter(|(spec, _)| spec.name != self.model.name);
+        let sampling = self.config.sampling.merge(overrides.as_ref().unwrap_or(&self.sampling));
+        if overrides.is_some() {
+            let spec = model.as_ref().map(|(spec, _)| spec).unwrap_or(&self.model);
+            let errors = ProjectConfig::validate_sampling(&sampling, spec, self.config.thinking_enabled(spec));
+            if !errors.is_empty() {
+                return Err(errors);
+            }
+        }
+
+        if let Some(overrides) = overrides {
+            self.sampling = overrides;
         }
         match model {
-            "gpt-4o" => {
-                let api_key = std::env::var("OPENAI_API_KEY")
-                    .expect("OPENAI_API_KEY environment variable not set");
-                self.inference = Box::new(
-                    OpenAIInference::new(
-                        model.to_string(),
-                        "https://api.openai.com/v1/chat/completions".to_string(),
-                        api_key,
-                        8096
-                    )
-                );
-                self.model = model.to_string();
-                true
-            },
-            "claude-3-5-sonnet-latest" | "claude-3-5-haiku-latest" => {
-                let api_key = std::env::var("ANTHROPIC_API_KEY")
-                    .expect("OPENAI_API_KEY environment variable not set");
-                self.inference = Box::new(
-                    AnthropicInference::new(
-                        model.to_string(),
-                        "https://api.anthropic.com/v1/messages".to_string(),
-                        api_key,
-                        8096
-                    )
-                );
-                self.model = model.to_string();
-                true
-            },
-            _ => {
-                false
+            Some((spec, api_key)) => {
+                self.fallback_index = 0;
+                self.primary = None;
+                self.switch_model(spec, api_key);
+            }
+            None => self.switch_model(self.model.clone(), self.api_key.clone()),
+        }
+        Ok(sampling)
+    }
+
+    /// Models served from the configured endpoint share its key, others read it from the environment.
+    fn resolve_api_key(&self, spec: &ModelSpec) -> Result<String, String> {
+        if spec.provider == Provider::Mock {
+            return Ok(String::new());
+        }
+        if self.config.is_replay() {
+            return Ok(Self::REPLAY_API_KEY.to_string());
+        }
+        let configured = self.config.model_spec();
+        if spec.provider == configured.provider && spec.api_url == configured.api_url {
+            return Ok(self.config.api_key.clone());
+        }
+
+        let api_key_env = spec.api_key_env.clone()
+            .unwrap_or_else(|| spec.provider.api_key_env().to_string());
+        std::env::var(&api_key_env)
+            .map_err(|_| format!("{} environment variable not set", api_key_env))
+    }
+
+    fn switch_model(&mut self, spec: ModelSpec, api_key: String) {
+        self.max_tokens = if spec.name == self.config.model {
+            self.config.max_context
+        } else {
+            self.config.max_context.min(spec.context_window)
+        };
+        let options = self.options_for(&spec);
+        self.inference = Self::create_inference(spec.clone(), api_key.clone(), options);
+        self.model = spec;
+        self.api_key = api_key;
+    }
+
+    // The configured model keeps the limits from pprog.toml, others can't go above them
+    fn options_for(&self, spec: &ModelSpec) -> InferenceOptions {
+        let max_output_tokens = if spec.name == self.config.model {
+            self.config.max_output_tokens
+        } else {
+            spec.max_output_tokens.min(self.config.max_output_tokens)
+        };
+        Self::inference_options(&self.config, &self.sampling, &self.client, spec, max_output_tokens)
+    }
+
+    /// Moves to the next usable entry of the fallback chain, returns false when it is exhausted.
+    fn switch_to_fallback(&mut self) -> bool {
+        while self.fallback_index < self.config.fallback.len() {
+            let entry = self.config.fallback[self.fallback_index].clone();
+            self.fallback_index += 1;
+
+            let spec = match self.config.fallback_spec(&entry) {
+                Some(spec) if spec.name != self.model.name => spec,
+                _ => continue,
+            };
+            let api_key = match &entry.api_key {
+                Some(api_key) => api_key.clone(),
+                None => match self.resolve_api_key(&spec) {
+                    Ok(api_key) => api_key,
+                    Err(e) => {
+                        warn!("Skipping fallback model {}: {}", spec.name, e);
+                        continue;
+                    }
+                },
+            };
+
+            warn!("Falling back from {} to {}", self.model.name, spec.name);
+            if self.primary.is_none() {
+                self.primary = Some((self.model.clone(), self.api_key.clone()));
             }
+            self.switch_model(spec, api_key);
+            return true;
         }
+        false
     }
 
-    async fn prune_messages(&mut self) -> Result<(), anyhow::Error> {
+    /// Goes back to the model the session fell back from, with the whole chain available again.
+    fn restore_primary(&mut self) {
+        if let Some((spec, api_key)) = self.primary.take() {
+            info!("Returning from {} to {}", self.model.name, spec.name);
+            self.switch_model(spec, api_key);
+        }
+        self.fallback_index = 0;
+    }
+
+    async fn query_with_retry(&self, system_message: &str) -> Result<ModelResponse, InferenceError> {
+        with_retry(&self.config.retry, &self.retry_status, || {
+            self.inference.query_model(self.messages.clone(), Some(system_message))
+        }).await
+    }
+
+    async fn count_with_retry(&self, system_message: &str) -> Result<u64, InferenceError> {
+        with_retry(&self.config.retry, &self.retry_status, || {
+            self.inference.get_token_count(self.messages.clone(), Some(system_message))
+        }).await
+    }
+
+    async fn prune_messages(&mut self) -> Result<(), ApiError> {
         let system_message = self.get_system_message()?;
         
         while !self.messages.is_empty() {
-            let token_count = self.inference.get_token_count(self.messages.clone(), Some(&system_message)).await?;
+            let token_count = match self.count_with_retry(&system_message).await {
+                Ok(token_count) => token_count,
+                // Counting goes through the provider too, so its outages move down the chain as well
+                Err(e) if e.is_retryable() && self.switch_to_fallback() => continue,
+                Err(e) => return Err(e.into()),
+            };
             println!("Token Count: {:?}", &token_count);
             
             if token_count <= self.max_tokens as u64 {
                 break;
             }
             
+            // The newest message is never pruned, if it doesn't fit on its own it is dropped
+            if self.messages.len() == 1 {
+                self.messages.pop();
+                return Err(ApiError::ContextBudgetExceeded {
+                    tokens: token_count,
+                    max_tokens: self.max_tokens as u64,
+                });
+            }
+
             // Remove the oldest non-system message
             // Find the first non-system message
             if let Some(index) = self.messages.iter()
@@ -164,71 +520,147 @@ impl Chat {
         Ok(())
     }
 
-    pub async fn handle_message(&mut self, message: &CommonMessage) -> Result<CommonMessage, anyhow::Error> {
-        if self.model.as_str() == "deepseek-reasoner" {
-            for content_item in message.content.clone() {
-                match content_item {
-                    ContentItem::ToolResult { tool_use_id, content } => {
-                        self.messages.push(CommonMessage {
-                            role: Role::User,
-                            content: vec![
-                                ContentItem::Text { 
-                                    text: format!(r#"
-Tool name: {}
-Tool use return content: {}
-                                    "#, tool_use_id, content)
-                                }
-                            ],
-                        });
-                    }
-                    _ => self.messages.push(message.clone()),
-                }
-            }
-        } else {
-            self.messages.push(message.clone());
+    pub async fn handle_message(&mut self, message: &CommonMessage) -> Result<CommonMessage, ApiError> {
+        if Self::is_simple_user_text_message(message) {
+            self.restore_primary();
+            self.pending_checkpoint = Some(Self::checkpoint_message(message));
+            // Within a turn the prompt stays the same so it can be cached
+            self.tree.refresh()?;
         }
+        self.messages.push(message.clone());
+        self.prune_messages().await?;
         
-        // After first message, check and prune if needed
-        if self.messages.len() > 1 {
-            self.prune_messages().await?;
+        let return_msg = self.send_messages().await?;
+        self.messages.push(return_msg.clone());
+
+        // The turn is over once the model stops asking for tools
+        let done = !return_msg.content.iter().any(|c| matches!(c, ContentItem::ToolUse { .. }));
+        if done && self.config.git.auto_commit {
+            self.commit_turn().await;
         }
-        
-        let mut return_msg = self.send_messages().await?;
-
-        // Check for plain text tool response in text content for deepseek R1
-        if self.model.as_str() == "deepseek-reasoner" {
-            for content_item in return_msg.content.clone() {
-                match content_item {
-                    ContentItem::Text { text, .. } => {
-                        match Chat::extract_tool_use(text.as_str()) {
-                            Some(tool_use_json) => {
-                                // TODO handle error case of unwrapping values
-                                return_msg.content.push(ContentItem::ToolUse { 
-                                    id: tool_use_json.get("name").unwrap().as_str().unwrap().to_string(), 
-                                    name: tool_use_json.get("name").unwrap().as_str().unwrap().to_string(), 
-                                    input: tool_use_json.get("inputs").unwrap().clone(),
-                                });
-                            },
-                            None => {},
-                        }
-                    },
-                    _ => {},
+        Ok(return_msg)
+    }
+
+    /// Commits the files written during the turn with a message from the model, if the check passes.
+    async fn commit_turn(&self) {
+        if self.repo_root.is_none() {
+            return;
+        }
+        let files = self.written_files();
+        if files.is_empty() {
+            return;
+        }
+        if self.check_enabled {
+            match Tools::run_check(&self.root, &self.config.check_cmd) {
+                Ok(output) if output.status.success() => {},
+                Ok(_) => {
+                    info!("Not committing {}, check failed", files.join(", "));
+                    return;
+                },
+                Err(e) => {
+                    warn!("Not committing {}, unable to run check: {}", files.join(", "), e);
+                    return;
+                },
+            }
+        }
+
+        let commit = match TurnCommit::stage(&self.root, &files) {
+            Ok(Some(commit)) => commit,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Failed to stage {}: {}", files.join(", "), e);
+                return;
+            },
+        };
+        let message = match commit.diff() {
+            Ok(diff) => self.commit_message(&diff).await,
+            Err(e) => {
+                warn!("Failed to diff {}: {}", files.join(", "), e);
+                None
+            },
+        };
+        let message = message.unwrap_or_else(|| self.turn_summary());
+        match commit.commit(&message, &self.config.git) {
+            Ok(id) => info!("Committed {} as {}", commit.files().join(", "), id),
+            Err(e) => warn!("{}", e),
+        }
+    }
+
+    // Paths given to write_file since the user's last message
+    fn written_files(&self) -> Vec<String> {
+        let start = self.messages.iter()
+            .rposition(Self::is_simple_user_text_message)
+            .unwrap_or_default();
+        let mut files = Vec::new();
+        for item in self.messages[start..].iter().flat_map(|message| &message.content) {
+            if let ContentItem::ToolUse { name, input, .. } = item {
+                let path = input.get("path").and_then(|v| v.as_str());
+                if let (true, Some(path)) = (name == "write_file", path) {
+                    if !files.iter().any(|file| file == path) {
+                        files.push(path.to_string());
+                    }
                 }
             }
-        } 
-        
-        self.messages.push(return_msg.clone());
-        Ok(return_msg)
+        }
+        files
     }
 
-    fn get_system_message(&self) -> Result<String, anyhow::Error> {
-        let tree_string = GitTree::get_tree()?;
+    /// Asks the model to describe the staged changes, None if it fails to.
+    async fn commit_message(&self, diff: &str) -> Option<String> {
+        let request = CommonMessage {
+            role: Role::User,
+            content: vec![ContentItem::Text {
+                text: format!("Request: {}\n\n```diff\n{}\n```", self.turn_summary(), diff),
+            }],
+            model: None,
+        };
+        // The session's model rather than a fallback standing in for it this turn
+        let (model, api_key) = self.primary.clone()
+            .unwrap_or_else(|| (self.model.clone(), self.api_key.clone()));
+        let options = self.options_for(&model);
+        let inference = Self::create_plain_inference(model, api_key, options);
+        let response = with_retry(&self.config.retry, &self.retry_status, || {
+            inference.query_model(vec![request.clone()], Some(COMMIT_MESSAGE_PROMPT))
+        }).await;
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Failed to generate commit message: {}", e);
+                return None;
+            },
+        };
+        let text = response.content.iter().find_map(|c| match c {
+            ContentItem::Text { text } => Some(text.as_str()),
+            _ => None,
+        })?;
+        // Models like to wrap the message in a code block
+        let message = text.trim()
+            .trim_start_matches("```text")
+            .trim_matches('`')
+            .trim();
+        (!message.is_empty()).then(|| message.to_string())
+    }
+
+    // Message the user started the turn with, used when the model can't write a commit message
+    fn turn_summary(&self) -> String {
+        self.messages.iter()
+            .rfind(|message| Self::is_simple_user_text_message(message))
+            .map(Self::checkpoint_message)
+            .unwrap_or_else(|| "Update files".to_string())
+    }
+
+    fn get_system_message(&mut self) -> Result<String, anyhow::Error> {
+        let tree_string = self.tree.get()?.to_string();
+        let repo_map = match self.tree.map() {
+            "" => String::new(),
+            map => format!("\nSymbols declared in the most used source files:\n{}", map),
+        };
         if self.check_enabled {
             Ok(format!(r#"
 You are a coding assistant working on a project.
 
 File tree structure:
-{}
+{}{}
 
 The user will give you instructions on how to change the project code.
 
@@ -237,16 +669,19 @@ Always call 'compile_check' tool after completing changes that the user requests
 - Never make any changes outside of the project's root directory.
 - Always read and write entire file contents.  Do not write partial contents of files with other sections commented out. Do not replace sections of code with comments like // Rest of the implementation remains the same...
 - DO NOT commit changes through git unless specified
+- Use git_status, git_log, git_blame and git_show to look at the project's history instead of running git through execute
+- Use repo_map to see the functions, types and classes declared under a directory before reading its files
 
 The user may also questions about the code base.  If a user asks a question DO NOT write to the files but instead read files to answer question."#,
                 &tree_string,
+                &repo_map,
             ))
         } else {
             Ok(format!(r#"
 You are a coding assistant working on a project.
 
 File tree structure:
-{}
+{}{}
 
 The user will give you instructions on how to change the project code.
 
@@ -255,29 +690,42 @@ If any bash commands are needed like installing packages use tool 'execute'.
 - Never make any changes outside of the project's root directory.
 - DO NOT truncate code with comments
 - DO NOT commit changes through git unless specified
+- Use git_status, git_log, git_blame and git_show to look at the project's history instead of running git through execute
+- Use repo_map to see the functions, types and classes declared under a directory before reading its files
 - DO NOT run compile checks
 
 The user may also questions about the code base.  If a user asks a question DO NOT write to the files but instead read files to answer question."#,
                 &tree_string,
+                &repo_map,
             ))
         }
     }
 
+    // User input is text, optionally with pasted images, as opposed to tool results
     fn is_simple_user_text_message(msg: &CommonMessage) -> bool {
         msg.role == Role::User && 
-        msg.content.len() == 1 && 
-        matches!(msg.content[0], ContentItem::Text { text: _ })
+        msg.content.iter().any(|c| matches!(c, ContentItem::Text { .. })) &&
+        msg.content.iter().all(|c| matches!(c, ContentItem::Text { .. } | ContentItem::Image { .. }))
     }
 
-    pub async fn send_messages(&mut self) -> Result<CommonMessage, anyhow::Error> {
+    pub async fn send_messages(&mut self) -> Result<CommonMessage, ApiError> {
         // TODO this should handle nay changes to system message instead of inference struct
         let system_message = self.get_system_message()?;
         
-        match self.inference.query_model(self.messages.clone(), Some(&system_message)).await {
+        let mut result = self.query_with_retry(&system_message).await;
+        while let Err(e) = &result {
+            if !e.is_retryable() || !self.switch_to_fallback() {
+                break;
+            }
+            result = self.query_with_retry(&system_message).await;
+        }
+
+        match result {
             Ok(response) => {
                 let new_msg = CommonMessage {
                     role: Role::Assistant,
-                    content: response.content.clone()
+                    content: response.content.clone(),
+                    model: Some(response.model.clone()),
                 };
                 Ok(new_msg)
             },
@@ -294,7 +742,8 @@ The user may also questions about the code base.  If a user asks a question DO N
                 
                 // If we emptied the vector or didn't find a simple user message, return the error
                 if self.messages.is_empty() || !Self::is_simple_user_text_message(self.messages.last().unwrap()) {
-                    return Err(anyhow::anyhow!("Inference Error: {}. Failed to find valid recovery point.", e));
+                    warn!("Failed to find valid recovery point after inference error");
+                    return Err(e.into());
                 }
                 
                 // Add an empty assistant message
@@ -302,10 +751,12 @@ The user may also questions about the code base.  If a user asks a question DO N
                     role: Role::Assistant,
                     content: vec![ContentItem::Text {
                         text: "Error, conversation interrupted.".to_string(),
-                    }]
+                    }],
+                    model: None,
                 });
                 
-                Err(anyhow::anyhow!("Inference Error: {}. Recovered to last simple user message and added empty assistant response.", e))
+                warn!("Recovered to last simple user message and added empty assistant response");
+                Err(e.into())
             }
         }
     }
@@ -316,13 +767,257 @@ The user may also questions about the code base.  If a user asks a question DO N
 
     pub fn clear(&mut self) {
         self.messages.clear();
+        self.pending_checkpoint = None;
+    }
+
+    pub fn checkpoints(&self) -> Option<&Checkpoints> {
+        self.checkpoints.as_ref()
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Refreshes the project tree at the start of the next turn, for when the watcher isn't running.
+    pub fn files_changed(&self) {
+        self.tree.invalidate();
     }
 
-    fn extract_tool_use(text: &str) -> Option<Value> {
-       text.split("```tool_use\n")
-           .nth(1)?
-           .split("\n```")
-           .next()
-           .and_then(|json_str| serde_json::from_str(json_str).ok())
+    pub fn worktree(&self) -> Option<&Worktree> {
+        self.worktree.as_ref()
+    }
+
+    /// Merges, squashes or discards the session's branch and starts a new session in a fresh worktree.
+    pub fn finish_worktree(&mut self, action: WorktreeAction, message: Option<String>) -> Result<(), ApiError> {
+        let worktree = self.worktree.as_ref()
+            .ok_or_else(|| ApiError::NotFound("Worktree isolation is disabled".to_string()))?;
+        let message = message.unwrap_or_else(|| format!("pprog session {}", self.session));
+        match action {
+            WorktreeAction::Merge => worktree.merge(&message)?,
+            WorktreeAction::Squash => worktree.squash(&message)?,
+            WorktreeAction::Discard => worktree.discard()?,
+        }
+        // The checkpoints were of the worktree that was just removed
+        if let Some(repo_root) = &self.repo_root {
+            if let Err(e) = Checkpoints::new(repo_root.clone(), self.session.clone()).remove_all() {
+                warn!("Failed to remove the checkpoints of session {}: {}", self.session, e);
+            }
+        }
+
+        // The old worktree is gone, tools stay pointed at it rather than the project root until a
+        // new one is created
+        self.worktree = None;
+        self.checkpoints = None;
+        self.pending_checkpoint = None;
+        let session = Self::new_session_id();
+        let (root, worktree, checkpoints) = Self::workspace(&self.config, self.repo_root.as_deref(), &session, None)?;
+        self.session = session;
+        self.tree = TreeCache::new(&root, &self.config.tree);
+        self.root = root;
+        self.worktree = worktree;
+        self.checkpoints = checkpoints;
+        Ok(())
+    }
+
+    /// Snapshots the working tree before the first tool call of the turn that writes files.
+    pub fn checkpoint_turn(&mut self) {
+        let (Some(checkpoints), Some(message)) = (&self.checkpoints, self.pending_checkpoint.take()) else {
+            return;
+        };
+        match checkpoints.create(&message) {
+            Ok(checkpoint) => info!("Created checkpoint {} in session {}", checkpoint.index, checkpoints.session()),
+            Err(e) => warn!("Failed to create checkpoint: {}", e),
+        }
+    }
+
+    // First line of the user's text, enough to recognise the turn in a list
+    fn checkpoint_message(message: &CommonMessage) -> String {
+        let text = message.content.iter().find_map(|c| match c {
+            ContentItem::Text { text } => Some(text.as_str()),
+            _ => None,
+        }).unwrap_or_default();
+        let line = text.lines().next().unwrap_or_default().trim();
+        match line.char_indices().nth(72) {
+            Some((end, _)) => format!("{}...", &line[..end]),
+            None => line.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::config::FallbackEntry;
+    use crate::inference::mock::{mock_config, write_script};
+
+    fn user_text(text: &str) -> CommonMessage {
+        CommonMessage {
+            role: Role::User,
+            content: vec![ContentItem::Text { text: text.to_string() }],
+            model: None,
+        }
+    }
+
+    fn text_of(message: &CommonMessage) -> &str {
+        match &message.content[0] {
+            ContentItem::Text { text } => text,
+            other => panic!("expected text, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tool_use_round_trip() {
+        let (config, _dir) = mock_config(json!([
+            { "content": [
+                { "type": "text", "text": "Reading it." },
+                { "type": "tool_use", "id": "call_1", "name": "read_file", "input": { "path": "Cargo.toml" } }
+            ] },
+            { "content": [{ "type": "text", "text": "It is a Rust project." }] }
+        ]));
+        let mut chat = Chat::new(config).unwrap();
+
+        let response = chat.handle_message(&user_text("What is this?")).await.unwrap();
+        assert!(matches!(&response.content[1], ContentItem::ToolUse { name, .. } if name == "read_file"));
+        assert_eq!(response.model.as_deref(), Some("mock-model"));
+
+        let tool_result = CommonMessage {
+            role: Role::User,
+            content: vec![ContentItem::ToolResult {
+                tool_use_id: "call_1".to_string(),
+                content: "[package]".to_string(),
+            }],
+            model: None,
+        };
+        let response = chat.handle_message(&tool_result).await.unwrap();
+        assert_eq!(text_of(&response), "It is a Rust project.");
+        assert_eq!(chat.get_messages().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_prunes_oldest_messages_over_max_context() {
+        let (mut config, _dir) = mock_config(json!([
+            { "content": [{ "type": "text", "text": "bbbbbbbbbb" }] },
+            { "content": [{ "type": "text", "text": "dddddddddd" }] }
+        ]));
+        config.max_context = 25;
+        let mut chat = Chat::new(config).unwrap();
+
+        chat.handle_message(&user_text("aaaaaaaaaa")).await.unwrap();
+        chat.handle_message(&user_text("cccccccccc")).await.unwrap();
+
+        let texts: Vec<&str> = chat.messages.iter().map(text_of).collect();
+        assert_eq!(texts, vec!["bbbbbbbbbb", "cccccccccc", "dddddddddd"]);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_message_larger_than_max_context() {
+        let (mut config, _dir) = mock_config(json!([
+            { "content": [{ "type": "text", "text": "ok" }] }
+        ]));
+        config.max_context = 5;
+        let mut chat = Chat::new(config).unwrap();
+
+        // Checked for the first message of a conversation too
+        let error = chat.handle_message(&user_text(&"x".repeat(50))).await.unwrap_err();
+        assert_eq!(error.code(), "context_budget_exceeded");
+        assert!(chat.messages.is_empty());
+
+        chat.handle_message(&user_text("hi")).await.unwrap();
+        let error = chat.handle_message(&user_text(&"x".repeat(50))).await.unwrap_err();
+
+        assert_eq!(error.code(), "context_budget_exceeded");
+        assert!(chat.messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_recovers_to_last_user_message_on_error() {
+        let (config, _dir) = mock_config(json!([
+            { "content": [
+                { "type": "tool_use", "id": "call_1", "name": "execute", "input": { "statement": "ls" } }
+            ] },
+            { "error": { "status": 400, "message": "invalid request" } }
+        ]));
+        let mut chat = Chat::new(config).unwrap();
+
+        chat.handle_message(&user_text("List files")).await.unwrap();
+        let tool_result = CommonMessage {
+            role: Role::User,
+            content: vec![ContentItem::ToolResult {
+                tool_use_id: "call_1".to_string(),
+                content: "src".to_string(),
+            }],
+            model: None,
+        };
+        let error = chat.handle_message(&tool_result).await.unwrap_err();
+
+        assert_eq!(error.code(), "provider_rejected");
+        let texts: Vec<&str> = chat.messages.iter().map(text_of).collect();
+        assert_eq!(texts, vec!["List files", "Error, conversation interrupted."]);
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_after_retryable_error() {
+        let (mut config, dir) = mock_config(json!([
+            { "error": { "status": 503, "message": "unavailable" } }
+        ]));
+        config.fallback = vec![FallbackEntry {
+            model: "mock-fallback".to_string(),
+            provider: Some(Provider::Mock),
+            api_url: None,
+            api_key: None,
+            fixture: Some(write_script(&dir, "fallback.json", json!([
+                { "content": [{ "type": "text", "text": "Hello from the fallback." }] }
+            ]))),
+        }];
+        let mut chat = Chat::new(config).unwrap();
+
+        let response = chat.handle_message(&user_text("Hello")).await.unwrap();
+        assert_eq!(response.model.as_deref(), Some("mock-fallback"));
+        assert_eq!(chat.get_model().name, "mock-fallback");
+
+        // The next turn starts on the primary again, which still fails, with the chain available again
+        let response = chat.handle_message(&user_text("Hello again")).await.unwrap();
+        assert_eq!(response.model.as_deref(), Some("mock-fallback"));
+    }
+
+    #[test]
+    fn test_config_update_is_validated_before_it_is_applied() {
+        let (mut config, dir) = mock_config(json!([]));
+        config.sampling.stop = Some(vec!["<END>".to_string()]);
+        let mut strict = config.model_spec();
+        strict.name = "mock-strict".to_string();
+        strict.fixture = Some(write_script(&dir, "strict.json", json!([])));
+        strict.quirks.fixed_sampling = true;
+        config.models = vec![strict];
+        let mut chat = Chat::new(config).unwrap();
+
+        let target = chat.resolve_model("mock-strict");
+        let overrides = SamplingParams { temperature: Some(0.5), ..Default::default() };
+        let errors = chat.update_config(target.clone(), Some(overrides)).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        // Neither the model nor the sampling changed
+        assert_eq!(chat.get_model().name, "mock-model");
+        assert_eq!(chat.sampling, SamplingParams::default());
+
+        let overrides: SamplingParams = serde_json::from_value(json!({ "stop": null })).unwrap();
+        let sampling = chat.update_config(target, Some(overrides)).unwrap();
+        assert_eq!(chat.get_model().name, "mock-strict");
+        assert!(sampling.stop().is_empty());
+    }
+
+    #[test]
+    fn test_thinking_disabled_when_budget_does_not_fit_model() {
+        let config = ProjectConfig { thinking_budget: 2048, ..Default::default() };
+        let model = config.registry().get("claude-3-7-sonnet-latest").unwrap().clone();
+        let client = HttpClient::default();
+        let sampling = SamplingParams::default();
+
+        let options = Chat::inference_options(&config, &sampling, &client, &model, 8192);
+        assert_eq!(options.thinking_budget, Some(2048));
+        // Switched to a model with a smaller output limit
+        assert_eq!(Chat::inference_options(&config, &sampling, &client, &model, 2048).thinking_budget, None);
+        let config = ProjectConfig { thinking_budget: 512, ..config };
+        assert_eq!(Chat::inference_options(&config, &sampling, &client, &model, 8192).thinking_budget, None);
     }
 }
@@ -0,0 +1,389 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use gix::bstr::BString;
+use gix::objs::tree::{Entry, EntryMode};
+use gix::refs::transaction::PreviousValue;
+use gix::ObjectId;
+use serde::Serialize;
+
+use crate::chat::Chat;
+use crate::tree::GitTree;
+
+/*
+    * Snapshots of the working tree taken before the model changes files, so a turn can be undone.
+    *
+    * A checkpoint is a commit of every tracked and untracked, not ignored, file as it is on disk,
+    * with HEAD as its parent.  It is stored under refs/pprog/checkpoints/<session>/<n> where the
+    * session is the time the server started and n counts from 1.  Only objects and these refs are
+    * written, the index, HEAD and branches are left alone.  Checkpoints taken in a session's
+    * worktree record its path in a `pprog-worktree` commit header, so they are never restored
+    * anywhere else.
+    *
+    * Restoring writes the snapshot's files back and removes files created since, ignored files
+    * such as build output are not touched.  The files are checkpointed before they are overwritten,
+    * so a restore can be undone by restoring that checkpoint.
+*/
+pub struct Checkpoints {
+    root: PathBuf,
+    session: String,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub index: usize,
+    pub id: String,
+    /// The user message that started the turn
+    pub message: String,
+    /// Unix time in seconds
+    pub time: i64,
+    /// Worktree the checkpoint was taken in, if not the main one
+    #[serde(skip)]
+    pub worktree: Option<PathBuf>,
+}
+
+const REF_PREFIX: &str = "refs/pprog/checkpoints";
+const WORKTREE_HEADER: &str = "pprog-worktree";
+
+enum Node {
+    File(EntryMode, ObjectId),
+    Dir(BTreeMap<BString, Node>),
+}
+
+impl Checkpoints {
+    /// Start of the message of checkpoints taken by `restore`
+    pub const BEFORE_RESTORE: &'static str = "Before restoring: ";
+
+    pub fn new(root: PathBuf, session: String) -> Self {
+        Checkpoints { root, session }
+    }
+
+    /// The most recent session that still has checkpoints.
+    pub fn latest_session(root: PathBuf) -> Result<Option<Self>> {
+        let repo = gix::open(&root)?;
+        let prefix = format!("{}/", REF_PREFIX);
+        let session = repo.references()?.prefixed(prefix.as_str())?
+            .filter_map(Result::ok)
+            .filter_map(|reference| {
+                let name = reference.name().as_bstr().to_string();
+                name.strip_prefix(&prefix)?.split('/').next().map(String::from)
+            })
+            .max_by_key(|session| Chat::session_start(session));
+        Ok(session.map(|session| Self::new(root, session)))
+    }
+
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    pub fn create(&self, message: &str) -> Result<Checkpoint> {
+        let repo = gix::open(&self.root)?;
+        let tree = self.snapshot(&repo)?;
+        let signature = gix::actor::Signature {
+            name: "pprog".into(),
+            email: "pprog@localhost".into(),
+            time: gix::date::Time::now_local_or_utc(),
+        };
+        let worktree = matches!(repo.kind(), gix::repository::Kind::WorkTree { is_linked: true })
+            .then(|| self.root.clone());
+        let commit = gix::objs::Commit {
+            tree,
+            parents: repo.head_id().ok().map(|id| id.detach()).into_iter().collect(),
+            author: signature.clone(),
+            committer: signature,
+            encoding: None,
+            message: message.into(),
+            extra_headers: worktree.iter()
+                .map(|path| (WORKTREE_HEADER.into(), gix::path::into_bstr(path.as_path()).into_owned()))
+                .collect(),
+        };
+        let id = repo.write_object(&commit)?.detach();
+
+        let index = self.list_in(&repo)?.last().map_or(1, |checkpoint| checkpoint.index + 1);
+        repo.reference(self.ref_name(index), id, PreviousValue::MustNotExist, "pprog checkpoint")?;
+        Ok(Checkpoint {
+            index,
+            id: id.to_string(),
+            message: message.to_string(),
+            time: commit.committer.time.seconds,
+            worktree,
+        })
+    }
+
+    pub fn list(&self) -> Result<Vec<Checkpoint>> {
+        let repo = gix::open(&self.root)?;
+        self.list_in(&repo)
+    }
+
+    /// Makes the working tree match checkpoint `index`, returns `None` if there is no such checkpoint.
+    pub fn restore(&self, index: usize) -> Result<Option<Checkpoint>> {
+        let repo = gix::open(&self.root)?;
+        let Some(checkpoint) = self.list_in(&repo)?.into_iter().find(|c| c.index == index) else {
+            return Ok(None);
+        };
+        self.create(&format!("{}{}", Self::BEFORE_RESTORE, checkpoint.message))?;
+        let tree = repo.find_object(ObjectId::from_hex(checkpoint.id.as_bytes())?)?
+            .try_into_commit()?
+            .tree()?;
+        let files: BTreeMap<String, (EntryMode, ObjectId)> = tree.traverse().breadthfirst.files()?
+            .into_iter()
+            .filter(|entry| matches!(entry.mode, EntryMode::Blob | EntryMode::BlobExecutable | EntryMode::Link))
+            .map(|entry| (entry.filepath.to_string(), (entry.mode, entry.oid)))
+            .collect();
+
+        for path in GitTree::list_files(&self.root)? {
+            if !files.contains_key(&path) {
+                let target = self.root.join(&path);
+                if fs::symlink_metadata(&target).is_ok() {
+                    fs::remove_file(&target)?;
+                    remove_empty_parents(&self.root, &target);
+                }
+            }
+        }
+        for (path, (mode, oid)) in files {
+            let data = repo.find_object(oid)?.detach().data;
+            restore_file(&self.root.join(path), mode, &data)?;
+        }
+        Ok(Some(checkpoint))
+    }
+
+    /// Deletes checkpoint `index`, used by `pprog undo` so the next undo goes further back.
+    pub fn remove(&self, index: usize) -> Result<()> {
+        let repo = gix::open(&self.root)?;
+        repo.find_reference(self.ref_name(index).as_str())?.delete()?;
+        Ok(())
+    }
+
+    /// Deletes all of the session's checkpoints, once its worktree is gone they can't be restored.
+    pub fn remove_all(&self) -> Result<()> {
+        let repo = gix::open(&self.root)?;
+        for checkpoint in self.list_in(&repo)? {
+            repo.find_reference(self.ref_name(checkpoint.index).as_str())?.delete()?;
+        }
+        Ok(())
+    }
+
+    fn ref_name(&self, index: usize) -> String {
+        format!("{}/{}/{}", REF_PREFIX, self.session, index)
+    }
+
+    fn list_in(&self, repo: &gix::Repository) -> Result<Vec<Checkpoint>> {
+        let prefix = format!("{}/{}/", REF_PREFIX, self.session);
+        let mut checkpoints = Vec::new();
+        for reference in repo.references()?.prefixed(prefix.as_str())? {
+            let mut reference = reference.map_err(|e| anyhow!(e))?;
+            let name = reference.name().as_bstr().to_string();
+            let Some(index) = name.rsplit('/').next().and_then(|n| n.parse().ok()) else {
+                continue;
+            };
+            let id = reference.peel_to_id_in_place()?.detach();
+            let commit = repo.find_object(id)?.try_into_commit()?;
+            let worktree = commit.decode()?.extra_headers().find(WORKTREE_HEADER)
+                .map(|path| gix::path::from_bstr(path).into_owned());
+            checkpoints.push(Checkpoint {
+                index,
+                id: id.to_string(),
+                message: commit.message_raw_sloppy().to_string(),
+                time: commit.time()?.seconds,
+                worktree,
+            });
+        }
+        checkpoints.sort_by_key(|checkpoint| checkpoint.index);
+        Ok(checkpoints)
+    }
+
+    fn snapshot(&self, repo: &gix::Repository) -> Result<ObjectId> {
+        let mut root = BTreeMap::new();
+        for path in GitTree::list_files(&self.root)? {
+            let full_path = self.root.join(&path);
+            // Tracked files deleted from disk and submodules are left out
+            let Ok(metadata) = fs::symlink_metadata(&full_path) else { continue };
+            let (mode, data) = if metadata.file_type().is_symlink() {
+                (EntryMode::Link, gix::path::into_bstr(fs::read_link(&full_path)?).to_vec())
+            } else if metadata.is_file() {
+                let mode = if is_executable(&metadata) { EntryMode::BlobExecutable } else { EntryMode::Blob };
+                (mode, fs::read(&full_path)?)
+            } else {
+                continue;
+            };
+            let oid = repo.write_blob(data)?.detach();
+
+            let mut parts: Vec<&str> = path.split('/').collect();
+            let file_name = parts.pop().unwrap_or_default();
+            let mut dir = &mut root;
+            for part in parts {
+                let node = dir.entry(part.into()).or_insert_with(|| Node::Dir(BTreeMap::new()));
+                dir = match node {
+                    Node::Dir(children) => children,
+                    Node::File(..) => return Err(anyhow!("{} is both a file and a directory", part)),
+                };
+            }
+            dir.insert(file_name.into(), Node::File(mode, oid));
+        }
+        write_tree(repo, root)
+    }
+}
+
+fn write_tree(repo: &gix::Repository, nodes: BTreeMap<BString, Node>) -> Result<ObjectId> {
+    let mut entries = Vec::with_capacity(nodes.len());
+    for (filename, node) in nodes {
+        let (mode, oid) = match node {
+            Node::File(mode, oid) => (mode, oid),
+            Node::Dir(children) => (EntryMode::Tree, write_tree(repo, children)?),
+        };
+        entries.push(Entry { mode, filename, oid });
+    }
+    // Git orders directories as if their name ended with a slash
+    entries.sort();
+    Ok(repo.write_object(&gix::objs::Tree { entries })?.detach())
+}
+
+fn restore_file(path: &Path, mode: EntryMode, data: &[u8]) -> Result<()> {
+    let existing = fs::symlink_metadata(path).ok();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if mode == EntryMode::Link {
+        let target = gix::path::from_byte_slice(data);
+        if fs::read_link(path).ok().as_deref() == Some(target) {
+            return Ok(());
+        }
+        if existing.is_some() {
+            fs::remove_file(path)?;
+        }
+        return create_symlink(target, path);
+    }
+
+    if existing.as_ref().is_some_and(|metadata| metadata.file_type().is_symlink()) {
+        fs::remove_file(path)?;
+    }
+    // Unchanged files are not rewritten so build tools don't see them as modified
+    if fs::read(path).ok().as_deref() != Some(data) {
+        fs::write(path, data)?;
+    }
+    set_executable(path, mode == EntryMode::BlobExecutable)
+}
+
+fn remove_empty_parents(root: &Path, path: &Path) {
+    let mut dir = path.parent();
+    while let Some(current) = dir {
+        if current == root || fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path, executable: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    let mode = permissions.mode();
+    let new_mode = if executable { mode | 0o111 } else { mode & !0o111 };
+    if new_mode != mode {
+        permissions.set_mode(new_mode);
+        fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path, _executable: bool) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, path: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, path)?;
+    Ok(())
+}
+
+// Copying the target instead would write a file git still sees as a symlink
+#[cfg(not(unix))]
+fn create_symlink(target: &Path, path: &Path) -> Result<()> {
+    Err(anyhow!("Unable to restore symlink {} to {}, symlinks are not supported on this platform", path.display(), target.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git;
+
+    #[test]
+    fn test_restore_undoes_changes_without_touching_index() {
+        let dir = git::test_repo(&[(".gitignore", "target/\n")]);
+        let root = dir.path().canonicalize().unwrap();
+        fs::write(root.join("main.rs"), "fn main() {}\n").unwrap();
+        git::run(&root, &["add", "main.rs"]).unwrap();
+        fs::create_dir(root.join("target")).unwrap();
+        fs::write(root.join("target/build.log"), "ok").unwrap();
+
+        let checkpoints = Checkpoints::new(root.clone(), "1".to_string());
+        let checkpoint = checkpoints.create("Add a module").unwrap();
+        assert_eq!(checkpoint.index, 1);
+
+        fs::write(root.join("main.rs"), "mod util;\nfn main() {}\n").unwrap();
+        fs::create_dir(root.join("src")).unwrap();
+        fs::write(root.join("src/util.rs"), "pub fn util() {}\n").unwrap();
+        assert_eq!(checkpoints.create("Rename util").unwrap().index, 2);
+        git::run(&root, &["fsck", "--strict", "--no-dangling"]).unwrap();
+
+        let restored = checkpoints.restore(1).unwrap().unwrap();
+        assert_eq!(restored.message, "Add a module");
+        assert_eq!(fs::read_to_string(root.join("main.rs")).unwrap(), "fn main() {}\n");
+        assert!(!root.join("src").exists());
+        assert!(root.join("target/build.log").exists());
+        assert!(checkpoints.restore(4).unwrap().is_none());
+
+        // The files from before the restore were checkpointed
+        let saved = checkpoints.list().unwrap().pop().unwrap();
+        assert_eq!((saved.index, saved.message.as_str()), (3, "Before restoring: Add a module"));
+        checkpoints.restore(3).unwrap().unwrap();
+        assert_eq!(fs::read_to_string(root.join("src/util.rs")).unwrap(), "pub fn util() {}\n");
+        checkpoints.restore(1).unwrap().unwrap();
+
+        // The index still holds what the user staged
+        let staged = git::run(&root, &["diff", "--cached", "--name-only"]).unwrap();
+        assert_eq!(staged.lines().collect::<Vec<_>>(), ["main.rs"]);
+
+        checkpoints.remove(2).unwrap();
+        let latest = Checkpoints::latest_session(root.clone()).unwrap().unwrap();
+        assert_eq!(latest.session(), "1");
+        assert_eq!(latest.list().unwrap().len(), 4);
+
+        Checkpoints::new(root.clone(), "1792334978123-00af".to_string()).create("Add a test").unwrap();
+        Checkpoints::new(root.clone(), "1792334978".to_string()).create("Add a test").unwrap();
+        let latest = Checkpoints::latest_session(root).unwrap().unwrap();
+        assert_eq!(latest.session(), "1792334978123-00af");
+    }
+
+    #[test]
+    fn test_checkpoints_in_a_worktree_record_it() {
+        let dir = git::test_repo(&[("main.rs", "fn main() {}\n")]);
+        let root = dir.path().canonicalize().unwrap();
+        let worktree = root.join("session");
+        git::run(&root, &["worktree", "add", "-q", "-b", "pprog/1", &worktree.to_string_lossy()]).unwrap();
+
+        let checkpoint = Checkpoints::new(worktree.clone(), "1".to_string()).create("Add a module").unwrap();
+        assert_eq!(checkpoint.worktree.as_deref(), Some(worktree.as_path()));
+        // Listed from the main worktree too, since refs are shared
+        let checkpoints = Checkpoints::new(root.clone(), "1".to_string());
+        assert_eq!(checkpoints.list().unwrap(), vec![checkpoint]);
+        git::run(&root, &["fsck", "--strict", "--no-dangling"]).unwrap();
+
+        checkpoints.remove_all().unwrap();
+        assert!(Checkpoints::latest_session(root).unwrap().is_none());
+    }
+}
@@ -0,0 +1,108 @@
+use std::path::Path;
+use anyhow::{anyhow, Result};
+
+use crate::config::GitConfig;
+use crate::git;
+
+/*
+    * Commits the files the model wrote during a turn, leaving anything else the user has changed
+    * or staged alone.
+    *
+    * Files are staged first so the staged diff can be handed to the model to write the commit
+    * message, then committed with `git commit --only` so only those paths end up in the commit.
+    * The author and signing come from the [git] section of pprog.toml, otherwise git's own config
+    * applies.
+*/
+pub struct TurnCommit<'a> {
+    root: &'a Path,
+    files: Vec<String>,
+}
+
+// Enough for the model to describe the change without blowing the context
+const MAX_DIFF_CHARS: usize = 20_000;
+
+impl<'a> TurnCommit<'a> {
+    /// Stages `files`, skipping ones that no longer exist.  None when there is nothing to commit.
+    pub fn stage(root: &'a Path, files: &[String]) -> Result<Option<Self>> {
+        let files: Vec<String> = files.iter()
+            .filter(|file| root.join(file).exists())
+            .cloned()
+            .collect();
+        if files.is_empty() {
+            return Ok(None);
+        }
+        let commit = TurnCommit { root, files };
+        git::run(root, &commit.args(&["add"]))?;
+        if commit.diff()?.trim().is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(commit))
+    }
+
+    /// Staged changes of the turn's files, cut short when very large.
+    pub fn diff(&self) -> Result<String> {
+        let diff = git::run(self.root, &self.args(&["diff", "--cached"]))?;
+        Ok(match diff.char_indices().nth(MAX_DIFF_CHARS) {
+            Some((end, _)) => format!("{}\n[diff truncated]", &diff[..end]),
+            None => diff,
+        })
+    }
+
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    /// Commits the staged files and returns the new commit's id.
+    pub fn commit(&self, message: &str, config: &GitConfig) -> Result<String> {
+        let mut options = vec!["commit", "--only", "-m", message];
+        let author = config.author.as_ref().map(|author| format!("--author={}", author));
+        if let Some(author) = &author {
+            options.push(author);
+        }
+        if config.sign {
+            options.push("-S");
+        }
+        git::run(self.root, &self.args(&options))
+            .map_err(|e| anyhow!("Unable to commit {}: {}", self.files.join(", "), e))?;
+        Ok(git::run(self.root, &["rev-parse", "HEAD"])?.trim().to_string())
+    }
+
+    fn args<'b>(&'b self, options: &[&'b str]) -> Vec<&'b str> {
+        let mut args = options.to_vec();
+        args.push("--");
+        args.extend(self.files.iter().map(String::as_str));
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_commits_only_turn_files_with_author() {
+        let dir = git::test_repo(&[("main.rs", "fn main() {}\n"), ("notes.txt", "todo\n")]);
+        let root = dir.path();
+
+        // The user's own staged change stays out of the turn's commit
+        fs::write(root.join("notes.txt"), "done\n").unwrap();
+        git::run(root, &["add", "notes.txt"]).unwrap();
+        fs::write(root.join("main.rs"), "fn main() { run(); }\n").unwrap();
+        fs::write(root.join("run.rs"), "pub fn run() {}\n").unwrap();
+
+        let files = ["main.rs", "run.rs", "missing.rs"].map(String::from);
+        let commit = TurnCommit::stage(root, &files).unwrap().unwrap();
+        assert_eq!(commit.files(), ["main.rs", "run.rs"]);
+        assert!(commit.diff().unwrap().contains("+pub fn run() {}"));
+
+        let config = GitConfig { author: Some("Reviewer <reviewer@example.com>".to_string()), ..GitConfig::default() };
+        commit.commit("Call run from main", &config).unwrap();
+        let log = git::run(root, &["log", "-1", "--format=%an <%ae>%n%s", "--name-only"]).unwrap();
+        assert_eq!(log.trim(), "Reviewer <reviewer@example.com>\nCall run from main\n\nmain.rs\nrun.rs");
+        assert_eq!(git::run(root, &["status", "--porcelain"]).unwrap(), "M  notes.txt\n");
+
+        // Writing a file with the same contents leaves nothing to commit
+        assert!(TurnCommit::stage(root, &files).unwrap().is_none());
+    }
+}
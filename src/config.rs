@@ -1,10 +1,305 @@
 use log::info;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::fs;
 use std::path::PathBuf;
 
+use crate::inference::types::SamplingParams;
+use crate::inference::http::{header_map, HttpClient};
+use crate::models::{AuthStyle, EndpointConfig, ModelRegistry, ModelSpec};
 use crate::tree::GitTree;
 
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    #[default]
+    Anthropic,
+    OpenAI,
+    Gemini,
+    /// Scripted responses for tests, see MockInference
+    Mock,
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Provider::Anthropic => write!(f, "anthropic"),
+            Provider::OpenAI => write!(f, "openai"),
+            Provider::Gemini => write!(f, "gemini"),
+            Provider::Mock => write!(f, "mock"),
+        }
+    }
+}
+
+impl Provider {
+    /// Environment variable checked for the API key of models outside of pprog.toml
+    pub fn api_key_env(&self) -> &'static str {
+        match self {
+            Provider::Anthropic => "ANTHROPIC_API_KEY",
+            Provider::OpenAI => "OPENAI_API_KEY",
+            Provider::Gemini => "GEMINI_API_KEY",
+            Provider::Mock => "MOCK_API_KEY",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    NotFound(PathBuf),
+    Parse(String),
+    Invalid(Vec<ValidationError>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    EmptyApiKey(Provider),
+    MaxOutputTokensExceeded {
+        model: String,
+        max_output_tokens: u32,
+        limit: u32,
+    },
+    MissingCheckCmd,
+    UnknownFallbackModel(String),
+    ThinkingUnsupported(String),
+    InvalidThinkingBudget {
+        thinking_budget: u32,
+        max_output_tokens: u32,
+    },
+    UnsupportedSamplingParam {
+        model: String,
+        param: &'static str,
+    },
+    SamplingParamOutOfRange {
+        model: String,
+        param: &'static str,
+        value: String,
+        max: String,
+    },
+    TooManyStopSequences {
+        model: String,
+        count: usize,
+        limit: usize,
+    },
+    InvalidHeader {
+        model: String,
+        error: String,
+    },
+    UnsupportedEndpointOption {
+        model: String,
+        option: &'static str,
+    },
+    InvalidHttpConfig(String),
+    InvalidCommitAuthor(String),
+    MissingFixture(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::NotFound(path) => write!(f, "Config file {} not found, run `pprog init` first", path.display()),
+            ConfigError::Parse(msg) => write!(f, "Failed to parse config: {}", msg),
+            ConfigError::Invalid(errors) => {
+                write!(f, "Invalid config:")?;
+                for error in errors {
+                    write!(f, "\n  - {}", error)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::EmptyApiKey(provider) => write!(f, "api_key is empty for provider '{}'", provider),
+            ValidationError::MaxOutputTokensExceeded { model, max_output_tokens, limit } => write!(
+                f,
+                "max_output_tokens = {} is above the limit of {} for model '{}'",
+                max_output_tokens, limit, model
+            ),
+            ValidationError::MissingCheckCmd => write!(f, "check_enabled = true but check_cmd is empty"),
+            ValidationError::ThinkingUnsupported(model) => write!(
+                f,
+                "thinking_budget is set but model '{}' does not support extended thinking",
+                model
+            ),
+            ValidationError::InvalidThinkingBudget { thinking_budget, max_output_tokens } => write!(
+                f,
+                "thinking_budget = {} must be at least {} and below max_output_tokens = {}",
+                thinking_budget, Self::MIN_THINKING_BUDGET, max_output_tokens
+            ),
+            ValidationError::UnsupportedSamplingParam { model, param } => write!(
+                f,
+                "{} is set but model '{}' does not accept it",
+                param, model
+            ),
+            ValidationError::SamplingParamOutOfRange { model, param, value, max } => write!(
+                f,
+                "{} = {} must be between 0 and {} for model '{}'",
+                param, value, max, model
+            ),
+            ValidationError::TooManyStopSequences { model, count, limit } => write!(
+                f,
+                "{} stop sequences are set but model '{}' accepts at most {}",
+                count, model, limit
+            ),
+            ValidationError::InvalidHeader { model, error } => write!(
+                f,
+                "invalid endpoint header for model '{}': {}",
+                model, error
+            ),
+            ValidationError::UnsupportedEndpointOption { model, option } => write!(
+                f,
+                "endpoint {} is only used by OpenAI compatible models, not by '{}'",
+                option, model
+            ),
+            ValidationError::InvalidHttpConfig(error) => write!(f, "invalid http config: {}", error),
+            ValidationError::InvalidCommitAuthor(author) => write!(
+                f,
+                "git author '{}' must be in the form 'Name <email>'",
+                author
+            ),
+            ValidationError::MissingFixture(model) => write!(f, "mock model '{}' needs a fixture", model),
+            ValidationError::UnknownFallbackModel(model) => write!(
+                f,
+                "fallback model '{}' is not a known model, set provider and api_url for it",
+                model
+            ),
+        }
+    }
+}
+
+impl ValidationError {
+    pub const MIN_THINKING_BUDGET: u32 = 1024;
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    pub max_attempts: u32,
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 5,
+            initial_delay_ms: 1000,
+            max_delay_ms: 60000,
+        }
+    }
+}
+
+/// Transport settings shared by all providers.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Proxy for all requests, e.g. `http://proxy.internal:3128`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// PEM files with extra root certificates to trust
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ca_certs: Vec<PathBuf>,
+}
+
+/// How pprog uses the project's git repository.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct GitConfig {
+    /// Snapshot the working tree before each turn that writes files
+    pub checkpoints: bool,
+    /// Work in a separate worktree and branch for each session
+    pub worktree: bool,
+    /// Commit the files written in a turn once the model is done and the check passes
+    pub auto_commit: bool,
+    /// Author of automatic commits as "Name <email>", git's user otherwise
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// Sign automatic commits with the key git is configured to use
+    pub sign: bool,
+}
+
+impl Default for GitConfig {
+    fn default() -> Self {
+        GitConfig {
+            checkpoints: true,
+            worktree: false,
+            auto_commit: false,
+            author: None,
+            sign: false,
+        }
+    }
+}
+
+/// What the project tree given to the model contains.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct TreeConfig {
+    /// List the files of initialized submodules instead of showing them as a single entry
+    pub submodules: bool,
+    /// Directories nested deeper are collapsed unless they hold changed or recent files
+    pub max_depth: usize,
+    /// Lines of tree to show before collapsing directories
+    pub max_entries: usize,
+    /// Only show paths matching one of these gitignore style patterns
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// Leave out paths matching one of these gitignore style patterns
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    /// Show each file's size
+    pub sizes: bool,
+    /// Lines of functions, types and classes per file to add after the tree, 0 leaves the map out
+    pub repo_map: usize,
+}
+
+impl Default for TreeConfig {
+    fn default() -> Self {
+        TreeConfig {
+            submodules: false,
+            max_depth: 6,
+            max_entries: 500,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            sizes: true,
+            repo_map: 200,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CassetteMode {
+    Record,
+    Replay,
+}
+
+/// Records provider traffic to `path` or serves a recording back without calling the API.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CassetteConfig {
+    pub mode: CassetteMode,
+    pub path: PathBuf,
+}
+
+/// Model to switch to when the current one keeps failing.  Models outside of the registry need
+/// `provider` and `api_url`, the key defaults to the provider's environment variable.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FallbackEntry {
+    pub model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<Provider>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fixture: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProjectConfig {
     pub model: String,
@@ -21,7 +316,32 @@ pub struct ProjectConfig {
     #[serde(default)]
     pub max_output_tokens: u32,
     #[serde(default)]
-    pub provider: String,
+    pub provider: Provider,
+    /// Extended thinking budget in tokens, 0 disables thinking
+    #[serde(default)]
+    pub thinking_budget: u32,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub sampling: SamplingParams,
+    /// Applies to the configured model
+    #[serde(default, skip_serializing_if = "EndpointConfig::is_default")]
+    pub endpoint: EndpointConfig,
+    /// Script of scripted responses for `provider = "mock"`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fixture: Option<String>,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cassette: Option<CassetteConfig>,
+    #[serde(default)]
+    pub git: GitConfig,
+    #[serde(default)]
+    pub tree: TreeConfig,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub models: Vec<ModelSpec>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallback: Vec<FallbackEntry>,
 }
 
 impl Default for ProjectConfig {
@@ -34,7 +354,18 @@ impl Default for ProjectConfig {
             api_key: String::new(),
             max_context: 100000,
             max_output_tokens: 8096,
-            provider: String::from("anthropic"),
+            provider: Provider::Anthropic,
+            thinking_budget: 0,
+            retry: RetryConfig::default(),
+            sampling: SamplingParams::default(),
+            endpoint: EndpointConfig::default(),
+            fixture: None,
+            http: HttpConfig::default(),
+            cassette: None,
+            git: GitConfig::default(),
+            tree: TreeConfig::default(),
+            models: Vec::new(),
+            fallback: Vec::new(),
         }
     }
 }
@@ -92,15 +423,230 @@ impl ProjectConfig {
             })
     }
 
+    pub fn registry(&self) -> ModelRegistry {
+        ModelRegistry::new(&self.models)
+    }
+
+    /// Spec of the configured model.  Provider and endpoint always come from pprog.toml, limits
+    /// and quirks from the registry when the model is known.
+    pub fn model_spec(&self) -> ModelSpec {
+        let registry = self.registry();
+        let mut spec = registry.get(&self.model).cloned().unwrap_or_else(|| ModelSpec {
+            name: self.model.clone(),
+            provider: self.provider,
+            api_url: self.api_url.clone(),
+            context_window: self.max_context,
+            max_output_tokens: self.max_output_tokens,
+            api_key_env: None,
+            pricing: None,
+            quirks: Default::default(),
+            endpoint: self.endpoint.clone(),
+            fixture: None,
+        });
+        spec.provider = self.provider;
+        if !self.api_url.is_empty() {
+            spec.api_url = self.api_url.clone();
+        }
+        if !self.endpoint.is_default() {
+            spec.endpoint = self.endpoint.clone();
+        }
+        if self.fixture.is_some() {
+            spec.fixture = self.fixture.clone();
+        }
+        spec
+    }
+
+    pub fn fallback_spec(&self, entry: &FallbackEntry) -> Option<ModelSpec> {
+        let mut spec = match self.registry().get(&entry.model) {
+            Some(spec) => spec.clone(),
+            None => ModelSpec {
+                name: entry.model.clone(),
+                provider: entry.provider?,
+                // Mock models only need their fixture
+                api_url: match entry.provider? {
+                    Provider::Mock => entry.api_url.clone().unwrap_or_default(),
+                    _ => entry.api_url.clone()?,
+                },
+                context_window: self.max_context,
+                max_output_tokens: self.max_output_tokens,
+                api_key_env: None,
+                pricing: None,
+                quirks: Default::default(),
+                endpoint: Default::default(),
+                fixture: None,
+            },
+        };
+        if let Some(provider) = entry.provider {
+            spec.provider = provider;
+        }
+        if let Some(api_url) = &entry.api_url {
+            spec.api_url = api_url.clone();
+        }
+        if entry.fixture.is_some() {
+            spec.fixture = entry.fixture.clone();
+        }
+        Some(spec)
+    }
+
     pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
+        Self::load_with_cassette(None)
+    }
+
+    /// Loads pprog.toml with a cassette from the command line replacing the configured one.
+    pub fn load_with_cassette(cassette: Option<CassetteConfig>) -> Result<Self, Box<dyn std::error::Error>> {
         let config_path = Self::config_path()?;
+        if !config_path.exists() {
+            return Err(ConfigError::NotFound(config_path).into());
+        }
 
         let content = fs::read_to_string(config_path)?;
-        let config: ProjectConfig = toml::from_str(&content)?;
+        let mut config: ProjectConfig = toml::from_str(&content)
+            .map_err(|e| ConfigError::Parse(e.to_string()))?;
+        if cassette.is_some() {
+            config.cassette = cassette;
+        }
+
+        let errors = config.validate();
+        if !errors.is_empty() {
+            return Err(ConfigError::Invalid(errors).into());
+        }
+
         info!("Loaded project config: {:?}", config);
         Ok(config)
     }
 
+    pub fn validate(&self) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+
+        // Replayed sessions never reach the provider
+        let needs_api_key = self.provider != Provider::Mock && !self.is_replay();
+        if self.api_key.trim().is_empty() && needs_api_key {
+            errors.push(ValidationError::EmptyApiKey(self.provider));
+        }
+
+        if let Some(spec) = self.registry().get(&self.model) {
+            if self.max_output_tokens > spec.max_output_tokens {
+                errors.push(ValidationError::MaxOutputTokensExceeded {
+                    model: self.model.clone(),
+                    max_output_tokens: self.max_output_tokens,
+                    limit: spec.max_output_tokens,
+                });
+            }
+        }
+
+        if self.check_enabled && self.check_cmd.trim().is_empty() {
+            errors.push(ValidationError::MissingCheckCmd);
+        }
+
+        if self.thinking_budget > 0 {
+            if !self.model_spec().quirks.thinking {
+                errors.push(ValidationError::ThinkingUnsupported(self.model.clone()));
+            } else if self.thinking_budget < ValidationError::MIN_THINKING_BUDGET
+                || self.thinking_budget >= self.max_output_tokens {
+                errors.push(ValidationError::InvalidThinkingBudget {
+                    thinking_budget: self.thinking_budget,
+                    max_output_tokens: self.max_output_tokens,
+                });
+            }
+        }
+
+        let model = self.model_spec();
+        errors.extend(Self::validate_sampling(&self.sampling, &model, self.thinking_enabled(&model)));
+
+        for entry in &self.fallback {
+            if self.fallback_spec(entry).is_none() {
+                errors.push(ValidationError::UnknownFallbackModel(entry.model.clone()));
+            }
+        }
+
+        let fallbacks: Vec<ModelSpec> = self.fallback.iter().filter_map(|entry| self.fallback_spec(entry)).collect();
+        for spec in std::iter::once(&model).chain(&fallbacks).chain(&self.models) {
+            if spec.provider == Provider::Mock && spec.fixture.is_none() {
+                errors.push(ValidationError::MissingFixture(spec.name.clone()));
+            }
+        }
+
+        for spec in std::iter::once(&model).chain(&self.models) {
+            if let Err(error) = header_map(&[], &spec.endpoint.headers) {
+                errors.push(ValidationError::InvalidHeader { model: spec.name.clone(), error });
+            }
+            // Anthropic and Gemini have their own key headers and no API versions in the URL
+            if matches!(spec.provider, Provider::Anthropic | Provider::Gemini) {
+                if spec.endpoint.auth != AuthStyle::Bearer {
+                    errors.push(ValidationError::UnsupportedEndpointOption { model: spec.name.clone(), option: "auth" });
+                }
+                if spec.endpoint.api_version.is_some() {
+                    errors.push(ValidationError::UnsupportedEndpointOption { model: spec.name.clone(), option: "api_version" });
+                }
+            }
+        }
+
+        if let Err(error) = HttpClient::new(&self.http, self.cassette.as_ref()) {
+            errors.push(ValidationError::InvalidHttpConfig(error));
+        }
+
+        if let Some(author) = &self.git.author {
+            let valid = author.split_once(" <")
+                .is_some_and(|(name, email)| !name.trim().is_empty() && email.len() > 1 && email.ends_with('>'));
+            if !valid {
+                errors.push(ValidationError::InvalidCommitAuthor(author.clone()));
+            }
+        }
+
+        errors
+    }
+
+    pub fn is_replay(&self) -> bool {
+        matches!(&self.cassette, Some(CassetteConfig { mode: CassetteMode::Replay, .. }))
+    }
+
+    /// Whether extended thinking is used with the given model
+    pub fn thinking_enabled(&self, model: &ModelSpec) -> bool {
+        model.quirks.thinking && self.thinking_budget > 0
+    }
+
+    pub fn validate_sampling(sampling: &SamplingParams, model: &ModelSpec, thinking: bool) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+        let accepts_sampling = model.accepts_sampling(thinking);
+
+        let ranges = [
+            ("temperature", sampling.temperature, model.max_temperature()),
+            ("top_p", sampling.top_p, 1.0),
+        ];
+        for (param, value, max) in ranges {
+            let Some(value) = value else { continue };
+            if !accepts_sampling {
+                errors.push(ValidationError::UnsupportedSamplingParam { model: model.name.clone(), param });
+            } else if !(0.0..=max).contains(&value) {
+                errors.push(ValidationError::SamplingParamOutOfRange {
+                    model: model.name.clone(),
+                    param,
+                    value: value.to_string(),
+                    max: max.to_string(),
+                });
+            }
+        }
+
+        if let Some(limit) = model.max_stop_sequences() {
+            if sampling.stop().len() > limit {
+                errors.push(ValidationError::TooManyStopSequences {
+                    model: model.name.clone(),
+                    count: sampling.stop().len(),
+                    limit,
+                });
+            }
+        }
+
+        if sampling.reasoning_effort.is_some() && !model.quirks.reasoning_effort {
+            errors.push(ValidationError::UnsupportedSamplingParam {
+                model: model.name.clone(),
+                param: "reasoning_effort",
+            });
+        }
+
+        errors
+    }
+
     pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
         let config_str = toml::to_string_pretty(self)?;
         let config_path = Self::config_path()?;
@@ -140,7 +686,18 @@ impl ProjectConfig {
             api_key,
             max_context: 100000,
             max_output_tokens: 8096,
-            provider: String::from("anthropic"),
+            provider: Provider::Anthropic,
+            thinking_budget: 0,
+            retry: RetryConfig::default(),
+            sampling: SamplingParams::default(),
+            endpoint: EndpointConfig::default(),
+            fixture: None,
+            http: HttpConfig::default(),
+            cassette: None,
+            git: GitConfig::default(),
+            tree: TreeConfig::default(),
+            models: Vec::new(),
+            fallback: Vec::new(),
         };
         config.save()?;
 
@@ -148,3 +705,112 @@ impl ProjectConfig {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_reports_each_problem() {
+        let config = ProjectConfig {
+            model: String::from("claude-3-5-haiku-latest"),
+            check_enabled: true,
+            check_cmd: String::new(),
+            api_key: String::new(),
+            max_output_tokens: 10000,
+            endpoint: EndpointConfig {
+                auth: AuthStyle::ApiKey,
+                api_version: Some(String::from("2024-10-21")),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(config.validate(), vec![
+            ValidationError::EmptyApiKey(Provider::Anthropic),
+            ValidationError::MaxOutputTokensExceeded {
+                model: String::from("claude-3-5-haiku-latest"),
+                max_output_tokens: 10000,
+                limit: 8192,
+            },
+            ValidationError::MissingCheckCmd,
+            ValidationError::UnsupportedEndpointOption {
+                model: String::from("claude-3-5-haiku-latest"),
+                option: "auth",
+            },
+            ValidationError::UnsupportedEndpointOption {
+                model: String::from("claude-3-5-haiku-latest"),
+                option: "api_version",
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_mock_models_need_a_fixture() {
+        let config: ProjectConfig = toml::from_str(r#"
+model = "mock"
+provider = "mock"
+"#).unwrap();
+        assert_eq!(config.validate(), vec![ValidationError::MissingFixture(String::from("mock"))]);
+
+        let config = ProjectConfig { fixture: Some(String::from("examples/mock_script.json")), ..config };
+        assert!(config.validate().is_empty());
+    }
+
+    #[test]
+    fn test_unknown_provider_is_rejected() {
+        let result: Result<ProjectConfig, _> = toml::from_str(r#"
+model = "claude-3-5-haiku-latest"
+provider = "antropic"
+api_key = "key"
+"#);
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("unknown variant `antropic`"), "{}", error);
+    }
+
+    #[test]
+    fn test_sampling_params_checked_against_model() {
+        let config: ProjectConfig = toml::from_str(r#"
+model = "o1"
+provider = "openai"
+api_key = "key"
+max_output_tokens = 8192
+
+[sampling]
+temperature = 0.5
+stop = ["a", "b", "c", "d", "e"]
+reasoning_effort = "low"
+"#).unwrap();
+
+        assert_eq!(config.validate(), vec![
+            ValidationError::UnsupportedSamplingParam {
+                model: String::from("o1"),
+                param: "temperature",
+            },
+            ValidationError::TooManyStopSequences {
+                model: String::from("o1"),
+                count: 5,
+                limit: 4,
+            },
+        ]);
+
+        let haiku = ProjectConfig::default().model_spec();
+        let sampling = SamplingParams {
+            temperature: Some(1.5),
+            reasoning_effort: config.sampling.reasoning_effort,
+            ..Default::default()
+        };
+        assert_eq!(ProjectConfig::validate_sampling(&sampling, &haiku, false), vec![
+            ValidationError::SamplingParamOutOfRange {
+                model: String::from("claude-3-5-haiku-latest"),
+                param: "temperature",
+                value: String::from("1.5"),
+                max: String::from("1"),
+            },
+            ValidationError::UnsupportedSamplingParam {
+                model: String::from("claude-3-5-haiku-latest"),
+                param: "reasoning_effort",
+            },
+        ]);
+    }
+}
@@ -0,0 +1,451 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::git;
+
+/*
+    * Structured diff of the project against HEAD, a commit or a checkpoint.
+    *
+    * Untracked files are shown by diffing with a copy of the index where they are added with
+    * --intent-to-add, so they compare properly against a base that already has them, like a
+    * checkpoint taken after the model created them.  The user's index is never touched.
+    *
+    * git's unified diff output is parsed into files, hunks and lines.  Each file and hunk also
+    * keeps its raw patch text, so single hunks can be staged with git apply --cached or reverted
+    * in the working tree with git apply -R.
+*/
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct DiffOptions {
+    /// Changes added to the index
+    pub staged: bool,
+    /// Changes in the working tree that aren't staged
+    pub unstaged: bool,
+    /// Files git doesn't track yet, only with unstaged
+    pub untracked: bool,
+    /// Only diff this file or directory
+    pub path: Option<String>,
+    /// Commit to compare staged and unstaged changes against, HEAD when None
+    pub base: Option<String>,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions {
+            staged: true,
+            unstaged: true,
+            untracked: true,
+            path: None,
+            base: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileStatus {
+    Added,
+    Modified,
+    Deleted,
+    Renamed,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LineKind {
+    Context,
+    Added,
+    Removed,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct DiffLine {
+    #[serde(rename = "type")]
+    pub kind: LineKind,
+    pub content: String,
+    pub old_line: Option<u32>,
+    pub new_line: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct Hunk {
+    pub old_start: u32,
+    pub old_lines: u32,
+    pub new_start: u32,
+    pub new_lines: u32,
+    /// Function or section the hunk is in, as found by git
+    pub header: String,
+    pub lines: Vec<DiffLine>,
+    #[serde(skip)]
+    pub patch: String,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct FileDiff {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_path: Option<String>,
+    pub status: FileStatus,
+    /// Not yet tracked by git
+    pub untracked: bool,
+    pub binary: bool,
+    pub hunks: Vec<Hunk>,
+    /// Lines before the first hunk, from `diff --git` up to `+++`
+    #[serde(skip)]
+    pub header: String,
+}
+
+impl FileDiff {
+    // Patch with the file header and the selected hunks
+    fn patch(&self, hunks: &[usize]) -> String {
+        hunks.iter()
+            .filter_map(|&index| self.hunks.get(index))
+            .fold(self.header.clone(), |patch, hunk| patch + &hunk.patch)
+    }
+
+    fn paths(&self) -> Vec<&str> {
+        std::iter::once(self.path.as_str()).chain(self.old_path.as_deref()).collect()
+    }
+
+    /// Stages the whole file, or only some of its hunks.
+    pub fn stage(&self, root: &Path, hunks: &[usize]) -> Result<()> {
+        if hunks.is_empty() {
+            let mut args = vec!["add", "-A", "--"];
+            args.extend(self.paths());
+            git::run(root, &args)?;
+            return Ok(());
+        }
+        Diff::apply(root, &self.patch(hunks), &["--cached"])
+    }
+
+    /// Puts the whole file back as it is in `base`, index and working tree, or as it is in the
+    /// index when there's no base.  With hunks only those are undone, in the working tree.
+    pub fn revert(&self, root: &Path, hunks: &[usize], base: Option<&str>) -> Result<()> {
+        if !hunks.is_empty() {
+            return Diff::apply(root, &self.patch(hunks), &["-R"]);
+        }
+        let base = base.map(|base| Diff::resolve_base(root, base)).transpose()?;
+        for path in self.paths() {
+            let restore = match base.as_deref() {
+                Some(base) => git::run(root, &["cat-file", "-e", &format!("{}:{}", base, path)])
+                    .map(|_| vec!["checkout", base, "--", path]),
+                None => git::run(root, &["ls-files", "--error-unmatch", "--", path])
+                    .map(|_| vec!["checkout", "--", path]),
+            };
+            match restore {
+                Ok(args) => {
+                    git::run(root, &args)?;
+                },
+                // Created since base
+                Err(_) => {
+                    git::run(root, &["rm", "-q", "--cached", "--ignore-unmatch", "--", path])?;
+                    if root.join(path).exists() {
+                        fs::remove_file(root.join(path))?;
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Diff;
+
+impl Diff {
+    /// Diffs the project in `root`.  Unstaged changes alone are against the index, so the base is ignored.
+    pub fn files(root: &Path, options: &DiffOptions) -> Result<Vec<FileDiff>> {
+        let base = match &options.base {
+            Some(base) => Self::resolve_base(root, base)?,
+            None => Self::head(root)?,
+        };
+
+        let mut args = vec!["-c", "core.quotePath=false", "diff", "--no-color", "--no-ext-diff", "-M"];
+        match (options.staged, options.unstaged) {
+            (true, true) => args.push(&base),
+            (true, false) => args.extend(["--cached", &base]),
+            (false, true) => {},
+            (false, false) => return Ok(Vec::new()),
+        }
+        args.push("--");
+        if let Some(path) = &options.path {
+            args.push(path);
+        }
+
+        let untracked = if options.unstaged && options.untracked {
+            Self::untracked(root, options.path.as_deref())?
+        } else {
+            Vec::new()
+        };
+        let output = if untracked.is_empty() {
+            git::run(root, &args)?
+        } else {
+            Self::with_intent_to_add(root, &untracked, &args)?
+        };
+
+        let mut files = Self::parse(&output)?;
+        for file in &mut files {
+            file.untracked = untracked.contains(&file.path);
+        }
+        Ok(files)
+    }
+
+    /// Id of the commit `base` names.  It comes from the client, so nothing git could take for an
+    /// option gets through.
+    pub fn resolve_base(root: &Path, base: &str) -> Result<String> {
+        if base.starts_with('-') {
+            return Err(anyhow!("Invalid base {}", base));
+        }
+        let commit = format!("{}^{{commit}}", base);
+        git::run(root, &["rev-parse", "--verify", "-q", "--end-of-options", &commit])
+            .map(|id| id.trim().to_string())
+            .map_err(|_| anyhow!("{} is not a commit", base))
+    }
+
+    fn head(root: &Path) -> Result<String> {
+        match git::run(root, &["rev-parse", "--verify", "-q", "HEAD"]) {
+            Ok(head) => Ok(head.trim().to_string()),
+            // Nothing committed yet, everything is new
+            Err(_) => Ok(git::run(root, &["hash-object", "-t", "tree", "/dev/null"])?.trim().to_string()),
+        }
+    }
+
+    fn untracked(root: &Path, path: Option<&str>) -> Result<Vec<String>> {
+        let mut args = vec!["-c", "core.quotePath=false", "ls-files", "-o", "--exclude-standard", "--"];
+        args.extend(path);
+        Ok(git::run(root, &args)?.lines().map(String::from).collect())
+    }
+
+    // Runs the diff with a throwaway copy of the index that has the untracked files in it
+    fn with_intent_to_add(root: &Path, untracked: &[String], args: &[&str]) -> Result<String> {
+        let index = root.join(git::run(root, &["rev-parse", "--git-path", "index"])?.trim());
+        let temp_index = PathBuf::from(format!("{}.pprog-diff", index.display()));
+        if index.exists() {
+            fs::copy(&index, &temp_index)?;
+        }
+        let git = |args: &[&str]| -> Result<String> {
+            let output = Command::new("git")
+                .args(args)
+                .env("GIT_INDEX_FILE", &temp_index)
+                .current_dir(root)
+                .output()?;
+            if !output.status.success() {
+                return Err(anyhow!("{}", String::from_utf8_lossy(&output.stderr).trim()));
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        };
+
+        let mut add = vec!["add", "--intent-to-add", "--"];
+        add.extend(untracked.iter().map(String::as_str));
+        let result = git(&add).and_then(|_| git(args));
+        let _ = fs::remove_file(&temp_index);
+        result
+    }
+
+    fn apply(root: &Path, patch: &str, options: &[&str]) -> Result<()> {
+        let mut child = Command::new("git")
+            .arg("apply")
+            .args(options)
+            .arg("-")
+            .current_dir(root)
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        child.stdin.take()
+            .ok_or_else(|| anyhow!("Unable to write patch to git apply"))?
+            .write_all(patch.as_bytes())?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(anyhow!("{}", String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        Ok(())
+    }
+
+    pub fn parse(output: &str) -> Result<Vec<FileDiff>> {
+        let mut files: Vec<FileDiff> = Vec::new();
+        let mut old_line = 0;
+        let mut new_line = 0;
+
+        for line in output.split_inclusive('\n') {
+            let text = line.strip_suffix('\n').unwrap_or(line);
+            if let Some(paths) = text.strip_prefix("diff --git ") {
+                // Replaced by the ---/+++ or rename lines when there are any
+                let path = paths.split_once(" b/").map_or(paths, |(_, b)| b).to_string();
+                files.push(FileDiff {
+                    path,
+                    old_path: None,
+                    status: FileStatus::Modified,
+                    untracked: false,
+                    binary: false,
+                    hunks: Vec::new(),
+                    header: line.to_string(),
+                });
+                continue;
+            }
+            let file = files.last_mut().ok_or_else(|| anyhow!("Unexpected diff line: {}", text))?;
+
+            match file.hunks.last_mut() {
+                Some(hunk) if !text.starts_with("@@") => {
+                    let (kind, content) = match text.split_at(text.len().min(1)) {
+                        (" ", content) | ("", content) => (LineKind::Context, content),
+                        ("+", content) => (LineKind::Added, content),
+                        ("-", content) => (LineKind::Removed, content),
+                        // "\ No newline at end of file" only matters to git apply
+                        ("\\", _) => {
+                            hunk.patch.push_str(line);
+                            continue;
+                        },
+                        _ => return Err(anyhow!("Unexpected diff line: {}", text)),
+                    };
+                    let (old, new) = match kind {
+                        LineKind::Context => (Some(old_line), Some(new_line)),
+                        LineKind::Added => (None, Some(new_line)),
+                        LineKind::Removed => (Some(old_line), None),
+                    };
+                    old_line += u32::from(old.is_some());
+                    new_line += u32::from(new.is_some());
+                    hunk.lines.push(DiffLine { kind, content: content.to_string(), old_line: old, new_line: new });
+                    hunk.patch.push_str(line);
+                    continue;
+                },
+                _ => {},
+            }
+
+            if text.starts_with("@@") {
+                let hunk = Self::parse_hunk_header(text)?;
+                old_line = hunk.old_start;
+                new_line = hunk.new_start;
+                file.hunks.push(Hunk { patch: line.to_string(), ..hunk });
+                continue;
+            }
+
+            file.header.push_str(line);
+            if text.starts_with("new file mode") {
+                file.status = FileStatus::Added;
+            } else if text.starts_with("deleted file mode") {
+                file.status = FileStatus::Deleted;
+            } else if let Some(from) = text.strip_prefix("rename from ") {
+                file.status = FileStatus::Renamed;
+                file.old_path = Some(from.to_string());
+            } else if let Some(to) = text.strip_prefix("rename to ") {
+                file.path = to.to_string();
+            } else if text.starts_with("Binary files ") {
+                file.binary = true;
+            } else if let Some(path) = text.strip_prefix("+++ b/") {
+                file.path = path.to_string();
+            } else if let Some(path) = text.strip_prefix("--- a/") {
+                if file.status == FileStatus::Deleted {
+                    file.path = path.to_string();
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    // @@ -old_start[,old_lines] +new_start[,new_lines] @@ header
+    fn parse_hunk_header(text: &str) -> Result<Hunk> {
+        let invalid = || anyhow!("Invalid hunk header: {}", text);
+        let rest = text.strip_prefix("@@ -").ok_or_else(invalid)?;
+        let (ranges, header) = rest.split_once(" @@").ok_or_else(invalid)?;
+        let (old, new) = ranges.split_once(" +").ok_or_else(invalid)?;
+        let range = |range: &str| -> Result<(u32, u32)> {
+            let (start, lines) = range.split_once(',').unwrap_or((range, "1"));
+            Ok((start.parse().map_err(|_| invalid())?, lines.parse().map_err(|_| invalid())?))
+        };
+        let (old_start, old_lines) = range(old)?;
+        let (new_start, new_lines) = range(new)?;
+        Ok(Hunk {
+            old_start,
+            old_lines,
+            new_start,
+            new_lines,
+            header: header.trim().to_string(),
+            lines: Vec::new(),
+            patch: String::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_includes_staged_and_untracked_files() {
+        let dir = git::test_repo(&[
+            ("main.rs", "fn main() {\n    println!(\"hi\");\n}\n"),
+            ("old.rs", "pub fn old() {}\n"),
+        ]);
+        let root = dir.path();
+
+        fs::write(root.join("main.rs"), "fn main() {\n    println!(\"hello\");\n}\n").unwrap();
+        git::run(root, &["add", "main.rs"]).unwrap();
+        fs::remove_file(root.join("old.rs")).unwrap();
+        fs::write(root.join("new.rs"), "pub fn new() {}").unwrap();
+
+        let files = Diff::files(root, &DiffOptions::default()).unwrap();
+        let summary: Vec<_> = files.iter().map(|f| (f.path.as_str(), f.status, f.untracked)).collect();
+        assert_eq!(summary, [
+            ("main.rs", FileStatus::Modified, false),
+            ("new.rs", FileStatus::Added, true),
+            ("old.rs", FileStatus::Deleted, false),
+        ]);
+
+        let hunk = &files[0].hunks[0];
+        assert_eq!((hunk.old_start, hunk.old_lines, hunk.new_start, hunk.new_lines), (1, 3, 1, 3));
+        assert_eq!(hunk.lines[1], DiffLine {
+            kind: LineKind::Removed,
+            content: "    println!(\"hi\");".to_string(),
+            old_line: Some(2),
+            new_line: None,
+        });
+        assert_eq!(hunk.lines[2].new_line, Some(2));
+        assert_eq!(files[1].hunks[0].lines[0].content, "pub fn new() {}");
+
+        // The untracked file was only added to a copy of the index
+        assert_eq!(git::run(root, &["status", "--porcelain"]).unwrap(), "M  main.rs\n D old.rs\n?? new.rs\n");
+
+        let staged = DiffOptions { unstaged: false, ..DiffOptions::default() };
+        let files = Diff::files(root, &staged).unwrap();
+        assert_eq!(files.iter().map(|f| f.path.as_str()).collect::<Vec<_>>(), ["main.rs"]);
+
+        let only_new = DiffOptions { path: Some("new.rs".to_string()), ..DiffOptions::default() };
+        assert_eq!(Diff::files(root, &only_new).unwrap().len(), 1);
+
+        // Bases are commits, never options
+        let output = root.join("output.txt");
+        let option = DiffOptions { base: Some(format!("--output={}", output.display())), ..DiffOptions::default() };
+        assert!(Diff::files(root, &option).is_err());
+        assert!(!output.exists());
+        let unknown = DiffOptions { base: Some("no-such-branch".to_string()), ..DiffOptions::default() };
+        assert!(Diff::files(root, &unknown).is_err());
+    }
+
+    #[test]
+    fn test_stage_and_revert_single_hunks() {
+        let lines: Vec<String> = (1..=20).map(|n| format!("line {}\n", n)).collect();
+        let dir = git::test_repo(&[("lines.txt", &lines.concat())]);
+        let root = dir.path();
+
+        let mut changed = lines.clone();
+        changed[1] = "line two\n".to_string();
+        changed[18] = "line nineteen\n".to_string();
+        fs::write(root.join("lines.txt"), changed.concat()).unwrap();
+        fs::write(root.join("new.txt"), "new\n").unwrap();
+
+        let files = Diff::files(root, &DiffOptions::default()).unwrap();
+        assert_eq!(files[0].hunks.len(), 2);
+        files[0].stage(root, &[1]).unwrap();
+        files[0].revert(root, &[0], None).unwrap();
+        files[1].revert(root, &[], None).unwrap();
+
+        let staged = git::run(root, &["diff", "--cached", "-U0"]).unwrap();
+        assert!(staged.contains("+line nineteen") && !staged.contains("line two"), "{}", staged);
+        changed[1] = "line 2\n".to_string();
+        assert_eq!(fs::read_to_string(root.join("lines.txt")).unwrap(), changed.concat());
+        assert!(!root.join("new.txt").exists());
+    }
+}
@@ -0,0 +1,178 @@
+use std::fmt;
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::Serialize;
+
+use crate::config::ValidationError;
+use crate::inference::types::InferenceError;
+use crate::tools::ToolError;
+
+/// Errors returned by the HTTP API, each with a status code and a stable `code` for the UI.
+#[derive(Debug)]
+pub enum ApiError {
+    Inference(InferenceError),
+    Tool(ToolError),
+    Config(Vec<ValidationError>),
+    ContextBudgetExceeded {
+        tokens: u64,
+        max_tokens: u64,
+    },
+    BadRequest(String),
+    NotFound(String),
+    /// The request doesn't apply to the current state, like a hunk that no longer matches
+    Conflict(String),
+    Internal(String),
+}
+
+#[derive(Serialize, Clone)]
+pub struct ErrorResponse {
+    pub error: String,
+    pub code: &'static str,
+    pub retryable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Inference(error) => match error {
+                InferenceError::MissingApiKey(_) => "missing_api_key",
+                InferenceError::RateLimited(..) => "rate_limited",
+                InferenceError::ApiError(status, _) => match status.as_u16() {
+                    401 | 403 => "provider_unauthorized",
+                    400..=499 => "provider_rejected",
+                    _ => "provider_error",
+                },
+                InferenceError::NetworkError(_) => "network_error",
+                InferenceError::InvalidResponse(_) => "invalid_response",
+                InferenceError::SerializationError(_) => "serialization_error",
+                InferenceError::InvalidConfig(_) => "invalid_config",
+            },
+            ApiError::Tool(ToolError::UnknownTool(_)) => "unknown_tool",
+            ApiError::Tool(ToolError::InvalidInput(_)) => "invalid_tool_input",
+            ApiError::Tool(ToolError::Failed(_)) => "tool_failed",
+            ApiError::Config(_) => "invalid_config",
+            ApiError::ContextBudgetExceeded { .. } => "context_budget_exceeded",
+            ApiError::BadRequest(_) => "invalid_request",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+
+    /// Whether sending the same request again may succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ApiError::Inference(error) => error.is_retryable(),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Inference(error) => write!(f, "Inference error: {}", error),
+            ApiError::Tool(error) => write!(f, "{}", error),
+            ApiError::Config(errors) => write!(
+                f,
+                "Invalid config: {}",
+                errors.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+            ),
+            ApiError::ContextBudgetExceeded { tokens, max_tokens } => write!(
+                f,
+                "Message needs {} tokens which is above max_context = {}",
+                tokens, max_tokens
+            ),
+            ApiError::BadRequest(msg) | ApiError::NotFound(msg) | ApiError::Conflict(msg) => write!(f, "{}", msg),
+            ApiError::Internal(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::Inference(error) => match error {
+                InferenceError::MissingApiKey(_) => StatusCode::UNAUTHORIZED,
+                InferenceError::RateLimited(..) => StatusCode::TOO_MANY_REQUESTS,
+                InferenceError::ApiError(status, _) => match status.as_u16() {
+                    401 | 403 => StatusCode::UNAUTHORIZED,
+                    400..=499 => StatusCode::BAD_REQUEST,
+                    _ => StatusCode::BAD_GATEWAY,
+                },
+                InferenceError::NetworkError(_) | InferenceError::InvalidResponse(_) => StatusCode::BAD_GATEWAY,
+                InferenceError::SerializationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                InferenceError::InvalidConfig(_) => StatusCode::BAD_REQUEST,
+            },
+            ApiError::Tool(ToolError::UnknownTool(_) | ToolError::InvalidInput(_)) => StatusCode::BAD_REQUEST,
+            ApiError::Tool(ToolError::Failed(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Config(_) | ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::ContextBudgetExceeded { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let retry_after_ms = match self {
+            ApiError::Inference(error) => error.retry_after().map(|d| d.as_millis() as u64),
+            _ => None,
+        };
+        HttpResponse::build(self.status_code()).json(ErrorResponse {
+            error: self.to_string(),
+            code: self.code(),
+            retryable: self.is_retryable(),
+            retry_after_ms,
+        })
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<InferenceError> for ApiError {
+    fn from(error: InferenceError) -> Self {
+        ApiError::Inference(error)
+    }
+}
+
+impl From<ToolError> for ApiError {
+    fn from(error: ToolError) -> Self {
+        ApiError::Tool(error)
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(error: anyhow::Error) -> Self {
+        ApiError::Internal(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use actix_web::body::to_bytes;
+
+    #[actix_web::test]
+    async fn test_rate_limit_response_is_retryable() {
+        let error = ApiError::from(InferenceError::RateLimited(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(Duration::from_secs(2)),
+            "slow down".to_string(),
+        ));
+        let response = error.error_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let body: serde_json::Value = serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(body["code"], "rate_limited");
+        assert_eq!(body["retryable"], true);
+        assert_eq!(body["retry_after_ms"], 2000);
+
+        let error = ApiError::from(InferenceError::ApiError(StatusCode::UNAUTHORIZED, "bad key".to_string()));
+        assert_eq!(error.status_code(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error.code(), "provider_unauthorized");
+        assert!(!error.is_retryable());
+    }
+}
@@ -0,0 +1,40 @@
+use std::path::Path;
+use std::process::Command;
+
+/*
+    * Runs the git binary, for what gix doesn't do yet: worktrees, merges, commits with hooks and
+    * signing, diffs and the model's git tools.  Reading the repository goes through gix in
+    * tree.rs instead.
+*/
+
+/// Runs git in `dir` and returns its output, failing with git's error message.
+pub fn run(dir: &Path, args: &[&str]) -> Result<String, anyhow::Error> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(std::io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string()
+        ).into());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Repository on branch main with a committer set and `files` in its first commit.
+#[cfg(test)]
+pub fn test_repo(files: &[(&str, &str)]) -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    run(root, &["init", "-q", "-b", "main"]).unwrap();
+    run(root, &["config", "user.name", "pprog"]).unwrap();
+    run(root, &["config", "user.email", "pprog@localhost"]).unwrap();
+    for (path, contents) in files {
+        std::fs::write(root.join(path), contents).unwrap();
+    }
+    run(root, &["add", "."]).unwrap();
+    run(root, &["commit", "-q", "-m", "Initial commit"]).unwrap();
+    dir
+}
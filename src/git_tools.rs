@@ -0,0 +1,383 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use gix::diff::blob::{diff, intern::InternedInput, Algorithm, UnifiedDiffBuilder};
+use gix::objs::tree::EntryMode;
+use gix::ObjectId;
+
+use crate::tree::GitTree;
+
+/*
+    * Read-only git tools so the model can look at the repository without shelling out.
+    *
+    * Everything is read with gix.  Status compares HEAD, the index and the files on disk by object
+    * id, and blame follows the first parent of each commit, diffing the file's lines with its
+    * parent's until every line is accounted for.  Renames are not followed.  Output is kept
+    * compact, one line per file, commit or line of code, and cut off after MAX_LINES lines.
+*/
+pub struct GitTools;
+
+const MAX_LINES: usize = 200;
+const DEFAULT_LOG_COUNT: usize = 20;
+
+impl GitTools {
+    fn open(root: &Path) -> Result<gix::Repository> {
+        gix::discover(root).map_err(|_| anyhow!("{} is not in a git repository", root.display()))
+    }
+
+    /// Staged, unstaged and untracked changes in the style of `git status --short`.
+    pub fn status(root: &Path) -> Result<String> {
+        let repo = Self::open(root)?;
+        let head = match repo.head_commit() {
+            Ok(commit) => Self::tree_files(&commit)?,
+            // Nothing committed yet
+            Err(_) => BTreeMap::new(),
+        };
+        let index = repo.index_or_empty()?;
+
+        let mut changes: BTreeMap<String, [char; 2]> = BTreeMap::new();
+        let mut tracked = BTreeSet::new();
+        for entry in index.entries() {
+            let path = entry.path(&index).to_string();
+            tracked.insert(path.clone());
+            if entry.stage() != 0 {
+                changes.insert(path, ['U', 'U']);
+                continue;
+            }
+            let staged = match head.get(&path) {
+                None => 'A',
+                Some(id) if *id != entry.id => 'M',
+                Some(_) => ' ',
+            };
+            // Submodules are left to `git submodule status`
+            let unstaged = if entry.mode == gix::index::entry::Mode::COMMIT {
+                ' '
+            } else {
+                match Self::worktree_id(&repo, &root.join(&path)) {
+                    None => 'D',
+                    Some(id) if id != entry.id => 'M',
+                    Some(_) => ' ',
+                }
+            };
+            if staged != ' ' || unstaged != ' ' {
+                changes.insert(path, [staged, unstaged]);
+            }
+        }
+        for path in head.keys().filter(|path| !tracked.contains(*path)) {
+            changes.insert(path.clone(), ['D', ' ']);
+        }
+
+        let branch = repo.head_name()?
+            .map(|name| name.shorten().to_string())
+            .unwrap_or_else(|| "HEAD (detached)".to_string());
+        let mut lines = vec![format!("On branch {}", branch)];
+        lines.extend(changes.into_iter().map(|(path, [staged, unstaged])| format!("{}{} {}", staged, unstaged, path)));
+        lines.extend(GitTree::list_files(root)?
+            .into_iter()
+            .filter(|path| !tracked.contains(path))
+            .map(|path| format!("?? {}", path)));
+        if lines.len() == 1 {
+            lines.push("Nothing to commit, working tree clean".to_string());
+        }
+        Ok(Self::truncate(lines))
+    }
+
+    /// Commits reachable from HEAD, newest first, optionally only those changing `path`.
+    pub fn log(root: &Path, path: Option<&str>, max_count: Option<usize>) -> Result<String> {
+        let repo = Self::open(root)?;
+        let head = repo.head_id().map_err(|_| anyhow!("No commits yet"))?;
+        let max_count = max_count.unwrap_or(DEFAULT_LOG_COUNT).min(MAX_LINES);
+
+        let mut lines = Vec::new();
+        let walk = head.ancestors()
+            .sorting(gix::traverse::commit::Sorting::ByCommitTimeNewestFirst)
+            .all()?;
+        for info in walk {
+            let commit = info?.object()?;
+            if let Some(path) = path {
+                let parent = commit.parent_ids().next()
+                    .map(|id| id.object()?.try_into_commit().map_err(anyhow::Error::from))
+                    .transpose()?;
+                let parent_entry = match &parent {
+                    Some(parent) => Self::entry_at(parent, path)?,
+                    None => None,
+                };
+                if Self::entry_at(&commit, path)? == parent_entry {
+                    continue;
+                }
+            }
+            lines.push(Self::describe(&commit)?);
+            if lines.len() >= max_count {
+                break;
+            }
+        }
+        if lines.is_empty() {
+            lines.push(format!("No commits change {}", path.unwrap_or_default()));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    /// The commit that last changed each line of `path`, lines are numbered from 1 and inclusive.
+    pub fn blame(root: &Path, path: &str, start_line: Option<usize>, end_line: Option<usize>) -> Result<String> {
+        let repo = Self::open(root)?;
+        let text = fs::read_to_string(root.join(path))?;
+        let line_count = text.lines().count();
+        let start = start_line.unwrap_or(1).max(1);
+        let end = end_line.unwrap_or(line_count).min(line_count);
+        if start > end {
+            return Err(anyhow!("{} has {} lines, can't blame lines {} to {}", path, line_count, start, end));
+        }
+
+        // Lines still looking for their commit, by line number in the file and in the version being compared
+        let mut owners: Vec<Option<ObjectId>> = vec![None; line_count];
+        let mut pending: Vec<(usize, u32)> = (start - 1..end).map(|line| (line, line as u32)).collect();
+        let mut commit = repo.head_commit().ok();
+        let mut after = text.clone();
+        // Lines changed on disk stay without a commit
+        let mut owner: Option<ObjectId> = None;
+
+        while !pending.is_empty() {
+            let before = match &commit {
+                Some(commit) => Self::blob_at(&repo, commit, path)?,
+                None => None,
+            };
+            let Some(before) = before else {
+                for &(line, _) in &pending {
+                    owners[line] = owner;
+                }
+                break;
+            };
+            if before != after {
+                let map = Self::line_map(&before, &after);
+                pending.retain_mut(|(line, version_line)| match map[*version_line as usize] {
+                    Some(before_line) => {
+                        *version_line = before_line;
+                        true
+                    },
+                    None => {
+                        owners[*line] = owner;
+                        false
+                    },
+                });
+            }
+            let Some(current) = commit.take() else { break };
+            owner = Some(current.id);
+            commit = current.parent_ids().next()
+                .map(|id| id.object()?.try_into_commit().map_err(anyhow::Error::from))
+                .transpose()?;
+            after = before;
+        }
+
+        let mut described: HashMap<ObjectId, String> = HashMap::new();
+        let mut lines = Vec::new();
+        for (index, content) in text.lines().enumerate().take(end).skip(start - 1) {
+            let commit = match owners[index] {
+                Some(id) => match described.get(&id) {
+                    Some(description) => description.clone(),
+                    None => {
+                        let commit = repo.find_object(id)?.try_into_commit()?;
+                        let author = commit.author()?;
+                        let description = format!(
+                            "{} {} {}",
+                            commit.id().shorten_or_id(),
+                            author.time.format(gix::date::time::format::SHORT),
+                            author.name
+                        );
+                        described.insert(id, description.clone());
+                        description
+                    },
+                },
+                None => "Not committed yet".to_string(),
+            };
+            lines.push(format!("{} {:>4}: {}", commit, index + 1, content));
+        }
+        Ok(Self::truncate(lines))
+    }
+
+    /// A commit with its patch against its first parent, or a file or directory at a revision.
+    pub fn show(root: &Path, revision: &str) -> Result<String> {
+        let repo = Self::open(root)?;
+        let object = repo.rev_parse_single(revision)
+            .map_err(|e| anyhow!("Unknown revision {}: {}", revision, e))?
+            .object()?;
+        let lines = match object.kind {
+            gix::object::Kind::Commit => {
+                let commit = object.try_into_commit()?;
+                let author = commit.author()?;
+                let mut lines = vec![
+                    format!("commit {}", commit.id),
+                    format!("Author: {} <{}>", author.name, author.email),
+                    format!("Date:   {}", author.time.format(gix::date::time::format::ISO8601)),
+                    String::new(),
+                ];
+                lines.extend(commit.message_raw_sloppy().to_string().lines().map(|line| format!("    {}", line)));
+                let parent = commit.parent_ids().next()
+                    .map(|id| id.object()?.try_into_commit().map_err(anyhow::Error::from))
+                    .transpose()?;
+                let before = match &parent {
+                    Some(parent) => Self::tree_files(parent)?,
+                    None => BTreeMap::new(),
+                };
+                let after = Self::tree_files(&commit)?;
+                let paths: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+                for path in paths {
+                    let (old, new) = (before.get(path), after.get(path));
+                    if old == new {
+                        continue;
+                    }
+                    lines.push(String::new());
+                    lines.push(format!("diff {}", path));
+                    let old = old.map(|id| Self::blob_text(&repo, *id)).transpose()?.unwrap_or_default();
+                    let new = new.map(|id| Self::blob_text(&repo, *id)).transpose()?.unwrap_or_default();
+                    let input = InternedInput::new(old.as_str(), new.as_str());
+                    lines.extend(diff(Algorithm::Histogram, &input, UnifiedDiffBuilder::new(&input)).lines().map(String::from));
+                }
+                lines
+            },
+            gix::object::Kind::Tree => object.into_tree()
+                .decode()?
+                .entries
+                .iter()
+                .map(|entry| match entry.mode {
+                    EntryMode::Tree => format!("{}/", entry.filename),
+                    _ => entry.filename.to_string(),
+                })
+                .collect(),
+            gix::object::Kind::Blob => match std::str::from_utf8(&object.data) {
+                Ok(text) => text.lines().map(String::from).collect(),
+                Err(_) => vec![format!("Binary file, {} bytes", object.data.len())],
+            },
+            gix::object::Kind::Tag => {
+                let tag = object.try_into_tag()?;
+                let tag = tag.decode()?;
+                vec![format!("tag {}", tag.name), format!("object {}", tag.target()), String::new(), tag.message.to_string()]
+            },
+        };
+        Ok(Self::truncate(lines))
+    }
+
+    fn describe(commit: &gix::Commit<'_>) -> Result<String> {
+        let author = commit.author()?;
+        Ok(format!(
+            "{} {} {} {}",
+            commit.id().shorten_or_id(),
+            author.time.format(gix::date::time::format::SHORT),
+            author.name,
+            commit.message()?.summary()
+        ))
+    }
+
+    // Blobs and symlinks of a commit by path
+    fn tree_files(commit: &gix::Commit<'_>) -> Result<BTreeMap<String, ObjectId>> {
+        Ok(commit.tree()?.traverse().breadthfirst.files()?
+            .into_iter()
+            .filter(|entry| matches!(entry.mode, EntryMode::Blob | EntryMode::BlobExecutable | EntryMode::Link))
+            .map(|entry| (entry.filepath.to_string(), entry.oid))
+            .collect())
+    }
+
+    fn entry_at(commit: &gix::Commit<'_>, path: &str) -> Result<Option<ObjectId>> {
+        let mut buf = Vec::new();
+        Ok(commit.tree()?
+            .lookup_entry_by_path(path, &mut buf)?
+            .map(|entry| entry.object_id()))
+    }
+
+    fn blob_at(repo: &gix::Repository, commit: &gix::Commit<'_>, path: &str) -> Result<Option<String>> {
+        Self::entry_at(commit, path)?
+            .map(|id| Self::blob_text(repo, id))
+            .transpose()
+    }
+
+    fn blob_text(repo: &gix::Repository, id: ObjectId) -> Result<String> {
+        Ok(String::from_utf8_lossy(&repo.find_object(id)?.data).to_string())
+    }
+
+    fn worktree_id(repo: &gix::Repository, path: &Path) -> Option<ObjectId> {
+        let metadata = fs::symlink_metadata(path).ok()?;
+        let data = if metadata.file_type().is_symlink() {
+            gix::path::into_bstr(fs::read_link(path).ok()?).to_vec()
+        } else {
+            fs::read(path).ok()?
+        };
+        Some(gix::objs::compute_hash(repo.object_hash(), gix::object::Kind::Blob, &data))
+    }
+
+    // For each line of `after`, the line it was in `before` if it is unchanged
+    fn line_map(before: &str, after: &str) -> Vec<Option<u32>> {
+        let input = InternedInput::new(before, after);
+        let mut map = Vec::with_capacity(input.after.len());
+        let mut before_line = 0;
+        diff(Algorithm::Histogram, &input, |removed: std::ops::Range<u32>, added: std::ops::Range<u32>| {
+            while (map.len() as u32) < added.start {
+                map.push(Some(before_line));
+                before_line += 1;
+            }
+            map.extend(added.map(|_| None));
+            before_line = removed.end;
+        });
+        while map.len() < input.after.len() {
+            map.push(Some(before_line));
+            before_line += 1;
+        }
+        map
+    }
+
+    fn truncate(mut lines: Vec<String>) -> String {
+        if lines.len() > MAX_LINES {
+            let more = lines.len() - MAX_LINES;
+            lines.truncate(MAX_LINES);
+            lines.push(format!("[{} more lines]", more));
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git;
+
+    fn commit(root: &Path, message: &str) {
+        git::run(root, &["add", "-A"]).unwrap();
+        git::run(root, &["commit", "-q", "-m", message]).unwrap();
+    }
+
+    #[test]
+    fn test_status_log_and_blame() {
+        let dir = git::test_repo(&[("main.rs", "fn main() {\n    run();\n}\n"), ("README.md", "# demo\n")]);
+        let root = dir.path();
+        fs::write(root.join("main.rs"), "fn main() {\n    setup();\n    run();\n}\n").unwrap();
+        commit(root, "Set up before running");
+        fs::write(root.join("README.md"), "# demo app\n").unwrap();
+        commit(root, "Rename app");
+
+        fs::write(root.join("main.rs"), "fn main() {\n    setup();\n    run();\n    exit();\n}\n").unwrap();
+        fs::write(root.join("new.rs"), "").unwrap();
+        fs::remove_file(root.join("README.md")).unwrap();
+        assert_eq!(GitTools::status(root).unwrap(), "On branch main\n D README.md\n M main.rs\n?? new.rs");
+
+        let log = GitTools::log(root, Some("main.rs"), None).unwrap();
+        let subjects: Vec<_> = log.lines().map(|line| line.splitn(4, ' ').last().unwrap()).collect();
+        assert_eq!(subjects, ["Set up before running", "Initial commit"]);
+
+        let blame: Vec<String> = GitTools::blame(root, "main.rs", Some(2), Some(4)).unwrap()
+            .lines()
+            .map(|line| line.split_once(": ").unwrap().1.to_string())
+            .collect();
+        assert_eq!(blame, ["    setup();", "    run();", "    exit();"]);
+        let blame = GitTools::blame(root, "main.rs", None, None).unwrap();
+        let owners: Vec<&str> = blame.lines().map(|line| line.split(' ').next().unwrap()).collect();
+        let setup = &GitTools::log(root, Some("main.rs"), Some(1)).unwrap()[..owners[1].len()];
+        assert_eq!(owners[1], setup);
+        assert_ne!(owners[0], setup);
+        assert_eq!(owners[0], owners[2]);
+        assert!(blame.lines().nth(3).unwrap().starts_with("Not committed yet"));
+
+        let show = GitTools::show(root, "HEAD~1").unwrap();
+        assert!(show.contains("    Set up before running"));
+        assert!(show.contains("diff main.rs\n@@ -1,3 +1,4 @@\n fn main() {\n+    setup();"), "{}", show);
+        assert_eq!(GitTools::show(root, "HEAD:README.md").unwrap(), "# demo app");
+    }
+}
@@ -1,12 +1,14 @@
 use std::collections::HashMap;
-use reqwest::Client;
+use reqwest::header::HeaderMap;
 use serde::{Serialize, Deserialize};
 use anyhow::Result;
 use async_trait::async_trait;
 
-use crate::chat::{CommonMessage, ContentItem};
+use crate::chat::{CommonMessage, ContentItem, Role};
+use crate::models::{EndpointConfig, ModelSpec};
 use super::inference::Inference;
-use super::types::{InferenceError, ModelResponse};
+use super::http::{header_map, HttpClient};
+use super::types::{InferenceError, InferenceOptions, ModelResponse, SamplingParams};
 use super::tools::{AnthropicTool, InputSchema, PropertySchema};
 
 // New struct to manage tools
@@ -20,9 +22,15 @@ impl AnthropicTools {
     pub fn get_tools(&self) -> Vec<AnthropicTool> {
         vec![
             self.read_file_tool(),
+            self.read_image_tool(),
             self.write_file_tool(),
             self.execute_tool(),
             self.compile_check_tool(),
+            self.git_status_tool(),
+            self.git_log_tool(),
+            self.git_blame_tool(),
+            self.git_show_tool(),
+            self.repo_map_tool(),
         ]
     }
 
@@ -52,6 +60,28 @@ impl AnthropicTools {
         }
     }
 
+    fn read_image_tool(&self) -> AnthropicTool {
+        AnthropicTool {
+            name: "read_image".to_string(),
+            description: "View a PNG, JPEG, GIF or WebP image using path relative to root directory of project.".to_string(),
+            input_schema: InputSchema {
+                schema_type: "object".to_string(),
+                properties: {
+                    let mut map = HashMap::new();
+                    map.insert(
+                        "path".to_string(),
+                        PropertySchema {
+                            property_type: "string".to_string(),
+                            description: "The image path relative to the project root directory".to_string(),
+                        },
+                    );
+                    map
+                },
+                required: vec!["path".to_string()],
+            },
+        }
+    }
+
     fn write_file_tool(&self) -> AnthropicTool {
         AnthropicTool {
             name: "write_file".to_string(),
@@ -107,10 +137,128 @@ impl AnthropicTools {
         AnthropicTool {
             name: "compile_check".to_string(),
             description: "Check if project compiles or runs without error.".to_string(),
+            input_schema: InputSchema {
+                schema_type: "object".to_string(),
+                properties: HashMap::new(),
+                required: vec![],
+            },
+        }
+    }
+
+    fn git_status_tool(&self) -> AnthropicTool {
+        AnthropicTool {
+            name: "git_status".to_string(),
+            description: "Show files that are staged, modified, deleted or untracked in the git repository.".to_string(),
+            input_schema: InputSchema {
+                schema_type: "object".to_string(),
+                properties: HashMap::new(),
+                required: vec![],
+            },
+        }
+    }
+
+    fn git_log_tool(&self) -> AnthropicTool {
+        AnthropicTool {
+            name: "git_log".to_string(),
+            description: "List commits from newest to oldest with short id, date, author and summary.".to_string(),
+            input_schema: InputSchema {
+                schema_type: "object".to_string(),
+                properties: {
+                    let mut map = HashMap::new();
+                    map.insert(
+                        "path".to_string(),
+                        PropertySchema {
+                            property_type: "string".to_string(),
+                            description: "Only list commits that changed this file or directory".to_string(),
+                        },
+                    );
+                    map.insert(
+                        "max_count".to_string(),
+                        PropertySchema {
+                            property_type: "integer".to_string(),
+                            description: "Maximum number of commits to list, 20 by default".to_string(),
+                        },
+                    );
+                    map
+                },
+                required: vec![],
+            },
+        }
+    }
+
+    fn git_blame_tool(&self) -> AnthropicTool {
+        AnthropicTool {
+            name: "git_blame".to_string(),
+            description: "Show the commit, date and author that last changed each line of a file.".to_string(),
+            input_schema: InputSchema {
+                schema_type: "object".to_string(),
+                properties: {
+                    let mut map = HashMap::new();
+                    map.insert(
+                        "path".to_string(),
+                        PropertySchema {
+                            property_type: "string".to_string(),
+                            description: "The file path relative to the project root directory".to_string(),
+                        },
+                    );
+                    map.insert(
+                        "start_line".to_string(),
+                        PropertySchema {
+                            property_type: "integer".to_string(),
+                            description: "First line to show, starting at 1".to_string(),
+                        },
+                    );
+                    map.insert(
+                        "end_line".to_string(),
+                        PropertySchema {
+                            property_type: "integer".to_string(),
+                            description: "Last line to show".to_string(),
+                        },
+                    );
+                    map
+                },
+                required: vec!["path".to_string()],
+            },
+        }
+    }
+
+    fn git_show_tool(&self) -> AnthropicTool {
+        AnthropicTool {
+            name: "git_show".to_string(),
+            description: "Show a commit with its changes, or a file or directory at a revision like HEAD~1:src/main.rs.".to_string(),
+            input_schema: InputSchema {
+                schema_type: "object".to_string(),
+                properties: {
+                    let mut map = HashMap::new();
+                    map.insert(
+                        "revision".to_string(),
+                        PropertySchema {
+                            property_type: "string".to_string(),
+                            description: "Commit, branch, tag or revision:path to show".to_string(),
+                        },
+                    );
+                    map
+                },
+                required: vec!["revision".to_string()],
+            },
+        }
+    }
+
+    fn repo_map_tool(&self) -> AnthropicTool {
+        AnthropicTool {
+            name: "repo_map".to_string(),
+            description: "List the functions, types and classes declared in each source file under a directory.".to_string(),
             input_schema: InputSchema {
                 schema_type: "object".to_string(),
                 properties: {
-                    let map = HashMap::new();
+                    let mut map = HashMap::new();
+                    map.insert(
+                        "path".to_string(),
+                        PropertySchema {
+                            property_type: "string".to_string(),
+                            description: "Directory relative to the project root, the whole project by default".to_string(),
+                        },
+                    );
                     map
                 },
                 required: vec![],
@@ -120,21 +268,45 @@ impl AnthropicTools {
 
 }
 
+#[derive(Serialize)]
+struct AnthropicMessage {
+    role: Role,
+    content: Vec<ContentItem>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(tag = "type", rename = "enabled")]
+struct ThinkingConfig {
+    budget_tokens: u32,
+}
+
 #[derive(Serialize)]
 struct AnthropicRequest<'a> {
     model: &'a str,
-    messages: Vec<CommonMessage>,
+    messages: Vec<AnthropicMessage>,
     max_tokens: u32,
-    tools: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<serde_json::Value>,
     system: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<ThinkingConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    stop_sequences: &'a [String],
 }
 
 #[derive(Serialize)]
 struct TokenCountRequest<'a> {
     model: &'a str,
-    messages: Vec<CommonMessage>,
-    tools: serde_json::Value,
+    messages: Vec<AnthropicMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<serde_json::Value>,
     system: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<ThinkingConfig>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -160,27 +332,67 @@ struct Usage {
 
 pub struct AnthropicInference {
     model: String,
-    client: Client,
+    client: HttpClient,
     api_url: String,
     api_key: String,
     max_output_tokens: u32,
+    thinking: Option<ThinkingConfig>,
+    sampling: SamplingParams,
+    endpoint: EndpointConfig,
+    // Models without native tools are wrapped in PromptedToolInference
+    native_tools: bool,
     tools: AnthropicTools,
 }
 
+impl AnthropicInference {
+    /*
+        * Thinking blocks are passed back unchanged so the signatures verify across tool use turns.
+        * Without thinking enabled, or when the block came from another provider and has no
+        * signature, they are dropped since the API would reject them.  So are messages left without
+        * content, the API combines the turns around them.
+    */
+    fn to_anthropic_messages(&self, messages: Vec<CommonMessage>) -> Vec<AnthropicMessage> {
+        messages.into_iter().map(|message| AnthropicMessage {
+            role: message.role,
+            content: message.content.into_iter().filter(|content_item| match content_item {
+                ContentItem::Thinking { signature, .. } => self.thinking.is_some() && signature.is_some(),
+                ContentItem::RedactedThinking { .. } => self.thinking.is_some(),
+                ContentItem::Reasoning { .. } => false,
+                _ => true,
+            }).map(|content_item| match content_item {
+                // Gemini's thought signatures are not part of Anthropic's tool_use block
+                ContentItem::ToolUse { id, name, input, .. } => ContentItem::ToolUse { id, name, input, signature: None },
+                content_item => content_item,
+            }).collect(),
+        }).filter(|message| !message.content.is_empty()).collect()
+    }
+
+    fn headers(&self) -> Result<HeaderMap, InferenceError> {
+        header_map(&[
+            ("Content-Type", "application/json"),
+            ("X-API-Key", &self.api_key),
+            ("anthropic-version", "2023-06-01"),
+        ], &self.endpoint.headers).map_err(InferenceError::InvalidConfig)
+    }
+}
+
 #[async_trait]
 impl Inference for AnthropicInference {
     fn new(
-        model: String,
-        api_url: String,
+        model: ModelSpec,
         api_key: String,
-        max_output_tokens: u32
+        options: InferenceOptions
     ) -> Self {
         AnthropicInference {
-            model,
-            client: Client::new(),
-            api_url,
+            model: model.name,
+            client: options.client,
+            api_url: model.api_url,
             api_key,
-            max_output_tokens,
+            max_output_tokens: options.max_output_tokens,
+            thinking: options.thinking_budget.map(|budget_tokens| ThinkingConfig { budget_tokens }),
+            sampling: options.sampling,
+            endpoint: model.endpoint,
+            native_tools: !model.quirks.prompted_tools,
             tools: AnthropicTools::new(),
         }
     }
@@ -192,34 +404,29 @@ impl Inference for AnthropicInference {
 
         let system = system_message.unwrap_or("").to_string();
 
-        let tools = self.tools.get_tools_json()
-            .map_err(|e| InferenceError::SerializationError(e.to_string()))?;
+        let tools = if self.native_tools {
+            Some(self.tools.get_tools_json()
+                .map_err(|e| InferenceError::SerializationError(e.to_string()))?)
+        } else {
+            None
+        };
 
         let request = AnthropicRequest {
             model: &self.model,
-            messages,
+            messages: self.to_anthropic_messages(messages),
             max_tokens: self.max_output_tokens,
             tools,
             system,
+            thinking: self.thinking,
+            temperature: self.sampling.temperature,
+            top_p: self.sampling.top_p,
+            stop_sequences: self.sampling.stop(),
         };
 
-        let response = self.client
-            .post(format!("{}", self.api_url))
-            .header("Content-Type", "application/json")
-            .header("X-API-Key", &self.api_key)
-            .header("anthropic-version", "2023-06-01")
-            .json(&request)
-            .send()
-            .await
-            .map_err(|e| InferenceError::NetworkError(e.to_string()))?;
-
-        let status = response.status();
-        let response_text = response.text().await
-            .map_err(|e| InferenceError::NetworkError(e.to_string()))?;
-
-        if !status.is_success() {
-            return Err(InferenceError::ApiError(status, response_text));
-        }
+        let response_text = self.client
+            .post(&self.api_url, self.headers()?, &request)
+            .await?
+            .text()?;
 
         let anthropic_response: AnthropicResponse = serde_json::from_str(&response_text)
             .map_err(|e| InferenceError::InvalidResponse(e.to_string()))?;
@@ -242,33 +449,26 @@ impl Inference for AnthropicInference {
 
         let system = system_message.unwrap_or("").to_string();
 
-        let tools = self.tools.get_tools_json()
-            .map_err(|e| InferenceError::SerializationError(e.to_string()))?;
+        let tools = if self.native_tools {
+            Some(self.tools.get_tools_json()
+                .map_err(|e| InferenceError::SerializationError(e.to_string()))?)
+        } else {
+            None
+        };
 
         let request = TokenCountRequest {
             model: &self.model,
-            messages,
+            messages: self.to_anthropic_messages(messages),
             tools,
             system,
+            thinking: self.thinking,
         };
 
-        let response = self.client
-            .post("https://api.anthropic.com/v1/messages/count_tokens")
-            .header("Content-Type", "application/json")
-            .header("X-API-Key", &self.api_key)
-            .header("anthropic-version", "2023-06-01")
-            .json(&request)
-            .send()
-            .await
-            .map_err(|e| InferenceError::NetworkError(e.to_string()))?;
-
-        let status = response.status();
-        let response_text = response.text().await
-            .map_err(|e| InferenceError::NetworkError(e.to_string()))?;
-
-        if !status.is_success() {
-            return Err(InferenceError::ApiError(status, response_text));
-        }
+        let url = format!("{}/count_tokens", self.api_url.trim_end_matches('/'));
+        let response_text = self.client
+            .post(&url, self.headers()?, &request)
+            .await?
+            .text()?;
 
         let token_count_response: TokenCountResponse = serde_json::from_str(&response_text)
             .map_err(|e| InferenceError::InvalidResponse(e.to_string()))?;
@@ -276,3 +476,57 @@ impl Inference for AnthropicInference {
         Ok(token_count_response.input_tokens)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Provider;
+
+    fn inference(thinking_budget: Option<u32>) -> AnthropicInference {
+        let model = ModelSpec {
+            name: "claude-3-7-sonnet-latest".to_string(),
+            provider: Provider::Anthropic,
+            api_url: "https://api.anthropic.com/v1/messages".to_string(),
+            context_window: 200000,
+            max_output_tokens: 64000,
+            api_key_env: None,
+            pricing: None,
+            quirks: Default::default(),
+            endpoint: Default::default(),
+            fixture: None,
+        };
+        AnthropicInference::new(model, "key".to_string(), InferenceOptions {
+            max_output_tokens: 8192,
+            thinking_budget,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_thinking_blocks_round_trip_only_when_enabled() {
+        let content: Vec<ContentItem> = serde_json::from_value(serde_json::json!([
+            { "type": "thinking", "thinking": "Need to read the file first.", "signature": "sig" },
+            { "type": "redacted_thinking", "data": "opaque" },
+            { "type": "thinking", "thinking": "From DeepSeek" },
+            { "type": "tool_use", "id": "toolu_1", "name": "read_file", "input": { "path": "a.rs" } },
+        ])).unwrap();
+        let messages = vec![CommonMessage { role: Role::Assistant, content, model: None }];
+
+        let enabled = inference(Some(2048)).to_anthropic_messages(messages.clone());
+        let enabled = serde_json::to_value(&enabled[0].content).unwrap();
+        assert_eq!(enabled.as_array().unwrap().len(), 3);
+        assert_eq!(enabled[0]["signature"], "sig");
+        assert_eq!(enabled[1]["type"], "redacted_thinking");
+
+        let disabled = inference(None).to_anthropic_messages(messages);
+        assert_eq!(disabled[0].content.len(), 1);
+
+        // A message that was only thinking is left out instead of sent empty
+        let thinking_only = vec![CommonMessage {
+            role: Role::Assistant,
+            content: vec![ContentItem::Thinking { thinking: "Hmm".to_string(), signature: None }],
+            model: None,
+        }];
+        assert!(inference(None).to_anthropic_messages(thinking_only).is_empty());
+    }
+}
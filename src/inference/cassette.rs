@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use log::warn;
+use reqwest::header::HeaderMap;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::{CassetteConfig, CassetteMode};
+
+/*
+    * Recording of provider traffic for reproducing bugs.
+    *
+    * In record mode every request and response is added to the cassette file as it happens, so
+    * a session that ends in an error still has everything up to it.  The file is written next to
+    * the cassette and renamed over it, a crash while writing leaves the previous version intact.
+    * Header values other than a few known safe ones are redacted, API keys never reach the file.
+    *
+    * In replay mode no requests are sent.  Each request gets the first unused recording with the
+    * same method and URL path, host and query are ignored so a cassette from a gateway or Azure
+    * deployment replays against another endpoint.  Bodies are not compared since the system
+    * prompt holds the project tree of the machine it was recorded on, so a replay only matches the
+    * recording when the same messages are sent in the same order.
+*/
+pub struct Cassette {
+    path: PathBuf,
+    mode: CassetteMode,
+    interactions: Mutex<Vec<Option<Interaction>>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct CassetteFile {
+    interactions: Vec<Interaction>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Interaction {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: BTreeMap<String, String>,
+    pub body: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RecordedResponse {
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+const SAFE_HEADERS: [&str; 2] = ["content-type", "anthropic-version"];
+const REDACTED: &str = "REDACTED";
+
+impl Cassette {
+    pub fn open(config: &CassetteConfig) -> Result<Self, String> {
+        let interactions = match config.mode {
+            CassetteMode::Record => Vec::new(),
+            CassetteMode::Replay => {
+                let content = fs::read_to_string(&config.path)
+                    .map_err(|e| format!("unable to read cassette {}: {}", config.path.display(), e))?;
+                let file: CassetteFile = serde_json::from_str(&content)
+                    .map_err(|e| format!("invalid cassette {}: {}", config.path.display(), e))?;
+                file.interactions.into_iter().map(Some).collect()
+            },
+        };
+        Ok(Cassette {
+            path: config.path.clone(),
+            mode: config.mode,
+            interactions: Mutex::new(interactions),
+        })
+    }
+
+    pub fn is_replay(&self) -> bool {
+        self.mode == CassetteMode::Replay
+    }
+
+    pub fn record(&self, interaction: Interaction) {
+        let mut interactions = self.interactions.lock().unwrap();
+        interactions.push(Some(interaction));
+        let file = CassetteFile {
+            interactions: interactions.iter().flatten().cloned().collect(),
+        };
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let result = serde_json::to_string_pretty(&file)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(&temp_path, json).map_err(|e| e.to_string()))
+            .and_then(|_| fs::rename(&temp_path, &self.path).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            warn!("Failed to write cassette {}: {}", self.path.display(), e);
+        }
+    }
+
+    pub fn replay(&self, method: &str, url: &str) -> Option<RecordedResponse> {
+        let path = url_path(url);
+        let mut interactions = self.interactions.lock().unwrap();
+        let slot = interactions.iter_mut().find(|slot| matches!(
+            slot,
+            Some(interaction) if interaction.request.method == method && url_path(&interaction.request.url) == path
+        ))?;
+        slot.take().map(|interaction| interaction.response)
+    }
+}
+
+fn url_path(url: &str) -> String {
+    Url::parse(url)
+        .map(|url| url.path().to_string())
+        .unwrap_or_else(|_| url.to_string())
+}
+
+pub fn redact_headers(headers: &HeaderMap) -> BTreeMap<String, String> {
+    headers.iter().map(|(name, value)| {
+        let value = if SAFE_HEADERS.contains(&name.as_str()) {
+            value.to_str().unwrap_or(REDACTED).to_string()
+        } else {
+            REDACTED.to_string()
+        };
+        (name.to_string(), value)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn interaction(url: &str, body: &str) -> Interaction {
+        Interaction {
+            request: RecordedRequest {
+                method: "POST".to_string(),
+                url: url.to_string(),
+                headers: BTreeMap::new(),
+                body: Value::Null,
+            },
+            response: RecordedResponse {
+                status: 200,
+                headers: BTreeMap::new(),
+                body: body.to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_records_and_replays_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        let recorder = Cassette::open(&CassetteConfig { mode: CassetteMode::Record, path: path.clone() }).unwrap();
+        recorder.record(interaction("https://api.anthropic.com/v1/messages", "first"));
+        recorder.record(interaction("https://api.anthropic.com/v1/messages/count_tokens", "count"));
+        recorder.record(interaction("https://api.anthropic.com/v1/messages", "second"));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let player = Cassette::open(&CassetteConfig { mode: CassetteMode::Replay, path }).unwrap();
+        let body = |url: &str| player.replay("POST", url).map(|r| r.body);
+        assert_eq!(body("https://gateway.internal/v1/messages").as_deref(), Some("first"));
+        assert_eq!(body("https://api.anthropic.com/v1/messages").as_deref(), Some("second"));
+        assert_eq!(body("https://api.anthropic.com/v1/messages/count_tokens").as_deref(), Some("count"));
+        assert_eq!(body("https://api.anthropic.com/v1/messages"), None);
+    }
+
+    #[test]
+    fn test_redacts_credentials() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", HeaderValue::from_static("sk-secret"));
+        headers.insert("authorization", HeaderValue::from_static("Bearer sk-secret"));
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+
+        let redacted = redact_headers(&headers);
+        assert_eq!(redacted["x-api-key"], REDACTED);
+        assert_eq!(redacted["authorization"], REDACTED);
+        assert_eq!(redacted["content-type"], "application/json");
+    }
+}
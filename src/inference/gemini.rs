@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+use reqwest::header::HeaderMap;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::chat::{CommonMessage, ContentItem, Role};
+use crate::models::{EndpointConfig, ModelSpec};
+use super::anthropic::AnthropicTools;
+use super::inference::Inference;
+use super::http::{header_map, HttpClient};
+use super::types::{generate_tool_id, InferenceError, InferenceOptions, ModelResponse, SamplingParams};
+use super::tools::{GeminiFunctionDeclaration, GeminiTool};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiRequest {
+    contents: Vec<GeminiContent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<GeminiContent>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<GeminiTool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generation_config: Option<GenerationConfig>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerationConfig {
+    max_output_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking_config: Option<ThinkingConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+struct ThinkingConfig {
+    thinking_budget: u32,
+    include_thoughts: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TokenCountRequest {
+    generate_content_request: GeminiRequestWithModel,
+}
+
+#[derive(Serialize)]
+struct GeminiRequestWithModel {
+    model: String,
+    #[serde(flatten)]
+    request: GeminiRequest,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TokenCountResponse {
+    total_tokens: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct GeminiContent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<String>,
+    #[serde(default)]
+    parts: Vec<GeminiPart>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct GeminiPart {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function_call: Option<GeminiFunctionCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function_response: Option<GeminiFunctionResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    inline_data: Option<GeminiInlineData>,
+    /// Marks text parts holding the model's thought summary
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thought: Option<bool>,
+    /// Opaque signature on function calls from thinking models, sent back as received
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thought_signature: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct GeminiInlineData {
+    mime_type: String,
+    data: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct GeminiFunctionCall {
+    name: String,
+    #[serde(default)]
+    args: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct GeminiFunctionResponse {
+    name: String,
+    response: Value,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiResponse {
+    #[serde(default)]
+    candidates: Vec<GeminiCandidate>,
+    model_version: Option<String>,
+    usage_metadata: Option<UsageMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiCandidate {
+    content: Option<GeminiContent>,
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UsageMetadata {
+    #[serde(default)]
+    total_token_count: u64,
+}
+
+/*
+    * Gemini has no notion of tool use ids, function responses are matched to function calls by
+    * name.  Ids are generated when a response is parsed and mapped back to names from the
+    * preceding tool uses when the conversation is sent again.
+*/
+fn to_gemini_contents(messages: Vec<CommonMessage>) -> Vec<GeminiContent> {
+    let mut tool_names: HashMap<String, String> = HashMap::new();
+    messages.into_iter().map(|msg| {
+        let role = match msg.role {
+            Role::Assistant => "model",
+            _ => "user",
+        };
+        let parts = msg.content.into_iter().filter_map(|content_item| {
+            let part = match content_item {
+                ContentItem::Text { text } => GeminiPart {
+                    text: Some(text),
+                    ..Default::default()
+                },
+                ContentItem::ToolUse { id, name, input, signature } => {
+                    tool_names.insert(id, name.clone());
+                    GeminiPart {
+                        function_call: Some(GeminiFunctionCall {
+                            name,
+                            args: input,
+                        }),
+                        thought_signature: signature,
+                        ..Default::default()
+                    }
+                },
+                ContentItem::ToolResult { tool_use_id, content } => {
+                    let name = tool_names.get(&tool_use_id)
+                        .cloned()
+                        .unwrap_or(tool_use_id);
+                    GeminiPart {
+                        function_response: Some(GeminiFunctionResponse {
+                            name,
+                            response: serde_json::json!({ "content": content }),
+                        }),
+                        ..Default::default()
+                    }
+                },
+                ContentItem::Image { source } => GeminiPart {
+                    inline_data: Some(GeminiInlineData {
+                        mime_type: source.media_type,
+                        data: source.data,
+                    }),
+                    ..Default::default()
+                },
+                // Thought summaries are for display only and are not sent back
+                ContentItem::Thinking { .. } | ContentItem::RedactedThinking { .. } | ContentItem::Reasoning { .. } => return None,
+            };
+            Some(part)
+        }).collect();
+        GeminiContent {
+            role: Some(role.to_string()),
+            parts,
+        }
+    }).collect()
+}
+
+pub struct GeminiInference {
+    model: String,
+    client: HttpClient,
+    api_url: String,
+    api_key: String,
+    max_output_tokens: u32,
+    thinking: Option<ThinkingConfig>,
+    sampling: SamplingParams,
+    endpoint: EndpointConfig,
+    tools: Vec<GeminiTool>,
+}
+
+impl GeminiInference {
+    fn function_declarations() -> Vec<GeminiTool> {
+        let function_declarations = AnthropicTools::new()
+            .get_tools()
+            .into_iter()
+            .map(GeminiFunctionDeclaration::from)
+            .collect();
+        vec![GeminiTool { function_declarations }]
+    }
+
+    fn build_request(&self, messages: Vec<CommonMessage>, system_message: Option<&str>) -> GeminiRequest {
+        GeminiRequest {
+            contents: to_gemini_contents(messages),
+            system_instruction: system_message.map(|sys_msg| GeminiContent {
+                role: None,
+                parts: vec![GeminiPart {
+                    text: Some(sys_msg.to_string()),
+                    ..Default::default()
+                }],
+            }),
+            tools: self.tools.clone(),
+            generation_config: Some(GenerationConfig {
+                max_output_tokens: self.max_output_tokens,
+                thinking_config: self.thinking,
+                temperature: self.sampling.temperature,
+                top_p: self.sampling.top_p,
+                stop_sequences: self.sampling.stop().to_vec(),
+            }),
+        }
+    }
+
+    fn endpoint(&self, method: &str) -> String {
+        format!("{}/{}:{}", self.api_url.trim_end_matches('/'), self.model, method)
+    }
+
+    fn headers(&self) -> Result<HeaderMap, InferenceError> {
+        header_map(&[
+            ("Content-Type", "application/json"),
+            ("x-goog-api-key", &self.api_key),
+        ], &self.endpoint.headers).map_err(InferenceError::InvalidConfig)
+    }
+}
+
+#[async_trait]
+impl Inference for GeminiInference {
+    fn new(
+        model: ModelSpec,
+        api_key: String,
+        options: InferenceOptions
+    ) -> Self {
+        GeminiInference {
+            model: model.name,
+            client: options.client,
+            api_url: model.api_url,
+            api_key,
+            max_output_tokens: options.max_output_tokens,
+            thinking: options.thinking_budget.map(|thinking_budget| ThinkingConfig {
+                thinking_budget,
+                include_thoughts: true,
+            }),
+            sampling: options.sampling,
+            endpoint: model.endpoint,
+            // Models without native tools are wrapped in PromptedToolInference
+            tools: if model.quirks.prompted_tools { Vec::new() } else { Self::function_declarations() },
+        }
+    }
+
+    async fn query_model(&self, messages: Vec<CommonMessage>, system_message: Option<&str>) -> Result<ModelResponse, InferenceError> {
+        if self.api_key.is_empty() {
+            return Err(InferenceError::MissingApiKey("Gemini API key not found".to_string()));
+        }
+
+        let request = self.build_request(messages, system_message);
+
+        let response_text = self.client
+            .post(&self.endpoint("generateContent"), self.headers()?, &request)
+            .await?
+            .text()?;
+
+        let gemini_response: GeminiResponse = serde_json::from_str(&response_text)
+            .map_err(|e| InferenceError::InvalidResponse(format!("Failed to parse Gemini response: {}", e)))?;
+
+        let candidate = gemini_response.candidates.into_iter().next()
+            .ok_or_else(|| InferenceError::InvalidResponse("No candidates in Gemini response".to_string()))?;
+
+        let content: Vec<ContentItem> = candidate.content
+            .map(|c| c.parts)
+            .unwrap_or_default()
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, part)| {
+                if let Some(call) = part.function_call {
+                    Some(ContentItem::ToolUse {
+                        id: generate_tool_id(&call.name, i),
+                        name: call.name,
+                        input: call.args,
+                        signature: part.thought_signature,
+                    })
+                } else if part.thought == Some(true) {
+                    part.text.map(|thinking| ContentItem::Thinking { thinking, signature: None })
+                } else {
+                    part.text.map(|text| ContentItem::Text { text })
+                }
+            })
+            .collect();
+
+        Ok(ModelResponse {
+            content,
+            model: gemini_response.model_version.unwrap_or_else(|| self.model.clone()),
+            role: Role::Assistant.to_string(),
+            message_type: "text".to_string(),
+            stop_reason: candidate.finish_reason.unwrap_or_default(),
+            stop_sequence: None,
+            total_tokens: gemini_response.usage_metadata
+                .map(|u| u.total_token_count)
+                .unwrap_or_default(),
+        })
+    }
+
+    async fn get_token_count(&self, messages: Vec<CommonMessage>, system_message: Option<&str>) -> Result<u64, InferenceError> {
+        if self.api_key.is_empty() {
+            return Err(InferenceError::MissingApiKey("Gemini API key not found".to_string()));
+        }
+
+        let request = TokenCountRequest {
+            generate_content_request: GeminiRequestWithModel {
+                model: format!("models/{}", self.model),
+                request: self.build_request(messages, system_message),
+            },
+        };
+
+        let response_text = self.client
+            .post(&self.endpoint("countTokens"), self.headers()?, &request)
+            .await?
+            .text()?;
+
+        let token_count_response: TokenCountResponse = serde_json::from_str(&response_text)
+            .map_err(|e| InferenceError::InvalidResponse(e.to_string()))?;
+
+        Ok(token_count_response.total_tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tool_result_maps_to_function_name() {
+        let messages = vec![
+            CommonMessage {
+                role: Role::Assistant,
+                content: vec![ContentItem::ToolUse {
+                    id: "read_file-1-0".to_string(),
+                    name: "read_file".to_string(),
+                    input: serde_json::json!({ "path": "src/main.rs" }),
+                    signature: Some("c2lnbmF0dXJl".to_string()),
+                }],
+                model: None,
+            },
+            CommonMessage {
+                role: Role::User,
+                content: vec![ContentItem::ToolResult {
+                    tool_use_id: "read_file-1-0".to_string(),
+                    content: "fn main() {}".to_string(),
+                }],
+                model: None,
+            },
+        ];
+
+        let contents = serde_json::to_value(to_gemini_contents(messages)).unwrap();
+        assert_eq!(contents[0]["role"], "model");
+        assert_eq!(contents[0]["parts"][0]["functionCall"]["name"], "read_file");
+        assert_eq!(contents[0]["parts"][0]["thoughtSignature"], "c2lnbmF0dXJl");
+        let part: GeminiPart = serde_json::from_value(contents[0]["parts"][0].clone()).unwrap();
+        assert_eq!(part.thought_signature.as_deref(), Some("c2lnbmF0dXJl"));
+        assert_eq!(contents[1]["role"], "user");
+        assert_eq!(contents[1]["parts"][0]["functionResponse"]["name"], "read_file");
+        assert_eq!(contents[1]["parts"][0]["functionResponse"]["response"]["content"], "fn main() {}");
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Certificate, Client, Proxy, StatusCode};
+use serde::Serialize;
+
+use crate::config::{CassetteConfig, HttpConfig};
+use super::cassette::{redact_headers, Cassette, Interaction, RecordedRequest, RecordedResponse};
+use super::types::{retry_after, InferenceError};
+
+/// Client shared by all inference backends, optionally recording to or replaying from a cassette.
+#[derive(Clone, Default)]
+pub struct HttpClient {
+    client: Client,
+    cassette: Option<Arc<Cassette>>,
+}
+
+impl std::fmt::Debug for HttpClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("HttpClient")
+            .field("cassette", &self.cassette.as_ref().map(|c| c.is_replay()))
+            .finish()
+    }
+}
+
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub retry_after: Option<Duration>,
+    pub body: String,
+}
+
+impl HttpResponse {
+    /// Body of a successful response, otherwise the error for the status.
+    pub fn text(self) -> Result<String, InferenceError> {
+        if !self.status.is_success() {
+            return Err(InferenceError::from_status(self.status, self.retry_after, self.body));
+        }
+        Ok(self.body)
+    }
+}
+
+impl HttpClient {
+    pub fn new(http: &HttpConfig, cassette: Option<&CassetteConfig>) -> Result<Self, String> {
+        Ok(HttpClient {
+            client: build_client(http)?,
+            cassette: cassette.map(Cassette::open).transpose()?.map(Arc::new),
+        })
+    }
+
+    pub async fn post<T: Serialize>(&self, url: &str, headers: HeaderMap, body: &T) -> Result<HttpResponse, InferenceError> {
+        if let Some(cassette) = self.cassette.as_ref().filter(|c| c.is_replay()) {
+            let recorded = cassette.replay("POST", url)
+                .ok_or_else(|| InferenceError::NetworkError(format!("No recorded response left for {}", url)))?;
+            let status = StatusCode::from_u16(recorded.status)
+                .map_err(|e| InferenceError::InvalidResponse(e.to_string()))?;
+            let headers: HeaderMap = recorded.headers.iter()
+                .filter_map(|(name, value)| Some((
+                    HeaderName::from_bytes(name.as_bytes()).ok()?,
+                    HeaderValue::from_str(value).ok()?,
+                )))
+                .collect();
+            return Ok(HttpResponse {
+                status,
+                retry_after: retry_after(&headers),
+                body: recorded.body,
+            });
+        }
+
+        let recorded_headers = redact_headers(&headers);
+        let response = self.client
+            .post(url)
+            .headers(headers)
+            .json(body)
+            .send()
+            .await
+            .map_err(|e| InferenceError::NetworkError(e.to_string()))?;
+
+        let status = response.status();
+        let response_headers = response.headers().clone();
+        let response_text = response.text().await
+            .map_err(|e| InferenceError::NetworkError(e.to_string()))?;
+
+        if let Some(cassette) = &self.cassette {
+            cassette.record(Interaction {
+                request: RecordedRequest {
+                    method: "POST".to_string(),
+                    url: url.to_string(),
+                    headers: recorded_headers,
+                    body: serde_json::to_value(body).unwrap_or_default(),
+                },
+                response: RecordedResponse {
+                    status: status.as_u16(),
+                    headers: response_headers.iter()
+                        .filter(|(name, _)| name.as_str().starts_with("retry-after"))
+                        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+                        .collect(),
+                    body: response_text.clone(),
+                },
+            });
+        }
+
+        Ok(HttpResponse {
+            status,
+            retry_after: retry_after(&response_headers),
+            body: response_text,
+        })
+    }
+}
+
+/// Builds the reqwest client with the proxy and certificates from pprog.toml.
+pub fn build_client(config: &HttpConfig) -> Result<Client, String> {
+    let mut builder = Client::builder();
+    if let Some(proxy) = &config.proxy {
+        let proxy = Proxy::all(proxy).map_err(|e| format!("invalid proxy '{}': {}", proxy, e))?;
+        builder = builder.proxy(proxy);
+    }
+    for path in &config.ca_certs {
+        let pem = fs::read(path)
+            .map_err(|e| format!("unable to read CA certificate {}: {}", path.display(), e))?;
+        let certificate = Certificate::from_pem(&pem)
+            .map_err(|e| format!("invalid CA certificate {}: {}", path.display(), e))?;
+        builder = builder.add_root_certificate(certificate);
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+/// Provider's default headers with the endpoint's headers added, replacing any with the same name.
+pub fn header_map(defaults: &[(&str, &str)], headers: &HashMap<String, String>) -> Result<HeaderMap, String> {
+    let custom = headers.iter().map(|(name, value)| (name.as_str(), value.as_str()));
+    let mut map = HeaderMap::new();
+    for (name, value) in defaults.iter().copied().chain(custom) {
+        let header_name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("invalid header name '{}'", name))?;
+        let header_value = HeaderValue::from_str(value)
+            .map_err(|_| format!("invalid value for header '{}'", name))?;
+        map.insert(header_name, header_value);
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoint_headers_replace_defaults() {
+        let headers = HashMap::from([
+            ("anthropic-version".to_string(), "2024-01-01".to_string()),
+            ("X-Team".to_string(), "platform".to_string()),
+        ]);
+        let map = header_map(&[("anthropic-version", "2023-06-01"), ("X-API-Key", "key")], &headers).unwrap();
+
+        assert_eq!(map.len(), 3);
+        assert_eq!(map["anthropic-version"], "2024-01-01");
+        assert_eq!(map["x-team"], "platform");
+
+        let invalid = HashMap::from([("bad header".to_string(), "value".to_string())]);
+        assert!(header_map(&[], &invalid).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_replays_recorded_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        fs::write(&path, serde_json::json!({ "interactions": [{
+            "request": { "method": "POST", "url": "https://api.anthropic.com/v1/messages", "headers": {}, "body": null },
+            "response": { "status": 429, "headers": { "retry-after": "3" }, "body": "rate limited" }
+        }] }).to_string()).unwrap();
+
+        let cassette = CassetteConfig { mode: crate::config::CassetteMode::Replay, path };
+        let client = HttpClient::new(&HttpConfig::default(), Some(&cassette)).unwrap();
+        let url = "http://localhost:1/v1/messages";
+        let response = client.post(url, HeaderMap::new(), &serde_json::json!({})).await.unwrap();
+        assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.retry_after, Some(Duration::from_secs(3)));
+        assert!(response.text().is_err());
+        assert!(client.post(url, HeaderMap::new(), &serde_json::json!({})).await.is_err());
+    }
+}
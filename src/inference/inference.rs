@@ -2,15 +2,15 @@ use anyhow::Result;
 use async_trait::async_trait;
 
 use crate::chat::CommonMessage;
-use super::types::{InferenceError, ModelResponse};
+use crate::models::ModelSpec;
+use super::types::{InferenceError, InferenceOptions, ModelResponse};
 
 #[async_trait]
 pub trait Inference: Send + Sync {
     fn new(
-        model: String,
-        api_url: String,
+        model: ModelSpec,
         api_key: String,
-        max_output_tokens: u32
+        options: InferenceOptions
     ) -> Self where Self: Sized;
     
     async fn query_model(
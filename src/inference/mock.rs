@@ -0,0 +1,162 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::sync::Mutex;
+use anyhow::Result;
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+use crate::chat::{CommonMessage, ContentItem, Role};
+use crate::models::ModelSpec;
+use super::inference::Inference;
+use super::types::{InferenceError, InferenceOptions, ModelResponse};
+
+/*
+    * Offline backend for tests and demos, selected with `provider = "mock"`.
+    *
+    * `fixture` is the path of a JSON file holding the scripted responses, each query takes the
+    * next one.  An entry is either a response with the usual content items, including tool uses,
+    * or an error.  Errors with a status are treated like an HTTP error from the provider, without
+    * one like a network failure.
+    *
+    *     { "responses": [
+    *         { "content": [{ "type": "text", "text": "Hello" }] },
+    *         { "error": { "status": 429, "message": "slow down" } }
+    *     ] }
+    *
+    * Token counts are the number of characters in the messages, the system message is left out
+    * since it holds the project tree.
+*/
+pub struct MockInference {
+    model: String,
+    responses: Result<Mutex<VecDeque<MockResponse>>, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MockScript {
+    responses: VecDeque<MockResponse>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum MockResponse {
+    Error {
+        error: MockError,
+    },
+    Response {
+        content: Vec<ContentItem>,
+        #[serde(default)]
+        stop_reason: Option<String>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct MockError {
+    #[serde(default)]
+    status: Option<u16>,
+    message: String,
+}
+
+impl MockInference {
+    fn load_script(path: &str) -> Result<MockScript, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read mock script {}: {}", path, e))?;
+        serde_json::from_str(&content)
+            .map_err(|e| format!("Invalid mock script {}: {}", path, e))
+    }
+}
+
+#[async_trait]
+impl Inference for MockInference {
+    fn new(
+        model: ModelSpec,
+        _api_key: String,
+        _options: InferenceOptions
+    ) -> Self {
+        MockInference {
+            responses: model.fixture.as_deref()
+                .ok_or_else(|| format!("Mock model {} has no fixture", model.name))
+                .and_then(Self::load_script)
+                .map(|script| Mutex::new(script.responses)),
+            model: model.name,
+        }
+    }
+
+    async fn query_model(&self, _messages: Vec<CommonMessage>, _system_message: Option<&str>) -> Result<ModelResponse, InferenceError> {
+        let responses = self.responses.as_ref()
+            .map_err(|e| InferenceError::InvalidConfig(e.clone()))?;
+        let response = responses.lock().unwrap().pop_front()
+            .ok_or_else(|| InferenceError::InvalidResponse("Mock script has no responses left".to_string()))?;
+
+        match response {
+            MockResponse::Response { content, stop_reason } => {
+                let has_tool_use = content.iter().any(|c| matches!(c, ContentItem::ToolUse { .. }));
+                Ok(ModelResponse {
+                    content,
+                    model: self.model.clone(),
+                    role: Role::Assistant.to_string(),
+                    message_type: "text".to_string(),
+                    stop_reason: stop_reason.unwrap_or_else(|| {
+                        if has_tool_use { "tool_use" } else { "end_turn" }.to_string()
+                    }),
+                    stop_sequence: None,
+                    total_tokens: 0,
+                })
+            },
+            MockResponse::Error { error } => Err(match error.status.and_then(|s| StatusCode::from_u16(s).ok()) {
+                Some(status) => InferenceError::from_status(status, None, error.message),
+                None => InferenceError::NetworkError(error.message),
+            }),
+        }
+    }
+
+    async fn get_token_count(&self, messages: Vec<CommonMessage>, _system_message: Option<&str>) -> Result<u64, InferenceError> {
+        let count = messages.iter()
+            .flat_map(|message| &message.content)
+            .map(|content_item| match content_item {
+                ContentItem::Text { text } => text.chars().count(),
+                ContentItem::ToolUse { input, .. } => input.to_string().chars().count(),
+                ContentItem::ToolResult { content, .. } => content.chars().count(),
+                ContentItem::Thinking { thinking, .. } => thinking.chars().count(),
+                ContentItem::RedactedThinking { data } => data.len(),
+                ContentItem::Reasoning { summary, .. } => summary.chars().count(),
+                ContentItem::Image { source } => source.data.len(),
+            })
+            .sum::<usize>();
+        Ok(count as u64)
+    }
+}
+
+/// Config using a mock script with the given responses, the directory holds the script and has
+/// to outlive the config.  Checkpoints are off so tests don't write refs into the repository
+/// they run in.
+#[cfg(test)]
+pub fn mock_config(responses: serde_json::Value) -> (crate::config::ProjectConfig, tempfile::TempDir) {
+    use crate::config::{GitConfig, ProjectConfig, Provider, RetryConfig};
+
+    let dir = tempfile::tempdir().unwrap();
+    let script = write_script(&dir, "script.json", responses);
+    let config = ProjectConfig {
+        model: "mock-model".to_string(),
+        provider: Provider::Mock,
+        api_url: String::new(),
+        fixture: Some(script),
+        retry: RetryConfig {
+            max_attempts: 1,
+            ..Default::default()
+        },
+        git: GitConfig {
+            checkpoints: false,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    (config, dir)
+}
+
+#[cfg(test)]
+pub fn write_script(dir: &tempfile::TempDir, name: &str, responses: serde_json::Value) -> String {
+    let path = dir.path().join(name);
+    fs::write(&path, serde_json::json!({ "responses": responses }).to_string()).unwrap();
+    path.to_string_lossy().to_string()
+}
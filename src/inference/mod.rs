@@ -1,9 +1,21 @@
+#[allow(clippy::module_inception)]
 pub mod inference;
 pub mod anthropic;
+pub mod cassette;
+pub mod gemini;
+pub mod http;
+pub mod mock;
 pub mod openai;
+pub mod prompted;
+pub mod responses;
+pub mod retry;
 pub mod tools;
 pub mod types;
 
 // Re-export the inference types
 pub use anthropic::AnthropicInference;
+pub use gemini::GeminiInference;
+pub use mock::MockInference;
 pub use openai::OpenAIInference;
+pub use prompted::PromptedToolInference;
+pub use responses::OpenAIResponsesInference;
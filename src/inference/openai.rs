@@ -1,11 +1,14 @@
 use std::collections::HashMap;
-use reqwest::Client;
+use reqwest::header::HeaderMap;
+use reqwest::Url;
 use serde::{Serialize, Deserialize};
 use anyhow::Result;
 use async_trait::async_trait;
 
 use crate::chat::{CommonMessage, ContentItem, Role};
-use super::types::{InferenceError, ModelResponse};
+use crate::models::{AuthStyle, EndpointConfig, ModelQuirks, ModelSpec};
+use super::http::{header_map, HttpClient};
+use super::types::{InferenceError, InferenceOptions, ModelResponse, SamplingParams};
 use super::tools::{OpenAITool, OpenAIToolFunction, InputSchema, PropertySchema};
 use super::inference::Inference;
 
@@ -15,6 +18,9 @@ struct OpenAIRequest {
     messages: Vec<OpenAIMessage>,
     max_completion_tokens: Option<u32>,
     tools: Option<serde_json::Value>,
+    // Field names match the OpenAI API
+    #[serde(flatten)]
+    sampling: SamplingParams,
 }
 
 #[derive(Serialize)]
@@ -23,6 +29,9 @@ struct LegacyOpenAIRequest {
     messages: Vec<OpenAIMessage>,
     max_tokens: Option<u32>,
     tools: Option<serde_json::Value>,
+    // Field names match the OpenAI API
+    #[serde(flatten)]
+    sampling: SamplingParams,
 }
 
 #[derive(Debug, Deserialize)]
@@ -46,13 +55,28 @@ struct OpenAIMessage {
     tool_calls: Option<Vec<OpenAIToolCall>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     tool_call_id: Option<String>,
+    // Returned by DeepSeek reasoning models, never sent back
+    #[serde(default, skip_serializing)]
+    reasoning_content: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(untagged)]
 enum OpenAIContent {
     String(String),
-    Array(Vec<ContentItem>),
+    Array(Vec<OpenAIContentPart>),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OpenAIContentPart {
+    Text { text: String },
+    ImageUrl { image_url: OpenAIImageUrl },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct OpenAIImageUrl {
+    url: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -70,7 +94,7 @@ struct OpenAIFunctionCall {
 }
 
 pub struct OpenAIToolProvider {
-    tools: Vec<OpenAITool>,
+    pub tools: Vec<OpenAITool>,
 }
 
 impl OpenAIToolProvider {
@@ -78,9 +102,15 @@ impl OpenAIToolProvider {
         Self {
             tools: vec![
                 Self::read_file_tool(),
+                Self::read_image_tool(),
                 Self::write_file_tool(),
                 Self::execute_tool(),
                 Self::compile_check_tool(),
+                Self::git_status_tool(),
+                Self::git_log_tool(),
+                Self::git_blame_tool(),
+                Self::git_show_tool(),
+                Self::repo_map_tool(),
             ],
         }
     }
@@ -114,6 +144,31 @@ impl OpenAIToolProvider {
         }
     }
 
+    fn read_image_tool() -> OpenAITool {
+        OpenAITool {
+            tool_type: "function".to_string(),
+            function: OpenAIToolFunction {
+                name: "read_image".to_string(),
+                description: "View a PNG, JPEG, GIF or WebP image using path relative to root directory of project.".to_string(),
+                parameters: InputSchema {
+                    schema_type: "object".to_string(),
+                    properties: {
+                        let mut map = HashMap::new();
+                        map.insert(
+                            "path".to_string(),
+                            PropertySchema {
+                                property_type: "string".to_string(),
+                                description: "The image path relative to the project root directory".to_string(),
+                            },
+                        );
+                        map
+                    },
+                    required: vec!["path".to_string()],
+                },
+            },
+        }
+    }
+
     fn write_file_tool() -> OpenAITool {
         OpenAITool {
             tool_type: "function".to_string(),
@@ -185,167 +240,309 @@ impl OpenAIToolProvider {
             },
         }
     }
+
+    fn git_status_tool() -> OpenAITool {
+        OpenAITool {
+            tool_type: "function".to_string(),
+            function: OpenAIToolFunction {
+                name: "git_status".to_string(),
+                description: "Show files that are staged, modified, deleted or untracked in the git repository.".to_string(),
+                parameters: InputSchema {
+                    schema_type: "object".to_string(),
+                    properties: HashMap::new(),
+                    required: vec![],
+                },
+            },
+        }
+    }
+
+    fn git_log_tool() -> OpenAITool {
+        OpenAITool {
+            tool_type: "function".to_string(),
+            function: OpenAIToolFunction {
+                name: "git_log".to_string(),
+                description: "List commits from newest to oldest with short id, date, author and summary.".to_string(),
+                parameters: InputSchema {
+                    schema_type: "object".to_string(),
+                    properties: {
+                        let mut map = HashMap::new();
+                        map.insert(
+                            "path".to_string(),
+                            PropertySchema {
+                                property_type: "string".to_string(),
+                                description: "Only list commits that changed this file or directory".to_string(),
+                            },
+                        );
+                        map.insert(
+                            "max_count".to_string(),
+                            PropertySchema {
+                                property_type: "integer".to_string(),
+                                description: "Maximum number of commits to list, 20 by default".to_string(),
+                            },
+                        );
+                        map
+                    },
+                    required: vec![],
+                },
+            },
+        }
+    }
+
+    fn git_blame_tool() -> OpenAITool {
+        OpenAITool {
+            tool_type: "function".to_string(),
+            function: OpenAIToolFunction {
+                name: "git_blame".to_string(),
+                description: "Show the commit, date and author that last changed each line of a file.".to_string(),
+                parameters: InputSchema {
+                    schema_type: "object".to_string(),
+                    properties: {
+                        let mut map = HashMap::new();
+                        map.insert(
+                            "path".to_string(),
+                            PropertySchema {
+                                property_type: "string".to_string(),
+                                description: "The file path relative to the project root directory".to_string(),
+                            },
+                        );
+                        map.insert(
+                            "start_line".to_string(),
+                            PropertySchema {
+                                property_type: "integer".to_string(),
+                                description: "First line to show, starting at 1".to_string(),
+                            },
+                        );
+                        map.insert(
+                            "end_line".to_string(),
+                            PropertySchema {
+                                property_type: "integer".to_string(),
+                                description: "Last line to show".to_string(),
+                            },
+                        );
+                        map
+                    },
+                    required: vec!["path".to_string()],
+                },
+            },
+        }
+    }
+
+    fn git_show_tool() -> OpenAITool {
+        OpenAITool {
+            tool_type: "function".to_string(),
+            function: OpenAIToolFunction {
+                name: "git_show".to_string(),
+                description: "Show a commit with its changes, or a file or directory at a revision like HEAD~1:src/main.rs.".to_string(),
+                parameters: InputSchema {
+                    schema_type: "object".to_string(),
+                    properties: {
+                        let mut map = HashMap::new();
+                        map.insert(
+                            "revision".to_string(),
+                            PropertySchema {
+                                property_type: "string".to_string(),
+                                description: "Commit, branch, tag or revision:path to show".to_string(),
+                            },
+                        );
+                        map
+                    },
+                    required: vec!["revision".to_string()],
+                },
+            },
+        }
+    }
+
+    fn repo_map_tool() -> OpenAITool {
+        OpenAITool {
+            tool_type: "function".to_string(),
+            function: OpenAIToolFunction {
+                name: "repo_map".to_string(),
+                description: "List the functions, types and classes declared in each source file under a directory.".to_string(),
+                parameters: InputSchema {
+                    schema_type: "object".to_string(),
+                    properties: {
+                        let mut map = HashMap::new();
+                        map.insert(
+                            "path".to_string(),
+                            PropertySchema {
+                                property_type: "string".to_string(),
+                                description: "Directory relative to the project root, the whole project by default".to_string(),
+                            },
+                        );
+                        map
+                    },
+                    required: vec![],
+                },
+            },
+        }
+    }
+}
+
+/*
+    * Tool uses in one assistant message become entries of its tool_calls and each tool result
+    * becomes its own tool message, in the order the calls were made.
+*/
+fn to_openai_messages(messages: Vec<CommonMessage>) -> Vec<OpenAIMessage> {
+    messages.into_iter().flat_map(|msg| {
+        let mut openai_message = OpenAIMessage {
+            role: msg.role,
+            content: Some(OpenAIContent::String("".to_string())),
+            tool_calls: None,
+            tool_call_id: None,
+            reasoning_content: None,
+        };
+        // Tool results are separate messages, any images sent alongside follow in a user message
+        let mut tool_messages = Vec::new();
+        let mut parts = Vec::new();
+        for content_item in msg.content {
+            match content_item {
+                ContentItem::Text { text } => {
+                    parts.push(OpenAIContentPart::Text { text });
+                },
+                ContentItem::Image { source } => {
+                    parts.push(OpenAIContentPart::ImageUrl {
+                        image_url: OpenAIImageUrl { url: source.data_url() },
+                    });
+                },
+                ContentItem::ToolUse { id, name, input, .. } => {
+                    openai_message.tool_calls.get_or_insert_with(Vec::new).push(OpenAIToolCall {
+                        id,
+                        call_type: "function".to_string(),
+                        function: OpenAIFunctionCall {
+                            name,
+                            arguments: input.to_string(),
+                        }
+                    });
+                },
+                ContentItem::ToolResult { tool_use_id, content } => {
+                    tool_messages.push(OpenAIMessage {
+                        role: Role::Tool,
+                        content: Some(OpenAIContent::String(content)),
+                        tool_calls: None,
+                        tool_call_id: Some(tool_use_id),
+                        reasoning_content: None,
+                    });
+                },
+                // DeepSeek rejects requests that include previous reasoning content
+                ContentItem::Thinking { .. } | ContentItem::RedactedThinking { .. } | ContentItem::Reasoning { .. } => {},
+            }
+        }
+        match parts.len() {
+            0 => {},
+            1 if matches!(parts[0], OpenAIContentPart::Text { .. }) => {
+                if let Some(OpenAIContentPart::Text { text }) = parts.pop() {
+                    openai_message.content = Some(OpenAIContent::String(text));
+                }
+            },
+            _ => openai_message.content = Some(OpenAIContent::Array(parts)),
+        }
+        let has_content = !matches!(&openai_message.content, Some(OpenAIContent::String(text)) if text.is_empty());
+        if tool_messages.is_empty() || has_content || openai_message.tool_calls.is_some() {
+            tool_messages.push(openai_message);
+        }
+        tool_messages
+    }).collect()
 }
 
 pub struct OpenAIInference {
     model: String,
-    client: Client,
+    client: HttpClient,
     api_url: String,
     api_key: String,
     max_output_tokens: u32,
+    sampling: SamplingParams,
+    endpoint: EndpointConfig,
+    quirks: ModelQuirks,
     tool_provider: OpenAIToolProvider,
 }
 
+// Upper bound of what a high detail image costs on OpenAI models
+const IMAGE_TOKEN_ESTIMATE: u64 = 1105;
+
+/// Headers for OpenAI compatible endpoints, shared with the Responses API backend.
+pub(super) fn request_headers(api_key: &str, endpoint: &EndpointConfig) -> Result<HeaderMap, InferenceError> {
+    let bearer;
+    let auth = match endpoint.auth {
+        AuthStyle::Bearer => {
+            bearer = format!("Bearer {}", api_key);
+            ("Authorization", bearer.as_str())
+        },
+        AuthStyle::ApiKey => ("api-key", api_key),
+    };
+    header_map(&[("Content-Type", "application/json"), auth], &endpoint.headers)
+        .map_err(InferenceError::InvalidConfig)
+}
+
+pub(super) fn request_url(api_url: &str, endpoint: &EndpointConfig) -> Result<String, InferenceError> {
+    let mut url = Url::parse(api_url)
+        .map_err(|e| InferenceError::InvalidConfig(format!("invalid api_url '{}': {}", api_url, e)))?;
+    if let Some(api_version) = &endpoint.api_version {
+        url.query_pairs_mut().append_pair("api-version", api_version);
+    }
+    Ok(url.to_string())
+}
+
 #[async_trait]
 impl Inference for OpenAIInference {
     fn new(
-       model: String,
-       api_url: String,
+       model: ModelSpec,
        api_key: String,
-       max_output_tokens: u32
+       options: InferenceOptions
     ) -> Self {
         OpenAIInference {
-            model,
-            client: Client::new(),
-            api_url,
+            model: model.name,
+            client: options.client,
+            api_url: model.api_url,
             api_key,
-            max_output_tokens,
+            max_output_tokens: options.max_output_tokens,
+            sampling: options.sampling,
+            endpoint: model.endpoint,
+            quirks: model.quirks,
             tool_provider: OpenAIToolProvider::new(),
         }
     }
 
 
     async fn query_model(&self, messages: Vec<CommonMessage>, system_message: Option<&str>) -> Result<ModelResponse, InferenceError> {
-        let mut openai_messages: Vec<OpenAIMessage> = messages.into_iter().map(|msg| {
-            let mut openai_message = OpenAIMessage {
-                role: msg.role,
-                content: Some(OpenAIContent::String("".to_string())),
-                tool_calls: None,
-                tool_call_id: None,
-            };
-            for content_item in msg.content {
-                match content_item {
-                    ContentItem::Text { text } => {
-                        openai_message.content = Some(OpenAIContent::String(text));
-                    },
-                    ContentItem::ToolUse { id, name, input } => {
-                        if self.model.as_str() != "deepseek-reasoner" {
-                            openai_message.tool_calls = Some(vec![OpenAIToolCall {
-                                id,
-                                call_type: "function".to_string(),
-                                function: OpenAIFunctionCall {
-                                    name,
-                                    arguments: input.to_string(),
-                                }
-                            }]);
-                        }
-                    },
-                    ContentItem::ToolResult { tool_use_id, content } => {
-                        openai_message.role = Role::Tool;
-                        openai_message.tool_call_id = Some(tool_use_id);
-                        openai_message.content = Some(OpenAIContent::String(content));
-                    }
-                }
-            }
-            openai_message
-        }).collect();
+        let mut openai_messages = to_openai_messages(messages);
 
         if let Some(sys_msg) = system_message {
-            match self.model.as_str() {
-                "o1" | "o1-mini" => {
-                    openai_messages.insert(0, OpenAIMessage {
-                        role: Role::Developer,
-                        content: Some(OpenAIContent::String(sys_msg.to_string())),
-                        tool_calls: None,
-                        tool_call_id: None,
-                    });
-                },
-                "deepseek-reasoner" => {
-                    let mut deepseek_sys_msg = String::new();
-                    deepseek_sys_msg.push_str(sys_msg);
-                    let tools_string = self.tool_provider.get_tools_json().unwrap();
-                    let tools_system_msg = format!(r#"
-Tool definitions:
-{}
-
-When tool is needed return as JSON in format 
-{{ 
-    "name": "function_name", 
-    "inputs": {{ 
-        "first_input_name": "first_input_value", 
-        "second_input_name", "second_input_value", 
-        ... 
-    }} 
-}} 
-surrounded by triple backticks.  
-
-For example if you were going to use a tool called 'read_file' the response would look like 
-```tool_use
-{{ "name": "read_file", "inputs": {{ "path": "index.js" }} }}
-```
-
-Only use one tool at a time. Do not assume anything about contents of files, use read_file instead.
-
-The user may also questions about the code base.  If a user asks a question DO NOT write to the files but instead read files to answer question."#, tools_string.to_string());
-                    deepseek_sys_msg.push_str(&tools_system_msg);
-                    openai_messages.insert(0, OpenAIMessage {
-                        role: Role::System,
-                        content: Some(OpenAIContent::String(deepseek_sys_msg)),
-                        tool_calls: None,
-                        tool_call_id: None,
-                    });
-                },
-                _ => {
-                    openai_messages.insert(0, OpenAIMessage {
-                        role: Role::System,
-                        content: Some(OpenAIContent::String(sys_msg.to_string())),
-                        tool_calls: None,
-                        tool_call_id: None,
-                    });
-                },
-            };
+            let role = if self.quirks.developer_role { Role::Developer } else { Role::System };
+            openai_messages.insert(0, OpenAIMessage {
+                role,
+                content: Some(OpenAIContent::String(sys_msg.to_string())),
+                tool_calls: None,
+                tool_call_id: None,
+                reasoning_content: None,
+            });
         }
 
         let tools = self.tool_provider.get_tools_json()
             .map_err(|e| InferenceError::SerializationError(e.to_string())).ok();
 
-        let request: serde_json::Value = match self.model.as_str() {
-            "o1" | "o1-mini" => serde_json::to_value(OpenAIRequest {
+        // Models without native tools are wrapped in PromptedToolInference
+        let tools = if self.quirks.prompted_tools { None } else { tools };
+        let request: serde_json::Value = match self.quirks.max_completion_tokens {
+            true => serde_json::to_value(OpenAIRequest {
                 model: self.model.clone(),
                 messages: openai_messages,
                 max_completion_tokens: Some(self.max_output_tokens),
                 tools,
+                sampling: self.sampling.clone(),
             }).unwrap(),
-            "deepseek-reasoner" => serde_json::to_value(LegacyOpenAIRequest {
-                model: self.model.clone(),
-                messages: openai_messages,
-                max_tokens: Some(self.max_output_tokens),
-                tools: None,
-            }).unwrap(),
-            _ => serde_json::to_value(LegacyOpenAIRequest {
+            false => serde_json::to_value(LegacyOpenAIRequest {
                 model: self.model.clone(),
                 messages: openai_messages,
                 max_tokens: Some(self.max_output_tokens),
                 tools,
+                sampling: self.sampling.clone(),
             }).unwrap()
         };
 
-        let response = self.client
-            .post(&self.api_url)
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .json(&request)
-            .send()
-            .await
-            .map_err(|e| InferenceError::NetworkError(e.to_string()))?;
-
-        let status = response.status();
-        let response_text = response.text().await
-            .map_err(|e| InferenceError::NetworkError(e.to_string()))?;
-
-        if !status.is_success() {
-            return Err(InferenceError::ApiError(status, response_text));
-        }
+        let response_text = self.client
+            .post(&request_url(&self.api_url, &self.endpoint)?, request_headers(&self.api_key, &self.endpoint)?, &request)
+            .await?
+            .text()?;
 
         let openai_response: OpenAIResponse = serde_json::from_str(&response_text)
             .map_err(|e| InferenceError::InvalidResponse(format!("Failed to parse OpenAI response: {}", e)))?;
@@ -355,6 +552,9 @@ The user may also questions about the code base.  If a user asks a question DO N
         }
         
         let mut content: Vec<ContentItem> = Vec::new();
+        if let Some(reasoning) = openai_response.choices[0].message.reasoning_content.clone() {
+            content.push(ContentItem::Thinking { thinking: reasoning, signature: None });
+        }
         if let Some(openai_content) = openai_response.choices[0].message.content.clone() {
             match openai_content {
                 OpenAIContent::String(text) => content.push(ContentItem::Text { text }),
@@ -369,6 +569,7 @@ The user may also questions about the code base.  If a user asks a question DO N
                         id: tool_call.id.clone(),
                         name: tool_call.function.name.clone(),
                         input,
+                        signature: None,
                     }
                 )
 
@@ -388,38 +589,96 @@ The user may also questions about the code base.  If a user asks a question DO N
     }
 
     async fn get_token_count(&self, messages: Vec<CommonMessage>, system_message: Option<&str>) -> Result<u64, InferenceError> {
-        let mut total_tokens = 0;
-        if let Some(system_message) = system_message {
-            total_tokens += (system_message.len() as u64 + 1) / 2;
-        }
-        for message in messages {
-            for content in message.content {
-                match content {
-                    ContentItem::Text { text } => {
-                        total_tokens += (text.len() as u64 + 1) / 2;
-                    },
-                    ContentItem::ToolUse { input, .. } => {
-                        total_tokens += (input.to_string().len() as u64 + 1) / 2;
-                    },
-                    ContentItem::ToolResult { content, .. } => {
-                        total_tokens += (content.len() as u64 + 1) / 2;
-                    }
-                }
+        Ok(estimate_token_count(&self.tool_provider.tools, messages, system_message))
+    }
+}
+
+/// Rough count for OpenAI compatible models, which have no token counting endpoint.
+pub(super) fn estimate_token_count(tools: &[OpenAITool], messages: Vec<CommonMessage>, system_message: Option<&str>) -> u64 {
+    let mut total_tokens = 0;
+    if let Some(system_message) = system_message {
+        total_tokens += (system_message.len() as u64).div_ceil(2);
+    }
+    for message in messages {
+        for content in message.content {
+            match content {
+                ContentItem::Text { text } => {
+                    total_tokens += (text.len() as u64).div_ceil(2);
+                },
+                ContentItem::ToolUse { input, .. } => {
+                    total_tokens += (input.to_string().len() as u64).div_ceil(2);
+                },
+                ContentItem::ToolResult { content, .. } => {
+                    total_tokens += (content.len() as u64).div_ceil(2);
+                },
+                ContentItem::Image { .. } => {
+                    total_tokens += IMAGE_TOKEN_ESTIMATE;
+                },
+                ContentItem::Thinking { .. } | ContentItem::RedactedThinking { .. } | ContentItem::Reasoning { .. } => {},
             }
         }
-        let tool_token_count: u64 = self.tool_provider.tools.iter().map(|tool| {
-            let tool_name_tokens = (tool.function.name.len() as u64 + 1) / 2;
-            let tool_description_tokens = (tool.function.description.len() as u64 + 1) / 2;
-            let mut param_tokens = 0;
-            for (_, prop) in tool.function.parameters.properties.iter() {
-                param_tokens += (prop.property_type.len() as u64 + 1) / 2;
-                param_tokens += (prop.description.len() as u64 + 1) / 2;
-            }
-            tool_name_tokens + tool_description_tokens + param_tokens
-        }).sum();
-        total_tokens += tool_token_count;
-        Ok(total_tokens)
     }
-
+    let tool_token_count: u64 = tools.iter().map(|tool| {
+        let tool_name_tokens = (tool.function.name.len() as u64).div_ceil(2);
+        let tool_description_tokens = (tool.function.description.len() as u64).div_ceil(2);
+        let mut param_tokens = 0;
+        for (_, prop) in tool.function.parameters.properties.iter() {
+            param_tokens += (prop.property_type.len() as u64).div_ceil(2);
+            param_tokens += (prop.description.len() as u64).div_ceil(2);
+        }
+        tool_name_tokens + tool_description_tokens + param_tokens
+    }).sum();
+    total_tokens += tool_token_count;
+    total_tokens
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multiple_tool_uses_in_one_message() {
+        let messages = vec![
+            CommonMessage {
+                role: Role::Assistant,
+                content: vec![
+                    ContentItem::ToolUse {
+                        id: "call_1".to_string(),
+                        name: "read_file".to_string(),
+                        input: serde_json::json!({ "path": "a.rs" }),
+                        signature: None,
+                    },
+                    ContentItem::ToolUse {
+                        id: "call_2".to_string(),
+                        name: "read_file".to_string(),
+                        input: serde_json::json!({ "path": "b.rs" }),
+                        signature: None,
+                    },
+                ],
+                model: None,
+            },
+            CommonMessage {
+                role: Role::User,
+                content: vec![
+                    ContentItem::ToolResult {
+                        tool_use_id: "call_1".to_string(),
+                        content: "a".to_string(),
+                    },
+                    ContentItem::ToolResult {
+                        tool_use_id: "call_2".to_string(),
+                        content: "b".to_string(),
+                    },
+                ],
+                model: None,
+            },
+        ];
+
+        let openai_messages = serde_json::to_value(to_openai_messages(messages)).unwrap();
+        assert_eq!(openai_messages.as_array().unwrap().len(), 3);
+        assert_eq!(openai_messages[0]["tool_calls"][0]["id"], "call_1");
+        assert_eq!(openai_messages[0]["tool_calls"][1]["id"], "call_2");
+        assert_eq!(openai_messages[1]["role"], "tool");
+        assert_eq!(openai_messages[1]["tool_call_id"], "call_1");
+        assert_eq!(openai_messages[2]["tool_call_id"], "call_2");
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use anyhow::Result;
+use async_trait::async_trait;
+use log::warn;
+use serde_json::Value;
+
+use crate::chat::{CommonMessage, ContentItem, Role};
+use crate::models::ModelSpec;
+use super::anthropic::AnthropicTools;
+use super::inference::Inference;
+use super::types::{generate_tool_id, InferenceError, InferenceOptions, ModelResponse};
+
+/*
+    * Tool calling for models without native tool support.
+    *
+    * Tool definitions are described in the system prompt and the model answers with fenced
+    * ```tool_use blocks in its text.  Outgoing tool uses and results are rewritten as plain text
+    * before reaching the wrapped backend, and tool_use blocks in responses are parsed back into
+    * ContentItem::ToolUse with unique ids.  When a block can't be parsed the model is told what
+    * went wrong and asked again instead of failing the turn.
+*/
+pub struct PromptedToolInference<I: Inference> {
+    inner: I,
+    tool_names: Vec<String>,
+    tools_json: String,
+}
+
+const TOOL_BLOCK_START: &str = "```tool_use";
+// Only a fence at the start of a line closes the block, JSON strings can't hold a raw newline
+const TOOL_BLOCK_END: &str = "\n```";
+const MAX_PARSE_ATTEMPTS: usize = 3;
+
+impl<I: Inference> PromptedToolInference<I> {
+    fn system_message(&self, system_message: Option<&str>) -> String {
+        format!(r#"{}
+
+Tool definitions:
+{}
+
+When a tool is needed respond with a JSON object in the format
+{{ "name": "tool_name", "inputs": {{ "first_input_name": "first_input_value", ... }} }}
+inside a code block fenced with ```tool_use.
+
+For example if you were going to use a tool called 'read_file' the response would contain
+```tool_use
+{{ "name": "read_file", "inputs": {{ "path": "index.js" }} }}
+```
+
+Multiple tools can be used in one response by writing one tool_use block per call.  The results are returned in the next message.  Do not assume anything about contents of files, use read_file instead."#,
+            system_message.unwrap_or(""),
+            self.tools_json,
+        )
+    }
+
+    /// Rewrites tool uses and results as text, the tool_use blocks are already in the assistant text.
+    /// Messages that were only tool uses, from a native tools model, get their blocks written out.
+    fn encode_messages(messages: Vec<CommonMessage>) -> Vec<CommonMessage> {
+        let mut tool_names: HashMap<String, String> = HashMap::new();
+        messages.into_iter().map(|msg| {
+            let mut blocks = Vec::new();
+            let mut content: Vec<ContentItem> = msg.content.into_iter().filter_map(|content_item| match content_item {
+                ContentItem::ToolUse { id, name, input, .. } => {
+                    let call = serde_json::json!({ "name": name, "inputs": input });
+                    blocks.push(format!("{}\n{}{}", TOOL_BLOCK_START, call, TOOL_BLOCK_END));
+                    tool_names.insert(id, name);
+                    None
+                },
+                ContentItem::ToolResult { tool_use_id, content } => {
+                    let name = tool_names.get(&tool_use_id).map(String::as_str).unwrap_or("unknown");
+                    Some(ContentItem::Text {
+                        text: format!("Result of tool '{}' (call {}):\n{}", name, tool_use_id, content),
+                    })
+                },
+                other => Some(other),
+            }).collect();
+            if content.is_empty() && !blocks.is_empty() {
+                content.push(ContentItem::Text { text: blocks.join("\n") });
+            }
+            CommonMessage { content, ..msg }
+        }).collect()
+    }
+
+    fn parse_tool_use(&self, block: &str, index: usize) -> Result<ContentItem, String> {
+        let json: Value = serde_json::from_str(block.trim())
+            .map_err(|e| format!("invalid JSON ({})", e))?;
+        let name = json.get("name")
+            .and_then(Value::as_str)
+            .ok_or_else(|| "missing string field 'name'".to_string())?;
+        if !self.tool_names.iter().any(|n| n == name) {
+            return Err(format!("unknown tool '{}', available tools are {}", name, self.tool_names.join(", ")));
+        }
+        let input = json.get("inputs")
+            .or_else(|| json.get("input"))
+            .cloned()
+            .unwrap_or_else(|| Value::Object(Default::default()));
+        if !input.is_object() {
+            return Err(format!("'inputs' of tool '{}' must be an object", name));
+        }
+        Ok(ContentItem::ToolUse {
+            id: generate_tool_id(name, index),
+            name: name.to_string(),
+            input,
+            signature: None,
+        })
+    }
+
+    /// Appends a ToolUse for each tool_use block in the text, or returns a description of every
+    /// block that could not be parsed.
+    fn decode_content(&self, mut content: Vec<ContentItem>) -> Result<Vec<ContentItem>, Vec<String>> {
+        let mut tool_uses = Vec::new();
+        let mut errors = Vec::new();
+        for content_item in &content {
+            let ContentItem::Text { text } = content_item else { continue };
+            let mut rest = text.as_str();
+            while let Some(start) = rest.find(TOOL_BLOCK_START) {
+                let after_start = &rest[start + TOOL_BLOCK_START.len()..];
+                let Some(end) = after_start.find(TOOL_BLOCK_END) else {
+                    errors.push(format!("tool_use block {} is not closed with ```", tool_uses.len() + errors.len() + 1));
+                    break;
+                };
+                let index = tool_uses.len() + errors.len();
+                match self.parse_tool_use(&after_start[..end], index) {
+                    Ok(tool_use) => tool_uses.push(tool_use),
+                    Err(e) => errors.push(format!("tool_use block {}: {}", index + 1, e)),
+                }
+                rest = &after_start[end + TOOL_BLOCK_END.len()..];
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        content.extend(tool_uses);
+        Ok(content)
+    }
+}
+
+#[async_trait]
+impl<I: Inference> Inference for PromptedToolInference<I> {
+    fn new(
+        model: ModelSpec,
+        api_key: String,
+        options: InferenceOptions
+    ) -> Self {
+        let tools = AnthropicTools::new().get_tools();
+        let tools_json = serde_json::to_string_pretty(&tools).unwrap_or_default();
+        PromptedToolInference {
+            inner: I::new(model, api_key, options),
+            tool_names: tools.into_iter().map(|t| t.name).collect(),
+            tools_json,
+        }
+    }
+
+    async fn query_model(&self, messages: Vec<CommonMessage>, system_message: Option<&str>) -> Result<ModelResponse, InferenceError> {
+        let system = self.system_message(system_message);
+        let mut messages = Self::encode_messages(messages);
+
+        let mut attempt = 1;
+        loop {
+            let response = self.inner.query_model(messages.clone(), Some(&system)).await?;
+            match self.decode_content(response.content.clone()) {
+                Ok(content) => return Ok(ModelResponse { content, ..response }),
+                Err(errors) if attempt < MAX_PARSE_ATTEMPTS => {
+                    warn!("Failed to parse tool use, asking model to retry: {:?}", errors);
+                    messages.push(CommonMessage {
+                        role: Role::Assistant,
+                        content: response.content,
+                        model: None,
+                    });
+                    messages.push(CommonMessage {
+                        role: Role::User,
+                        content: vec![ContentItem::Text {
+                            text: format!(
+                                "Your tool calls could not be parsed:\n- {}\nRespond again with every tool call as valid JSON inside a ```tool_use block.",
+                                errors.join("\n- ")
+                            ),
+                        }],
+                        model: None,
+                    });
+                    attempt += 1;
+                },
+                // Give up on the tool calls but still show the model's answer
+                Err(errors) => {
+                    warn!("Giving up parsing tool use after {} attempts: {:?}", attempt, errors);
+                    return Ok(response);
+                },
+            }
+        }
+    }
+
+    async fn get_token_count(&self, messages: Vec<CommonMessage>, system_message: Option<&str>) -> Result<u64, InferenceError> {
+        let system = self.system_message(system_message);
+        self.inner.get_token_count(Self::encode_messages(messages), Some(&system)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Provider;
+    use crate::inference::OpenAIInference;
+
+    fn inference() -> PromptedToolInference<OpenAIInference> {
+        let model = ModelSpec {
+            name: "deepseek-reasoner".to_string(),
+            provider: Provider::OpenAI,
+            api_url: "https://api.deepseek.com/v1/chat/completions".to_string(),
+            context_window: 64000,
+            max_output_tokens: 8192,
+            api_key_env: None,
+            pricing: None,
+            quirks: Default::default(),
+            endpoint: Default::default(),
+            fixture: None,
+        };
+        PromptedToolInference::new(model, "key".to_string(), InferenceOptions::default())
+    }
+
+    #[test]
+    fn test_decodes_multiple_tool_uses_with_unique_ids() {
+        let text = r#"Reading both files.
+```tool_use
+{ "name": "read_file", "inputs": { "path": "a.rs" } }
+```
+```tool_use
+{ "name": "read_file", "inputs": { "path": "b.rs" } }
+```"#;
+        let content = inference()
+            .decode_content(vec![ContentItem::Text { text: text.to_string() }])
+            .unwrap();
+
+        let ids: Vec<&String> = content.iter().filter_map(|c| match c {
+            ContentItem::ToolUse { id, .. } => Some(id),
+            _ => None,
+        }).collect();
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids[0], ids[1]);
+    }
+
+    #[test]
+    fn test_fences_inside_json_strings_do_not_end_the_block() {
+        let text = r##"Adding usage.
+```tool_use
+{ "name": "write_file", "inputs": { "path": "README.md", "content": "# pprog\n```sh\ncargo run\n```\n" } }
+```"##;
+        let content = inference()
+            .decode_content(vec![ContentItem::Text { text: text.to_string() }])
+            .unwrap();
+
+        match &content[1] {
+            ContentItem::ToolUse { input, .. } => assert_eq!(input["content"], "# pprog\n```sh\ncargo run\n```\n"),
+            other => panic!("expected tool use, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reports_malformed_tool_use() {
+        let text = "```tool_use\n{ \"name\": \"read_file\", \"inputs\": { \"path\": \n```\n```tool_use\n{ \"name\": \"delete_everything\" }\n```";
+        let errors = inference()
+            .decode_content(vec![ContentItem::Text { text: text.to_string() }])
+            .unwrap_err();
+
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].contains("invalid JSON"));
+        assert!(errors[1].contains("unknown tool 'delete_everything'"));
+    }
+
+    #[test]
+    fn test_encodes_tool_results_as_text() {
+        let messages = vec![
+            CommonMessage {
+                role: Role::Assistant,
+                content: vec![ContentItem::ToolUse {
+                    id: "call_1".to_string(),
+                    name: "execute".to_string(),
+                    input: serde_json::json!({ "statement": "ls" }),
+                    signature: None,
+                }],
+                model: None,
+            },
+            CommonMessage {
+                role: Role::User,
+                content: vec![ContentItem::ToolResult {
+                    tool_use_id: "call_1".to_string(),
+                    content: "Cargo.toml".to_string(),
+                }],
+                model: None,
+            },
+        ];
+
+        let encoded = PromptedToolInference::<OpenAIInference>::encode_messages(messages);
+        assert_eq!(encoded[0].content, vec![ContentItem::Text {
+            text: "```tool_use\n{\"inputs\":{\"statement\":\"ls\"},\"name\":\"execute\"}\n```".to_string(),
+        }]);
+        assert_eq!(encoded[1].content, vec![ContentItem::Text {
+            text: "Result of tool 'execute' (call call_1):\nCargo.toml".to_string(),
+        }]);
+    }
+}
@@ -0,0 +1,462 @@
+use std::sync::Mutex;
+use anyhow::Result;
+use async_trait::async_trait;
+use log::warn;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::chat::{CommonMessage, ContentItem, Role};
+use crate::models::{EndpointConfig, ModelSpec};
+use super::http::HttpClient;
+use super::inference::Inference;
+use super::openai::{estimate_token_count, request_headers, request_url, OpenAIToolProvider};
+use super::tools::ResponsesTool;
+use super::types::{InferenceError, InferenceOptions, ModelResponse, ReasoningEffort, SamplingParams};
+
+/*
+    * Backend for the OpenAI Responses API, selected with the `responses_api` quirk.
+    *
+    * The conversation is sent as a list of items instead of messages: text and images become
+    * message items, tool uses and results become function_call and function_call_output items,
+    * and reasoning items are sent back by id so reasoning models keep their chain of thought
+    * across tool calls.
+    *
+    * Responses are stored by OpenAI.  When the conversation continues from the last response
+    * unchanged, only the new messages are sent with `previous_response_id`.  After pruning, a
+    * model switch or a restart the whole conversation is sent again, as it is when the previous
+    * response is no longer available.
+*/
+pub struct OpenAIResponsesInference {
+    model: String,
+    client: HttpClient,
+    api_url: String,
+    api_key: String,
+    max_output_tokens: u32,
+    sampling: SamplingParams,
+    endpoint: EndpointConfig,
+    reasoning: bool,
+    tool_provider: OpenAIToolProvider,
+    tools: Vec<ResponsesTool>,
+    previous: Mutex<Option<PreviousResponse>>,
+}
+
+/// Last response and the conversation it ended, including its own message.
+struct PreviousResponse {
+    id: String,
+    messages: Vec<CommonMessage>,
+}
+
+#[derive(Serialize)]
+struct ResponsesRequest<'a> {
+    model: &'a str,
+    input: Vec<InputItem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instructions: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_response_id: Option<&'a str>,
+    max_output_tokens: u32,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    tools: &'a [ResponsesTool],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning: Option<ReasoningConfig>,
+    store: bool,
+}
+
+#[derive(Serialize)]
+struct ReasoningConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    effort: Option<ReasoningEffort>,
+    summary: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum InputItem {
+    Message {
+        role: Role,
+        content: MessageContent,
+    },
+    FunctionCall {
+        call_id: String,
+        name: String,
+        arguments: String,
+    },
+    FunctionCallOutput {
+        call_id: String,
+        output: String,
+    },
+    Reasoning {
+        id: String,
+        summary: Vec<Summary>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum MessageContent {
+    Text(String),
+    Parts(Vec<InputContent>),
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum InputContent {
+    InputText { text: String },
+    InputImage { image_url: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Summary {
+    SummaryText { text: String },
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponsesResponse {
+    id: String,
+    model: String,
+    #[serde(default)]
+    incomplete_details: Option<IncompleteDetails>,
+    output: Vec<OutputItem>,
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IncompleteDetails {
+    reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Usage {
+    total_tokens: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OutputItem {
+    Message {
+        content: Vec<OutputContent>,
+    },
+    FunctionCall {
+        call_id: String,
+        name: String,
+        arguments: String,
+    },
+    Reasoning {
+        id: String,
+        #[serde(default)]
+        summary: Vec<Summary>,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OutputContent {
+    OutputText { text: String },
+    Refusal { refusal: String },
+    #[serde(other)]
+    Other,
+}
+
+/*
+    * Items keep the order of the content they come from, a reasoning item has to come before the
+    * function calls it led to.  Assistant text is sent as a plain string since assistant messages
+    * only accept output content.
+*/
+fn to_input_items(messages: Vec<CommonMessage>) -> Vec<InputItem> {
+    let mut items = Vec::new();
+    for message in messages {
+        let mut parts = Vec::new();
+        for content_item in message.content {
+            match content_item {
+                ContentItem::Text { text } => parts.push(InputContent::InputText { text }),
+                ContentItem::Image { source } => parts.push(InputContent::InputImage { image_url: source.data_url() }),
+                ContentItem::ToolUse { id, name, input, .. } => {
+                    flush_message(&mut items, &message.role, &mut parts);
+                    items.push(InputItem::FunctionCall { call_id: id, name, arguments: input.to_string() });
+                },
+                ContentItem::ToolResult { tool_use_id, content } => {
+                    flush_message(&mut items, &message.role, &mut parts);
+                    items.push(InputItem::FunctionCallOutput { call_id: tool_use_id, output: content });
+                },
+                ContentItem::Reasoning { id, summary } => {
+                    flush_message(&mut items, &message.role, &mut parts);
+                    let summary = if summary.is_empty() { Vec::new() } else { vec![Summary::SummaryText { text: summary }] };
+                    items.push(InputItem::Reasoning { id, summary });
+                },
+                // Reasoning of other providers can't be verified by OpenAI
+                ContentItem::Thinking { .. } | ContentItem::RedactedThinking { .. } => {},
+            }
+        }
+        flush_message(&mut items, &message.role, &mut parts);
+    }
+    items
+}
+
+fn flush_message(items: &mut Vec<InputItem>, role: &Role, parts: &mut Vec<InputContent>) {
+    if parts.is_empty() {
+        return;
+    }
+    let parts = std::mem::take(parts);
+    let has_images = parts.iter().any(|part| matches!(part, InputContent::InputImage { .. }));
+    let content = if *role == Role::Assistant || !has_images {
+        let text = parts.into_iter().filter_map(|part| match part {
+            InputContent::InputText { text } => Some(text),
+            InputContent::InputImage { .. } => None,
+        }).collect::<Vec<_>>().join("\n");
+        MessageContent::Text(text)
+    } else {
+        MessageContent::Parts(parts)
+    };
+    items.push(InputItem::Message { role: role.clone(), content });
+}
+
+fn from_output_items(output: Vec<OutputItem>) -> Vec<ContentItem> {
+    output.into_iter().flat_map(|item| -> Vec<ContentItem> {
+        match item {
+            OutputItem::Message { content } => content.into_iter().filter_map(|content| match content {
+                OutputContent::OutputText { text } => Some(ContentItem::Text { text }),
+                OutputContent::Refusal { refusal } => Some(ContentItem::Text { text: refusal }),
+                OutputContent::Other => None,
+            }).collect(),
+            OutputItem::FunctionCall { call_id, name, arguments } => {
+                let input = serde_json::from_str(&arguments).unwrap_or(serde_json::Value::String(arguments));
+                vec![ContentItem::ToolUse { id: call_id, name, input, signature: None }]
+            },
+            OutputItem::Reasoning { id, summary } => {
+                let summary = summary.into_iter()
+                    .map(|Summary::SummaryText { text }| text)
+                    .collect::<Vec<_>>()
+                    .join("\n\n");
+                vec![ContentItem::Reasoning { id, summary }]
+            },
+            OutputItem::Other => Vec::new(),
+        }
+    }).collect()
+}
+
+/// Whether an error is about `previous_response_id`, such as an expired or deleted response, rather
+/// than something resending the conversation wouldn't fix.
+fn previous_response_unavailable(body: &str) -> bool {
+    let Ok(body) = serde_json::from_str::<serde_json::Value>(body) else {
+        return false;
+    };
+    let error = &body["error"];
+    error["code"] == "previous_response_not_found" || error["param"] == "previous_response_id"
+}
+
+impl OpenAIResponsesInference {
+    /// Id of the response the conversation continues from and the index of the first new message.
+    fn continuation(&self, messages: &[CommonMessage]) -> (Option<String>, usize) {
+        let previous = self.previous.lock().unwrap();
+        match previous.as_ref() {
+            Some(previous) if messages.len() > previous.messages.len()
+                && previous.messages.iter().zip(messages).all(|(a, b)| a.role == b.role && a.content == b.content) => {
+                (Some(previous.id.clone()), previous.messages.len())
+            },
+            _ => (None, 0),
+        }
+    }
+
+    async fn send(
+        &self,
+        messages: &[CommonMessage],
+        previous_response_id: Option<&str>,
+        start: usize,
+        system_message: Option<&str>,
+    ) -> Result<ModelResponse, InferenceError> {
+        let request = ResponsesRequest {
+            model: &self.model,
+            input: to_input_items(messages[start..].to_vec()),
+            instructions: system_message,
+            previous_response_id,
+            max_output_tokens: self.max_output_tokens,
+            tools: &self.tools,
+            temperature: self.sampling.temperature,
+            top_p: self.sampling.top_p,
+            reasoning: self.reasoning.then_some(ReasoningConfig {
+                effort: self.sampling.reasoning_effort,
+                summary: "auto",
+            }),
+            store: true,
+        };
+
+        let response_text = self.client
+            .post(&request_url(&self.api_url, &self.endpoint)?, request_headers(&self.api_key, &self.endpoint)?, &request)
+            .await?
+            .text()?;
+
+        let response: ResponsesResponse = serde_json::from_str(&response_text)
+            .map_err(|e| InferenceError::InvalidResponse(format!("Failed to parse OpenAI response: {}", e)))?;
+
+        let content = from_output_items(response.output);
+        let has_tool_use = content.iter().any(|c| matches!(c, ContentItem::ToolUse { .. }));
+
+        let mut conversation = messages.to_vec();
+        conversation.push(CommonMessage {
+            role: Role::Assistant,
+            content: content.clone(),
+            model: None,
+        });
+        *self.previous.lock().unwrap() = Some(PreviousResponse {
+            id: response.id,
+            messages: conversation,
+        });
+
+        Ok(ModelResponse {
+            content,
+            model: response.model,
+            role: Role::Assistant.to_string(),
+            message_type: "text".to_string(),
+            stop_reason: match response.incomplete_details {
+                Some(details) => details.reason,
+                None if has_tool_use => "tool_use".to_string(),
+                None => "end_turn".to_string(),
+            },
+            stop_sequence: None,
+            total_tokens: response.usage.map(|u| u.total_tokens).unwrap_or_default(),
+        })
+    }
+}
+
+#[async_trait]
+impl Inference for OpenAIResponsesInference {
+    fn new(
+        model: ModelSpec,
+        api_key: String,
+        options: InferenceOptions
+    ) -> Self {
+        let tool_provider = OpenAIToolProvider::new();
+        // Models without native tools are wrapped in PromptedToolInference
+        let tools = if model.quirks.prompted_tools {
+            Vec::new()
+        } else {
+            tool_provider.tools.iter().cloned().map(ResponsesTool::from).collect()
+        };
+        OpenAIResponsesInference {
+            model: model.name,
+            client: options.client,
+            api_url: model.api_url,
+            api_key,
+            max_output_tokens: options.max_output_tokens,
+            sampling: options.sampling,
+            endpoint: model.endpoint,
+            reasoning: model.quirks.reasoning_effort,
+            tool_provider,
+            tools,
+            previous: Mutex::new(None),
+        }
+    }
+
+    async fn query_model(&self, messages: Vec<CommonMessage>, system_message: Option<&str>) -> Result<ModelResponse, InferenceError> {
+        if self.api_key.is_empty() {
+            return Err(InferenceError::MissingApiKey("OpenAI API key not found".to_string()));
+        }
+
+        let (previous_response_id, start) = self.continuation(&messages);
+        let Some(previous_response_id) = previous_response_id else {
+            return self.send(&messages, None, 0, system_message).await;
+        };
+
+        match self.send(&messages, Some(&previous_response_id), start, system_message).await {
+            Err(InferenceError::ApiError(status, body))
+                if (status == StatusCode::BAD_REQUEST || status == StatusCode::NOT_FOUND) && previous_response_unavailable(&body) => {
+                warn!("Unable to continue from response {}, sending the whole conversation: {}", previous_response_id, body);
+                self.send(&messages, None, 0, system_message).await
+            },
+            result => result,
+        }
+    }
+
+    async fn get_token_count(&self, messages: Vec<CommonMessage>, system_message: Option<&str>) -> Result<u64, InferenceError> {
+        Ok(estimate_token_count(&self.tool_provider.tools, messages, system_message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ModelRegistry;
+
+    fn message(role: Role, content: Vec<ContentItem>) -> CommonMessage {
+        CommonMessage { role, content, model: None }
+    }
+
+    fn text(text: &str) -> ContentItem {
+        ContentItem::Text { text: text.to_string() }
+    }
+
+    #[test]
+    fn test_items_keep_reasoning_before_function_calls() {
+        let messages = vec![
+            message(Role::User, vec![text("Read a.rs")]),
+            message(Role::Assistant, vec![
+                ContentItem::Reasoning { id: "rs_1".to_string(), summary: String::new() },
+                text("Reading it"),
+                ContentItem::ToolUse {
+                    id: "call_1".to_string(),
+                    name: "read_file".to_string(),
+                    input: serde_json::json!({ "path": "a.rs" }),
+                    signature: None,
+                },
+            ]),
+            message(Role::User, vec![ContentItem::ToolResult {
+                tool_use_id: "call_1".to_string(),
+                content: "fn main() {}".to_string(),
+            }]),
+        ];
+
+        let items = serde_json::to_value(to_input_items(messages)).unwrap();
+        let types: Vec<&str> = items.as_array().unwrap().iter().map(|i| i["type"].as_str().unwrap()).collect();
+        assert_eq!(types, ["message", "reasoning", "message", "function_call", "function_call_output"]);
+        assert_eq!(items[1]["id"], "rs_1");
+        assert_eq!(items[2]["role"], "assistant");
+        assert_eq!(items[2]["content"], "Reading it");
+        assert_eq!(items[3]["arguments"], r#"{"path":"a.rs"}"#);
+        assert_eq!(items[4]["call_id"], "call_1");
+    }
+
+    #[test]
+    fn test_continues_only_from_unchanged_conversation() {
+        let inference = OpenAIResponsesInference::new(
+            ModelRegistry::new(&[]).get("o1-pro").unwrap().clone(),
+            "key".to_string(),
+            InferenceOptions::default(),
+        );
+        let tool = serde_json::to_value(&inference.tools[0]).unwrap();
+        assert_eq!(tool["type"], "function");
+        assert_eq!(tool["strict"], false);
+
+        let first = vec![message(Role::User, vec![text("hi")])];
+        let reply = message(Role::Assistant, vec![text("hello")]);
+        assert_eq!(inference.continuation(&first), (None, 0));
+
+        *inference.previous.lock().unwrap() = Some(PreviousResponse {
+            id: "resp_1".to_string(),
+            messages: vec![first[0].clone(), reply.clone()],
+        });
+        let next = vec![
+            first[0].clone(),
+            CommonMessage { model: Some("o1-pro".to_string()), ..reply },
+            message(Role::User, vec![text("again")]),
+        ];
+        assert_eq!(inference.continuation(&next), (Some("resp_1".to_string()), 2));
+
+        // Pruned conversations are sent in full
+        assert_eq!(inference.continuation(&next[1..]), (None, 0));
+    }
+
+    #[test]
+    fn test_only_previous_response_errors_resend_the_conversation() {
+        assert!(previous_response_unavailable(r#"{"error": {"message": "Previous response with id 'resp_1' not found.", "type": "invalid_request_error", "param": "previous_response_id", "code": "previous_response_not_found"}}"#));
+        assert!(!previous_response_unavailable(r#"{"error": {"message": "Invalid value for 'temperature'.", "type": "invalid_request_error", "param": "temperature", "code": "invalid_value"}}"#));
+        assert!(!previous_response_unavailable("Bad Request"));
+    }
+}
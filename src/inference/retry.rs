@@ -0,0 +1,130 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use log::warn;
+use rand::Rng;
+use serde::Serialize;
+
+use crate::config::RetryConfig;
+use super::types::InferenceError;
+
+/// Progress of the request currently being retried, polled by the client through `/status`.
+#[derive(Debug, Serialize, Clone)]
+pub struct RetryStatus {
+    pub attempt: u32,
+    pub max_attempts: u32,
+    pub delay_ms: u64,
+    pub error: String,
+}
+
+pub type SharedRetryStatus = Arc<Mutex<Option<RetryStatus>>>;
+
+/// Exponential backoff with equal jitter, the delay for attempt n is in [d/2, d] where
+/// d = initial_delay * 2^(n-1) capped at max_delay.
+fn backoff(config: &RetryConfig, attempt: u32) -> Duration {
+    let exp = config.initial_delay_ms.saturating_mul(1u64 << attempt.saturating_sub(1).min(32));
+    let capped = exp.min(config.max_delay_ms);
+    let half = capped / 2;
+    Duration::from_millis(half + rand::thread_rng().gen_range(0..=capped - half))
+}
+
+/// Runs `operation` until it succeeds, fails with a non-retryable error or runs out of attempts.
+/// Rate limit responses honor the provider's `retry-after` but never wait longer than `max_delay_ms`.
+pub async fn with_retry<T, F, Fut>(
+    config: &RetryConfig,
+    status: &SharedRetryStatus,
+    mut operation: F,
+) -> Result<T, InferenceError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, InferenceError>>,
+{
+    let max_attempts = config.max_attempts.max(1);
+    let mut attempt = 1;
+    let result = loop {
+        match operation().await {
+            Err(e) if e.is_retryable() && attempt < max_attempts => {
+                let delay = e.retry_after()
+                    .map(|d| d.min(Duration::from_millis(config.max_delay_ms)))
+                    .unwrap_or_else(|| backoff(config, attempt));
+                warn!("Attempt {}/{} failed, retrying in {:?}: {}", attempt, max_attempts, delay, e);
+                *status.lock().unwrap() = Some(RetryStatus {
+                    attempt,
+                    max_attempts,
+                    delay_ms: delay.as_millis() as u64,
+                    error: e.to_string(),
+                });
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            },
+            result => break result,
+        }
+    };
+    *status.lock().unwrap() = None;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[tokio::test]
+    async fn test_retries_until_success() {
+        let config = RetryConfig {
+            max_attempts: 3,
+            initial_delay_ms: 1,
+            max_delay_ms: 2,
+        };
+        let status = SharedRetryStatus::default();
+        let calls = Cell::new(0);
+
+        let result = with_retry(&config, &status, || {
+            calls.set(calls.get() + 1);
+            let call = calls.get();
+            async move {
+                if call < 3 {
+                    Err(InferenceError::RateLimited(reqwest::StatusCode::TOO_MANY_REQUESTS, None, String::new()))
+                } else {
+                    Ok(call)
+                }
+            }
+        }).await;
+
+        assert_eq!(result.unwrap(), 3);
+        assert!(status.lock().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_does_not_retry_client_errors() {
+        let config = RetryConfig::default();
+        let status = SharedRetryStatus::default();
+        let calls = Cell::new(0);
+
+        let result: Result<(), _> = with_retry(&config, &status, || {
+            calls.set(calls.get() + 1);
+            async { Err(InferenceError::ApiError(reqwest::StatusCode::BAD_REQUEST, String::new())) }
+        }).await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_retry_after_in_seconds_or_http_date() {
+        use reqwest::header::{HeaderMap, HeaderValue};
+        use crate::inference::types::retry_after;
+
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", HeaderValue::from_static("3"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(3)));
+
+        let date = httpdate::fmt_http_date(std::time::SystemTime::now() + Duration::from_secs(120));
+        headers.insert("retry-after", HeaderValue::from_str(&date).unwrap());
+        let delay = retry_after(&headers).unwrap();
+        assert!(delay > Duration::from_secs(110) && delay <= Duration::from_secs(120), "{:?}", delay);
+
+        headers.insert("retry-after", HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+    }
+}
@@ -37,3 +37,57 @@ pub struct PropertySchema {
     pub property_type: String,
     pub description: String,
 }
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiTool {
+    pub function_declarations: Vec<GeminiFunctionDeclaration>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GeminiFunctionDeclaration {
+    pub name: String,
+    pub description: String,
+    // Gemini rejects object schemas without properties, so parameterless tools omit them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<InputSchema>,
+}
+
+impl From<AnthropicTool> for GeminiFunctionDeclaration {
+    fn from(tool: AnthropicTool) -> Self {
+        let parameters = if tool.input_schema.properties.is_empty() {
+            None
+        } else {
+            Some(tool.input_schema)
+        };
+        GeminiFunctionDeclaration {
+            name: tool.name,
+            description: tool.description,
+            parameters,
+        }
+    }
+}
+
+/// Function tool in the Responses API, which has the fields of OpenAIToolFunction at the top level
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ResponsesTool {
+    #[serde(rename = "type")]
+    pub tool_type: String,
+    pub name: String,
+    pub description: String,
+    pub parameters: InputSchema,
+    /// Strict mode needs every property listed as required, which the optional tool inputs are not
+    pub strict: bool,
+}
+
+impl From<OpenAITool> for ResponsesTool {
+    fn from(tool: OpenAITool) -> Self {
+        ResponsesTool {
+            tool_type: tool.tool_type,
+            name: tool.function.name,
+            description: tool.function.description,
+            parameters: tool.function.parameters,
+            strict: false,
+        }
+    }
+}
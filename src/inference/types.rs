@@ -1,6 +1,11 @@
-use serde::{Serialize, Deserialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use serde::{Serialize, Deserialize, Deserializer};
 
 use crate::chat::ContentItem;
+use crate::models::ModelSpec;
+use super::http::HttpClient;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ModelResponse {
@@ -14,13 +19,146 @@ pub struct ModelResponse {
     pub total_tokens: u64,
 }
 
+/// Settings fixed for the lifetime of an inference backend.
+#[derive(Debug, Clone, Default)]
+pub struct InferenceOptions {
+    pub max_output_tokens: u32,
+    /// Token budget for extended thinking, only set for models that support it
+    pub thinking_budget: Option<u32>,
+    /// Only holds parameters the model accepts
+    pub sampling: SamplingParams,
+    /// Shared client with the proxy and certificates from pprog.toml
+    pub client: HttpClient,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+/// Sampling parameters, unset ones are left to the provider's defaults.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct SamplingParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    /// An empty list, or null, clears stop sequences set in pprog.toml
+    #[serde(skip_serializing_if = "Option::is_none", deserialize_with = "deserialize_stop")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<ReasoningEffort>,
+}
+
+impl SamplingParams {
+    /// Parameters set in `overrides` replace these.
+    pub fn merge(&self, overrides: &SamplingParams) -> SamplingParams {
+        SamplingParams {
+            temperature: overrides.temperature.or(self.temperature),
+            top_p: overrides.top_p.or(self.top_p),
+            stop: overrides.stop.clone()
+                .or_else(|| self.stop.clone())
+                .filter(|stop| !stop.is_empty()),
+            reasoning_effort: overrides.reasoning_effort.or(self.reasoning_effort),
+        }
+    }
+
+    /// Drops or limits the parameters a model doesn't accept, used when switching models at runtime.
+    pub fn supported_by(&self, model: &ModelSpec, thinking: bool) -> SamplingParams {
+        let accepts_sampling = model.accepts_sampling(thinking);
+        let mut stop = self.stop.clone();
+        if let (Some(stop), Some(limit)) = (&mut stop, model.max_stop_sequences()) {
+            stop.truncate(limit);
+        }
+        SamplingParams {
+            temperature: self.temperature
+                .filter(|_| accepts_sampling)
+                .map(|t| t.min(model.max_temperature())),
+            top_p: self.top_p.filter(|_| accepts_sampling),
+            stop,
+            reasoning_effort: self.reasoning_effort.filter(|_| model.quirks.reasoning_effort),
+        }
+    }
+
+    pub fn stop(&self) -> &[String] {
+        self.stop.as_deref().unwrap_or_default()
+    }
+}
+
+// A stop field that is present but null is an explicit empty list, unlike a missing one
+fn deserialize_stop<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    Ok(Some(Option::<Vec<String>>::deserialize(deserializer)?.unwrap_or_default()))
+}
+
 #[derive(Debug)]
 pub enum InferenceError {
     NetworkError(String),
     ApiError(reqwest::StatusCode, String),
+    RateLimited(reqwest::StatusCode, Option<Duration>, String),
     InvalidResponse(String),
     MissingApiKey(String),
     SerializationError(String),
+    InvalidConfig(String),
+}
+
+// Anthropic returns 529 when the API is overloaded
+const OVERLOADED: u16 = 529;
+
+impl InferenceError {
+    pub fn from_status(status: StatusCode, retry_after: Option<Duration>, body: String) -> Self {
+        if status == StatusCode::TOO_MANY_REQUESTS || status.as_u16() == OVERLOADED {
+            InferenceError::RateLimited(status, retry_after, body)
+        } else {
+            InferenceError::ApiError(status, body)
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            InferenceError::NetworkError(_) | InferenceError::RateLimited(..) => true,
+            InferenceError::ApiError(status, _) => status.is_server_error() || *status == StatusCode::REQUEST_TIMEOUT,
+            _ => false,
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            InferenceError::RateLimited(_, retry_after, _) => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+/// Reads `retry-after-ms` or `retry-after`, either in seconds or as an HTTP date.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = |name: &str| headers.get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim);
+    let number = |name: &str| value(name)
+        .and_then(|v| v.parse::<f64>().ok())
+        .filter(|v| v.is_finite() && *v >= 0.0);
+
+    number("retry-after-ms")
+        .map(|ms| Duration::from_secs_f64(ms / 1000.0))
+        .or_else(|| number("retry-after").map(Duration::from_secs_f64))
+        .or_else(|| {
+            let date = httpdate::parse_http_date(value("retry-after")?).ok()?;
+            // A date in the past means retry now
+            Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+        })
+}
+
+/// Id for tool uses of providers that don't assign their own.
+pub fn generate_tool_id(name: &str, index: usize) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!("{}-{}-{}", name, nanos, index)
 }
 
 impl From<serde_json::Error> for InferenceError {
@@ -34,9 +172,11 @@ impl std::fmt::Display for InferenceError {
         match self {
             InferenceError::NetworkError(msg) => write!(f, "Network error: {}", msg),
             InferenceError::ApiError(status, msg) => write!(f, "API error ({}): {}", status, msg),
+            InferenceError::RateLimited(status, _, msg) => write!(f, "Rate limited ({}): {}", status, msg),
             InferenceError::InvalidResponse(msg) => write!(f, "Invalid response: {}", msg),
             InferenceError::MissingApiKey(msg) => write!(f, "Missing API key: {}", msg),
             InferenceError::SerializationError(msg) => write!(f, "Serialization error: {}", msg),
+            InferenceError::InvalidConfig(msg) => write!(f, "Invalid config: {}", msg),
         }
     }
 }
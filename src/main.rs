@@ -1,15 +1,26 @@
 mod inference;
 mod chat;
 mod tree;
+mod git;
 mod config;
+mod models;
 mod server;
 mod tools;
+mod error;
+mod checkpoint;
+mod worktree;
+mod commit;
+mod diff;
+mod git_tools;
+mod repo_map;
 
 use std::fs::OpenOptions;
 use std::io::Write;
+use std::path::PathBuf;
 
+use checkpoint::Checkpoints;
 use clap::{CommandFactory, Parser, Subcommand};
-use config::ProjectConfig;
+use config::{CassetteConfig, CassetteMode, ProjectConfig};
 use env_logger::{Builder, Target};
 use tree::GitTree;
 
@@ -30,9 +41,26 @@ enum Commands {
         host: String,
         #[arg(short, long, default_value_t = 8080)]
         port: u16,
+        #[arg(long, value_name = "PATH", conflicts_with = "replay", help = "Record provider traffic to a cassette")]
+        record: Option<PathBuf>,
+        #[arg(long, value_name = "PATH", help = "Replay provider traffic from a cassette instead of calling the API")]
+        replay: Option<PathBuf>,
+    },
+    #[command(about = "Restore the files from before the last turn that changed them")]
+    Undo,
+    #[command(about = "Inspect project config")]
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
     },
 }
 
+#[derive(Subcommand)]
+enum ConfigCommands {
+    #[command(about = "Validate pprog.toml")]
+    Validate,
+}
+
 fn setup_logger() -> Result<(), anyhow::Error> {
     let home_dir = dirs::home_dir().expect("Failed to get home directory");
     let pprog_dir = home_dir.join(".pprog");
@@ -85,7 +113,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 if !gitignore_contents.contains("pprog.toml") {
                     println!("Adding config to .gitignore.");
                     let mut gitignore = std::fs::OpenOptions::new()
-                        .write(true)
                         .append(true)
                         .create(true)
                         .open(gitignore_path)
@@ -100,8 +127,59 @@ pprog.toml
                 println!("Init successful.");
             }
         }
-        Some(Commands::Serve { host, port }) => {
-            server::start_server(host.clone(), *port).await?;
+        Some(Commands::Serve { host, port, record, replay }) => {
+            let cassette = match (record, replay) {
+                (Some(path), _) => Some(CassetteConfig { mode: CassetteMode::Record, path: path.clone() }),
+                (_, Some(path)) => Some(CassetteConfig { mode: CassetteMode::Replay, path: path.clone() }),
+                _ => None,
+            };
+            let config = match ProjectConfig::load_with_cassette(cassette) {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            server::start_server(host.clone(), *port, config).await?;
+        }
+        Some(Commands::Undo) => {
+            let root = GitTree::get_git_root()?;
+            let Some(checkpoints) = Checkpoints::latest_session(root.clone())? else {
+                println!("No checkpoints to undo.");
+                return Ok(());
+            };
+            // Checkpoints taken by a restore are kept so it can be undone from the UI, undo goes back a turn
+            let last = checkpoints.list()?.into_iter()
+                .rev()
+                .find(|checkpoint| !checkpoint.message.starts_with(Checkpoints::BEFORE_RESTORE));
+            let Some(last) = last else {
+                println!("No checkpoints to undo.");
+                return Ok(());
+            };
+            // Sessions isolated in a worktree took their checkpoints there
+            let checkpoints = match &last.worktree {
+                Some(worktree) if !worktree.is_dir() => {
+                    eprintln!(
+                        "Session {} ran in worktree {} which no longer exists, not restoring its checkpoint.",
+                        checkpoints.session(), worktree.display()
+                    );
+                    std::process::exit(1);
+                }
+                Some(worktree) => Checkpoints::new(worktree.clone(), checkpoints.session().to_string()),
+                None => checkpoints,
+            };
+            checkpoints.restore(last.index)?;
+            checkpoints.remove(last.index)?;
+            println!("Restored files from before \"{}\".", last.message);
+        }
+        Some(Commands::Config { command: ConfigCommands::Validate }) => {
+            match ProjectConfig::load() {
+                Ok(_) => println!("Config is valid."),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
         }
         None => {
             let mut cmd = Cli::command();
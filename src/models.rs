@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Provider;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ModelSpec {
+    pub name: String,
+    pub provider: Provider,
+    pub api_url: String,
+    pub context_window: usize,
+    pub max_output_tokens: u32,
+    /// Environment variable holding the API key when the model is selected from the UI
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_env: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pricing: Option<Pricing>,
+    #[serde(default)]
+    pub quirks: ModelQuirks,
+    #[serde(default, skip_serializing_if = "EndpointConfig::is_default")]
+    pub endpoint: EndpointConfig,
+    /// Script of the mock provider
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fixture: Option<String>,
+}
+
+/// How the API key is sent to OpenAI compatible endpoints
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthStyle {
+    /// `Authorization: Bearer <key>`
+    #[default]
+    Bearer,
+    /// `api-key: <key>` as used by Azure OpenAI
+    ApiKey,
+}
+
+/// Request settings for endpoints other than the provider's public API, such as Azure OpenAI
+/// deployments or internal gateways.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct EndpointConfig {
+    pub auth: AuthStyle,
+    /// Sent as the `api-version` query parameter
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_version: Option<String>,
+    /// Added to every request, replacing default headers with the same name
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+}
+
+impl EndpointConfig {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// USD per million tokens
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Pricing {
+    pub input: f64,
+    pub output: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct ModelQuirks {
+    /// System prompt is sent with the `developer` role
+    pub developer_role: bool,
+    /// Output limit is sent as `max_completion_tokens` instead of `max_tokens`
+    pub max_completion_tokens: bool,
+    /// No native tool support, tools are described in the system prompt and parsed from text
+    pub prompted_tools: bool,
+    /// Supports extended thinking with a token budget
+    pub thinking: bool,
+    /// Rejects `temperature` and `top_p`
+    pub fixed_sampling: bool,
+    /// Accepts `reasoning_effort`
+    pub reasoning_effort: bool,
+    /// Served through the OpenAI Responses API instead of chat completions
+    pub responses_api: bool,
+}
+
+impl ModelSpec {
+    pub fn max_temperature(&self) -> f32 {
+        match self.provider {
+            Provider::Anthropic => 1.0,
+            Provider::OpenAI | Provider::Gemini | Provider::Mock => 2.0,
+        }
+    }
+
+    pub fn max_stop_sequences(&self) -> Option<usize> {
+        match self.provider {
+            Provider::Anthropic | Provider::Mock => None,
+            // The Responses API has no stop sequences
+            Provider::OpenAI if self.quirks.responses_api => Some(0),
+            Provider::OpenAI => Some(4),
+            Provider::Gemini => Some(5),
+        }
+    }
+
+    /// Whether `temperature` and `top_p` can be set, Anthropic doesn't allow them with thinking
+    pub fn accepts_sampling(&self, thinking: bool) -> bool {
+        let thinking_restricted = thinking && self.provider == Provider::Anthropic;
+        !self.quirks.fixed_sampling && !thinking_restricted
+    }
+}
+
+pub struct ModelRegistry {
+    models: Vec<ModelSpec>,
+}
+
+impl ModelRegistry {
+    /// Builds the registry from the built-in models, user entries with the same name replace them.
+    pub fn new(user_models: &[ModelSpec]) -> Self {
+        let mut models = Self::builtin();
+        for user_model in user_models {
+            match models.iter_mut().find(|m| m.name == user_model.name) {
+                Some(existing) => *existing = user_model.clone(),
+                None => models.push(user_model.clone()),
+            }
+        }
+        Self { models }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ModelSpec> {
+        self.models.iter().find(|m| m.name == name)
+    }
+
+    pub fn models(&self) -> &[ModelSpec] {
+        &self.models
+    }
+
+    fn builtin() -> Vec<ModelSpec> {
+        const ANTHROPIC_URL: &str = "https://api.anthropic.com/v1/messages";
+        const OPENAI_URL: &str = "https://api.openai.com/v1/chat/completions";
+        const OPENAI_RESPONSES_URL: &str = "https://api.openai.com/v1/responses";
+        const DEEPSEEK_URL: &str = "https://api.deepseek.com/v1/chat/completions";
+        const GEMINI_URL: &str = "https://generativelanguage.googleapis.com/v1beta/models";
+
+        let model = |name: &str, provider: Provider, api_url: &str, context_window: usize, max_output_tokens: u32, input: f64, output: f64| ModelSpec {
+            name: name.to_string(),
+            provider,
+            api_url: api_url.to_string(),
+            context_window,
+            max_output_tokens,
+            api_key_env: None,
+            pricing: Some(Pricing { input, output }),
+            quirks: ModelQuirks::default(),
+            endpoint: EndpointConfig::default(),
+            fixture: None,
+        };
+        let reasoning = ModelQuirks {
+            developer_role: true,
+            max_completion_tokens: true,
+            fixed_sampling: true,
+            ..Default::default()
+        };
+        let thinking = ModelQuirks {
+            thinking: true,
+            ..Default::default()
+        };
+
+        vec![
+            ModelSpec {
+                quirks: thinking.clone(),
+                ..model("claude-3-7-sonnet-latest", Provider::Anthropic, ANTHROPIC_URL, 200000, 64000, 3.0, 15.0)
+            },
+            model("claude-3-5-sonnet-latest", Provider::Anthropic, ANTHROPIC_URL, 200000, 8192, 3.0, 15.0),
+            model("claude-3-5-haiku-latest", Provider::Anthropic, ANTHROPIC_URL, 200000, 8192, 0.8, 4.0),
+            model("gpt-4o", Provider::OpenAI, OPENAI_URL, 128000, 16384, 2.5, 10.0),
+            model("gpt-4o-mini", Provider::OpenAI, OPENAI_URL, 128000, 16384, 0.15, 0.6),
+            ModelSpec {
+                quirks: ModelQuirks {
+                    reasoning_effort: true,
+                    ..reasoning.clone()
+                },
+                ..model("o1", Provider::OpenAI, OPENAI_URL, 200000, 100000, 15.0, 60.0)
+            },
+            ModelSpec {
+                quirks: ModelQuirks {
+                    reasoning_effort: true,
+                    responses_api: true,
+                    ..reasoning.clone()
+                },
+                ..model("o1-pro", Provider::OpenAI, OPENAI_RESPONSES_URL, 200000, 100000, 150.0, 600.0)
+            },
+            ModelSpec {
+                quirks: reasoning,
+                ..model("o1-mini", Provider::OpenAI, OPENAI_URL, 128000, 65536, 3.0, 12.0)
+            },
+            ModelSpec {
+                api_key_env: Some("DEEPSEEK_API_KEY".to_string()),
+                ..model("deepseek-chat", Provider::OpenAI, DEEPSEEK_URL, 64000, 8192, 0.27, 1.1)
+            },
+            ModelSpec {
+                api_key_env: Some("DEEPSEEK_API_KEY".to_string()),
+                quirks: ModelQuirks {
+                    prompted_tools: true,
+                    ..Default::default()
+                },
+                ..model("deepseek-reasoner", Provider::OpenAI, DEEPSEEK_URL, 64000, 8192, 0.55, 2.19)
+            },
+            ModelSpec {
+                quirks: thinking,
+                ..model("gemini-2.5-flash", Provider::Gemini, GEMINI_URL, 1048576, 65536, 0.3, 2.5)
+            },
+            model("gemini-2.0-flash", Provider::Gemini, GEMINI_URL, 1048576, 8192, 0.1, 0.4),
+            model("gemini-1.5-pro", Provider::Gemini, GEMINI_URL, 2097152, 8192, 1.25, 5.0),
+            model("gemini-1.5-flash", Provider::Gemini, GEMINI_URL, 1048576, 8192, 0.075, 0.3),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_models_override_builtin() {
+        let user_models = vec![
+            ModelSpec {
+                name: "gpt-4o".to_string(),
+                provider: Provider::OpenAI,
+                api_url: "https://gateway.internal/v1/chat/completions".to_string(),
+                context_window: 64000,
+                max_output_tokens: 4096,
+                api_key_env: None,
+                pricing: None,
+                quirks: ModelQuirks::default(),
+                endpoint: EndpointConfig::default(),
+                fixture: None,
+            },
+        ];
+
+        let registry = ModelRegistry::new(&user_models);
+        let builtin_count = ModelRegistry::builtin().len();
+
+        assert_eq!(registry.models().len(), builtin_count);
+        assert_eq!(registry.get("gpt-4o"), Some(&user_models[0]));
+        assert!(registry.get("deepseek-reasoner").unwrap().quirks.prompted_tools);
+    }
+}
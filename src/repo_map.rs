@@ -0,0 +1,341 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use anyhow::{anyhow, Result};
+use tree_sitter::{Language, Node, Parser};
+
+use crate::tree::GitTree;
+
+/*
+    * Condensed map of the project's code: each source file with the functions, types and classes
+    * it declares at the top level, and the methods of its impls and classes.
+    *
+    * Files are parsed with tree-sitter.  Every identifier in a file is collected as a reference,
+    * and a file ranks by how many other files refer to the names it declares, so widely used
+    * modules make it into the map first when it has to be cut down to `max_lines`.  The files that
+    * fit are listed in path order.
+    *
+    * Parsed files are kept for the life of the process, keyed by absolute path and only parsed
+    * again when their modification time or size changes, so refreshing the map in the system
+    * prompt and the repo_map tool only parse what was edited since.
+*/
+pub struct RepoMap;
+
+// Larger files are generated or vendored more often than not
+const MAX_FILE_BYTES: u64 = 512 * 1024;
+// Longer lines mean minified bundles rather than code anyone reads
+const MAX_LINE_CHARS: usize = 1000;
+// Budget of the repo_map tool, the one in the system prompt comes from [tree]
+const MAX_TOOL_LINES: usize = 500;
+
+struct Symbol {
+    kind: &'static str,
+    name: String,
+    members: Vec<Symbol>,
+}
+
+struct FileMap {
+    path: String,
+    symbols: Vec<Symbol>,
+    references: HashSet<String>,
+}
+
+// A file as it was parsed, without a map if it had no symbols or was skipped
+struct Parsed {
+    modified: Option<SystemTime>,
+    len: u64,
+    map: Option<Arc<FileMap>>,
+}
+
+static PARSED: Mutex<BTreeMap<PathBuf, Parsed>> = Mutex::new(BTreeMap::new());
+
+impl Symbol {
+    fn lines(&self) -> usize {
+        1 + self.members.len()
+    }
+}
+
+impl FileMap {
+    fn lines(&self) -> usize {
+        1 + self.symbols.iter().map(Symbol::lines).sum::<usize>()
+    }
+}
+
+impl RepoMap {
+    /// Map of `files`, relative to `root`, cut down to the best ranked files that fit in `max_lines`.
+    pub fn build(root: &Path, files: &[String], max_lines: usize) -> Result<String> {
+        let maps = Self::parse_files(root, files)?;
+
+        // Number of files referring to each name
+        let mut referenced_by: HashMap<&str, usize> = HashMap::new();
+        for map in &maps {
+            for name in &map.references {
+                *referenced_by.entry(name.as_str()).or_default() += 1;
+            }
+        }
+        let mut ranked: Vec<(usize, &FileMap)> = maps.iter()
+            .map(|map| {
+                let map = map.as_ref();
+                let rank = map.symbols.iter()
+                    .flat_map(|symbol| std::iter::once(symbol).chain(&symbol.members))
+                    .map(|symbol| referenced_by.get(symbol.name.as_str()).copied().unwrap_or_default())
+                    // Its own uses don't count
+                    .map(|count| count.saturating_sub(1))
+                    .sum();
+                (rank, map)
+            })
+            .collect();
+        ranked.sort_by(|(a_rank, a), (b_rank, b)| b_rank.cmp(a_rank).then_with(|| a.path.cmp(&b.path)));
+
+        let mut budget = max_lines;
+        let mut shown: Vec<&FileMap> = Vec::new();
+        for (_, map) in ranked {
+            if map.lines() <= budget {
+                budget -= map.lines();
+                shown.push(map);
+            }
+        }
+        shown.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut result = String::new();
+        for map in &shown {
+            result.push_str(&map.path);
+            result.push('\n');
+            for symbol in &map.symbols {
+                result.push_str(&format!("  {} {}\n", symbol.kind, symbol.name));
+                for member in &symbol.members {
+                    result.push_str(&format!("    {} {}\n", member.kind, member.name));
+                }
+            }
+        }
+        let left_out = maps.len() - shown.len();
+        if left_out > 0 {
+            result.push_str(&format!("... {} more file{} with symbols\n", left_out, if left_out == 1 { "" } else { "s" }));
+        }
+        Ok(result)
+    }
+
+    // Maps of the files with symbols, reusing earlier parses of files that haven't changed
+    fn parse_files(root: &Path, files: &[String]) -> Result<Vec<Arc<FileMap>>> {
+        let mut parsed = PARSED.lock().unwrap();
+        // Deleted files
+        parsed.retain(|path, _| !path.starts_with(root) || path.exists());
+
+        let mut parser = Parser::new();
+        let mut maps = Vec::new();
+        for path in files {
+            let Some(language) = Self::language(path) else {
+                continue;
+            };
+            let full_path = root.join(path);
+            let Ok(metadata) = fs::metadata(&full_path) else {
+                continue;
+            };
+            let (modified, len) = (metadata.modified().ok(), metadata.len());
+            let map = match parsed.get(&full_path) {
+                Some(cached) if cached.modified == modified && cached.len == len => cached.map.clone(),
+                _ => {
+                    let map = Self::parse(&mut parser, &language, path, &full_path, len)?.map(Arc::new);
+                    parsed.insert(full_path, Parsed { modified, len, map: map.clone() });
+                    map
+                }
+            };
+            maps.extend(map);
+        }
+        Ok(maps)
+    }
+
+    fn parse(parser: &mut Parser, language: &Language, path: &str, full_path: &Path, len: u64) -> Result<Option<FileMap>> {
+        if len > MAX_FILE_BYTES {
+            return Ok(None);
+        }
+        // Not UTF-8, so not source code we can map
+        let Ok(source) = fs::read_to_string(full_path) else {
+            return Ok(None);
+        };
+        if source.lines().any(|line| line.len() > MAX_LINE_CHARS) {
+            return Ok(None);
+        }
+        parser.set_language(language)?;
+        let Some(tree) = parser.parse(&source, None) else {
+            return Ok(None);
+        };
+        let root_node = tree.root_node();
+        let symbols = Self::symbols(root_node, source.as_bytes(), false);
+        if symbols.is_empty() {
+            return Ok(None);
+        }
+        let mut references = HashSet::new();
+        Self::references(root_node, source.as_bytes(), &mut references);
+        Ok(Some(FileMap { path: path.to_string(), symbols, references }))
+    }
+
+    /// Map of the files under `dir`, or the whole project, for the repo_map tool.
+    pub fn directory(root: &Path, dir: Option<&str>) -> Result<String> {
+        let dir = dir.unwrap_or_default().trim_matches('/');
+        let files: Vec<String> = GitTree::list_files(root)?
+            .into_iter()
+            .filter(|file| dir.is_empty() || file.strip_prefix(dir).is_some_and(|rest| rest.starts_with('/')))
+            .collect();
+        let map = Self::build(root, &files, MAX_TOOL_LINES)?;
+        if map.is_empty() {
+            return Err(anyhow!("No functions, types or classes found in {}", if dir.is_empty() { "the project" } else { dir }));
+        }
+        Ok(map)
+    }
+
+    fn language(path: &str) -> Option<Language> {
+        let extension = Path::new(path).extension()?.to_str()?;
+        let language = match extension {
+            "rs" => tree_sitter_rust::LANGUAGE,
+            "py" => tree_sitter_python::LANGUAGE,
+            "js" | "jsx" | "mjs" | "cjs" => tree_sitter_javascript::LANGUAGE,
+            "ts" | "mts" | "cts" => tree_sitter_typescript::LANGUAGE_TYPESCRIPT,
+            "tsx" => tree_sitter_typescript::LANGUAGE_TSX,
+            "go" => tree_sitter_go::LANGUAGE,
+            _ => return None,
+        };
+        Some(language.into())
+    }
+
+    // Kind shown in the map for declarations of interest, node kinds are unique across the grammars
+    fn kind(node_kind: &str) -> Option<&'static str> {
+        Some(match node_kind {
+            "function_item" | "function_signature_item" => "fn",
+            "struct_item" => "struct",
+            "enum_item" | "enum_declaration" => "enum",
+            "union_item" => "union",
+            "trait_item" => "trait",
+            "impl_item" => "impl",
+            "mod_item" => "mod",
+            "macro_definition" => "macro",
+            "type_item" | "type_alias_declaration" | "type_spec" => "type",
+            "function_definition" => "def",
+            "class_definition" => "class",
+            "function_declaration" | "generator_function_declaration" => "function",
+            "class_declaration" | "abstract_class_declaration" => "class",
+            "method_definition" | "method_declaration" => "method",
+            "interface_declaration" => "interface",
+            "variable_declarator" => "const",
+            _ => return None,
+        })
+    }
+
+    fn symbols(node: Node, source: &[u8], in_body: bool) -> Vec<Symbol> {
+        let mut cursor = node.walk();
+        node.named_children(&mut cursor)
+            .flat_map(|child| Self::declaration(child, source, in_body))
+            .collect()
+    }
+
+    fn declaration(node: Node, source: &[u8], in_body: bool) -> Vec<Symbol> {
+        match node.kind() {
+            // Wrappers around the declaration itself
+            "export_statement" | "decorated_definition" => node.child_by_field_name("declaration")
+                .or_else(|| node.child_by_field_name("definition"))
+                .map(|inner| Self::declaration(inner, source, in_body))
+                .unwrap_or_default(),
+            // `const handler = () => {}` at the top of a JavaScript module, and Go's type blocks
+            "lexical_declaration" | "type_declaration" if !in_body => Self::symbols(node, source, in_body),
+            _ => Self::symbols_of(node, source, in_body).into_iter().collect(),
+        }
+    }
+
+    fn symbols_of(node: Node, source: &[u8], in_body: bool) -> Option<Symbol> {
+        let kind = Self::kind(node.kind())?;
+        // Impls are named by their type, with the trait they implement if any
+        let name = match node.kind() {
+            "impl_item" => {
+                let type_name = Self::text(node.child_by_field_name("type")?, source);
+                match node.child_by_field_name("trait") {
+                    Some(name) => format!("{} for {}", Self::text(name, source), type_name),
+                    None => type_name,
+                }
+            }
+            _ => Self::text(node.child_by_field_name("name")?, source),
+        };
+        // `mod name;` is only a pointer to another file and tests aren't part of the API
+        if kind == "mod" && (node.child_by_field_name("body").is_none() || name == "tests") {
+            return None;
+        }
+        // Only methods and functions make it into a body, not nested types
+        if in_body && !matches!(kind, "fn" | "def" | "method") {
+            return None;
+        }
+        let members = match node.child_by_field_name("body") {
+            Some(body) if !in_body && matches!(kind, "impl" | "trait" | "class" | "interface") => {
+                Self::symbols(body, source, true)
+            }
+            _ => Vec::new(),
+        };
+        Some(Symbol { kind, name, members })
+    }
+
+    fn references(node: Node, source: &[u8], references: &mut HashSet<String>) {
+        let mut cursor = node.walk();
+        let mut stack = vec![node];
+        while let Some(node) = stack.pop() {
+            if node.kind().ends_with("identifier") {
+                references.insert(Self::text(node, source));
+            }
+            stack.extend(node.named_children(&mut cursor));
+        }
+    }
+
+    fn text(node: Node, source: &[u8]) -> String {
+        node.utf8_text(source).unwrap_or_default().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lists_symbols_of_most_referenced_files_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("lib.rs"), "mod config;\npub struct Config;\nimpl Config {\n    pub fn load() -> Self { Config }\n}\nfn helper() {}\n#[cfg(test)]\nmod tests {}\n").unwrap();
+        fs::write(root.join("main.rs"), "fn main() { let config = Config::load(); }\n").unwrap();
+        fs::write(root.join("app.py"), "from lib import load\n\n@route\ndef index():\n    return load()\n\nclass View:\n    def get(self):\n        pass\n").unwrap();
+        fs::write(root.join("ui.ts"), "export interface Props { name: string }\nexport const render = (props: Props) => props.name;\n").unwrap();
+        fs::write(root.join("README.md"), "# notes\n").unwrap();
+        let files = ["README.md", "app.py", "lib.rs", "main.rs", "ui.ts"].map(String::from);
+
+        assert_eq!(RepoMap::build(root, &files, 100).unwrap(), concat!(
+            "app.py\n",
+            "  def index\n",
+            "  class View\n",
+            "    def get\n",
+            "lib.rs\n",
+            "  struct Config\n",
+            "  impl Config\n",
+            "    fn load\n",
+            "  fn helper\n",
+            "main.rs\n",
+            "  fn main\n",
+            "ui.ts\n",
+            "  interface Props\n",
+            "  const render\n",
+        ));
+
+        // lib.rs declares what the other files use, so it is kept over the rest
+        assert_eq!(RepoMap::build(root, &files, 6).unwrap(), concat!(
+            "lib.rs\n",
+            "  struct Config\n",
+            "  impl Config\n",
+            "    fn load\n",
+            "  fn helper\n",
+            "... 3 more files with symbols\n",
+        ));
+
+        // Edited files are parsed again, deleted ones dropped
+        fs::write(root.join("lib.rs"), "pub struct Config;\npub fn load_config() -> Config { Config }\n").unwrap();
+        fs::remove_file(root.join("app.py")).unwrap();
+        let map = RepoMap::build(root, &files, 100).unwrap();
+        assert!(map.starts_with("lib.rs\n  struct Config\n  fn load_config\nmain.rs\n"), "{}", map);
+        assert!(!PARSED.lock().unwrap().contains_key(&root.join("app.py")));
+    }
+}
@@ -1,16 +1,15 @@
-use actix_web::{web, App, HttpResponse, HttpServer, Responder, get, HttpRequest};
+use actix_web::{web, App, HttpResponse, HttpServer, Responder, get, post, HttpRequest};
 use actix_cors::Cors;
 use handlebars::Handlebars;
 use include_dir::{include_dir, Dir};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::sync::Mutex;
+use tokio::sync::Mutex;
 use std::collections::HashMap;
 use actix_web::http;
-use std::process::Command;
 use std::str;
 
-use crate::{chat::{Chat, CommonMessage}, tools::Tools};
+use crate::{checkpoint::Checkpoint, chat::{Chat, CommonMessage, ContentItem, ImageSource, WorktreeAction}, config::ProjectConfig, diff::{Diff, DiffOptions, FileDiff}, error::ApiError, inference::{retry::SharedRetryStatus, types::SamplingParams}, models::{EndpointConfig, ModelSpec}, tools::Tools};
 
 #[derive(Deserialize)]
 pub struct ChatRequest {
@@ -24,9 +23,60 @@ pub struct ToolRequest {
     input: Value,
 }
 
+#[derive(Deserialize)]
+pub struct ToolBatchRequest {
+    tool_uses: Vec<ToolRequest>,
+}
+
 #[derive(Deserialize)]
 pub struct ConfigRequest {
-    model: String,
+    #[serde(default)]
+    model: Option<String>,
+    /// Replaces the session's overrides of the sampling parameters in pprog.toml
+    #[serde(default)]
+    sampling: Option<SamplingParams>,
+}
+
+#[derive(Deserialize)]
+pub struct WorktreeRequest {
+    /// Commit message for the session's changes
+    #[serde(default)]
+    message: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ReviewRequest {
+    path: String,
+    /// Hunks of the file to accept or reject, the whole file when empty
+    #[serde(default)]
+    hunks: Vec<ReviewHunk>,
+    /// Reject back to this checkpoint instead of HEAD
+    #[serde(default)]
+    checkpoint: Option<usize>,
+}
+
+/// A hunk as the client saw it, its position tells whether the diff changed since
+#[derive(Deserialize)]
+pub struct ReviewHunk {
+    index: usize,
+    old_start: u32,
+    new_start: u32,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReviewAction {
+    Accept,
+    Reject,
+}
+
+impl std::fmt::Display for ReviewAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReviewAction::Accept => write!(f, "accept"),
+            ReviewAction::Reject => write!(f, "reject"),
+        }
+    }
 }
 
 #[derive(Serialize, Clone)]
@@ -38,25 +88,42 @@ pub struct ChatResponse {
 pub struct ToolResponse {
     tool_use_id: String,
     content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<ImageSource>,
 }
 
-#[derive(Serialize, Clone)]
-pub struct ErrorResponse {
-    error: String,
+#[derive(Serialize)]
+pub struct ToolBatchResponse {
+    results: Vec<ToolResponse>,
+}
+
+#[derive(Serialize)]
+pub struct ModelsResponse {
+    current: String,
+    models: Vec<ModelSpec>,
 }
 
 #[derive(Serialize)]
 pub struct DiffResponse {
-    diff: String,
+    files: Vec<FileDiff>,
+}
+
+#[derive(Serialize)]
+pub struct CheckpointsResponse {
+    checkpoints: Vec<Checkpoint>,
 }
 
 pub struct AppState {
     chat: Mutex<Chat>,
+    retry_status: SharedRetryStatus,
     static_files: HashMap<String, Vec<u8>>,
 }
 
 static DIST_DIR: Dir = include_dir!("./frontend/dist/");
 
+// Room for a few base64 encoded images in a single chat message
+const MAX_JSON_PAYLOAD: usize = 32 * 1024 * 1024;
+
 // Rest of the existing code remains the same
 fn get_mime_type(filename: &str) -> &'static str {
     match filename {
@@ -70,13 +137,33 @@ fn get_mime_type(filename: &str) -> &'static str {
 
 #[get("/messages")]
 async fn get_messages(data: web::Data<AppState>) -> impl Responder {
-    let chat = data.chat.lock().unwrap();
-    HttpResponse::Ok().json(&chat.get_messages())
+    let chat = data.chat.lock().await;
+    HttpResponse::Ok().json(chat.get_messages())
+}
+
+#[get("/models")]
+async fn get_models(data: web::Data<AppState>) -> impl Responder {
+    let chat = data.chat.lock().await;
+    HttpResponse::Ok().json(ModelsResponse {
+        current: chat.get_model().name.clone(),
+        // Endpoint headers may hold credentials
+        models: chat.get_registry().models().iter().map(|spec| ModelSpec {
+            endpoint: EndpointConfig { headers: HashMap::new(), ..spec.endpoint.clone() },
+            ..spec.clone()
+        }).collect(),
+    })
+}
+
+// Does not lock the chat so it can be polled while a message is being handled
+#[get("/status")]
+async fn get_status(data: web::Data<AppState>) -> impl Responder {
+    let retry = data.retry_status.lock().unwrap().clone();
+    HttpResponse::Ok().json(json!({"retry": retry}))
 }
 
 #[get("/clear")]
 async fn clear_chat(data: web::Data<AppState>) -> impl Responder {
-    let mut chat = data.chat.lock().unwrap();
+    let mut chat = data.chat.lock().await;
     chat.clear();
     HttpResponse::Ok().json(json!({"cleared": true, "message": "Chat history cleared"}))
 }
@@ -84,36 +171,163 @@ async fn clear_chat(data: web::Data<AppState>) -> impl Responder {
 async fn config_handler(
     data: web::Data<AppState>, 
     req: web::Json<ConfigRequest>
-) -> impl Responder {
-    let mut chat = data.chat.lock().unwrap();
-    let acknowledged = chat.update_config(&req.0.model);
-    match acknowledged {
-        true => HttpResponse::Ok().json(json!({"acknowledged": true})),
-        false => HttpResponse::Ok().json(json!({"acknowledged": false})),
+) -> Result<HttpResponse, ApiError> {
+    let mut chat = data.chat.lock().await;
+    let req = req.into_inner();
+    let model = match req.model {
+        Some(model) => match chat.resolve_model(&model) {
+            Some(model) => Some(model),
+            None => return Ok(HttpResponse::Ok().json(json!({"acknowledged": false}))),
+        },
+        None => None,
+    };
+    let has_sampling = req.sampling.is_some();
+    let sampling = chat.update_config(model, req.sampling).map_err(ApiError::Config)?;
+    if has_sampling {
+        Ok(HttpResponse::Ok().json(json!({"acknowledged": true, "sampling": sampling})))
+    } else {
+        Ok(HttpResponse::Ok().json(json!({"acknowledged": true})))
     }
 }
 
 #[get("/diff")]
-async fn get_diff() -> impl Responder {
-    // Run git diff command
-    let output = match Command::new("git")
-        .args(["diff"])
-        .output() {
-            Ok(output) => output,
-            Err(e) => return HttpResponse::InternalServerError().json(ErrorResponse {
-                error: e.to_string(),
-            })
-        };
+async fn get_diff(
+    data: web::Data<AppState>,
+    query: web::Query<DiffOptions>,
+) -> Result<HttpResponse, ApiError> {
+    let chat = data.chat.lock().await;
+    let mut options = query.into_inner();
+    if let Some(base) = &options.base {
+        let base = Diff::resolve_base(chat.root(), base).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+        options.base = Some(base);
+    }
+    let files = Diff::files(chat.root(), &options)?;
+    Ok(HttpResponse::Ok().json(DiffResponse { files }))
+}
 
-    // Convert output to string
-    let diff_str = match str::from_utf8(&output.stdout) {
-        Ok(s) => s.to_string(),
-        Err(e) => return HttpResponse::InternalServerError().json(ErrorResponse {
-            error: e.to_string(),
-        })
+#[get("/checkpoints")]
+async fn get_checkpoints(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let chat = data.chat.lock().await;
+    let checkpoints = match chat.checkpoints() {
+        Some(checkpoints) => checkpoints.list()?,
+        None => Vec::new(),
+    };
+    Ok(HttpResponse::Ok().json(CheckpointsResponse { checkpoints }))
+}
+
+/*
+    * Changes still to review are the ones not staged yet, /diff?staged=false.  Accepting stages a
+    * file or some of its hunks.  Rejecting puts a whole file back as it was staged, or as it was in
+    * a checkpoint, or reverts some of its hunks in the working tree.  Hunks are numbered as in
+    * /diff?staged=false&path=<path>, or /checkpoints/<n>/diff?path=<path> when rejecting back to
+    * a checkpoint, and come with the start lines they were shown with.  If the file changed since
+    * and a hunk moved, the request is a conflict rather than applied to whatever is there now.
+*/
+async fn review_handler(
+    data: web::Data<AppState>,
+    path: web::Path<ReviewAction>,
+    req: web::Json<ReviewRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let action = path.into_inner();
+    let ReviewRequest { path, hunks, checkpoint } = req.into_inner();
+    let chat = data.chat.lock().await;
+    let base = match checkpoint {
+        Some(_) if action == ReviewAction::Accept => {
+            return Err(ApiError::BadRequest("Accepted changes are staged against HEAD, not a checkpoint".to_string()));
+        },
+        Some(checkpoint_index) => Some(checkpoint_id(&chat, checkpoint_index)?),
+        None => None,
+    };
+
+    let options = DiffOptions {
+        staged: base.is_some(),
+        path: Some(path.clone()),
+        base: base.clone(),
+        ..DiffOptions::default()
+    };
+    let files = Diff::files(chat.root(), &options)?;
+    let file = files.iter()
+        .find(|file| file.path == path)
+        .ok_or_else(|| ApiError::NotFound(format!("{} has no changes", path)))?;
+    let hunks = hunk_indices(file, &hunks)?;
+
+    let result = match action {
+        ReviewAction::Accept => file.stage(chat.root(), &hunks),
+        ReviewAction::Reject => file.revert(chat.root(), &hunks, base.as_deref()),
     };
+    result.map_err(|e| ApiError::Conflict(format!("Unable to {} {}: {}", action, path, e)))?;
+    chat.files_changed();
+
+    let files = Diff::files(chat.root(), &options)?;
+    Ok(HttpResponse::Ok().json(DiffResponse { files }))
+}
+
+fn hunk_indices(file: &FileDiff, hunks: &[ReviewHunk]) -> Result<Vec<usize>, ApiError> {
+    hunks.iter().map(|hunk| {
+        let current = file.hunks.get(hunk.index)
+            .ok_or_else(|| ApiError::BadRequest(format!("{} has no hunk {}", file.path, hunk.index)))?;
+        if (current.old_start, current.new_start) != (hunk.old_start, hunk.new_start) {
+            return Err(ApiError::Conflict(format!("Hunk {} of {} changed since it was shown", hunk.index, file.path)));
+        }
+        Ok(hunk.index)
+    }).collect()
+}
 
-    HttpResponse::Ok().json(DiffResponse { diff: diff_str })
+fn checkpoint_id(chat: &Chat, checkpoint_index: usize) -> Result<String, ApiError> {
+    chat.checkpoints()
+        .map(|checkpoints| checkpoints.list())
+        .transpose()?
+        .and_then(|checkpoints| checkpoints.into_iter().find(|c| c.index == checkpoint_index))
+        .map(|checkpoint| checkpoint.id)
+        .ok_or_else(|| ApiError::NotFound(format!("Checkpoint {} not found", checkpoint_index)))
+}
+
+#[get("/checkpoints/{index}/diff")]
+async fn get_checkpoint_diff(
+    data: web::Data<AppState>,
+    path: web::Path<usize>,
+    query: web::Query<DiffOptions>,
+) -> Result<HttpResponse, ApiError> {
+    let checkpoint_index = path.into_inner();
+    let chat = data.chat.lock().await;
+    let options = DiffOptions { base: Some(checkpoint_id(&chat, checkpoint_index)?), ..query.into_inner() };
+    let files = Diff::files(chat.root(), &options)?;
+    Ok(HttpResponse::Ok().json(DiffResponse { files }))
+}
+
+#[post("/checkpoints/{index}/restore")]
+async fn restore_checkpoint(
+    data: web::Data<AppState>,
+    path: web::Path<usize>,
+) -> Result<HttpResponse, ApiError> {
+    let checkpoint_index = path.into_inner();
+    let chat = data.chat.lock().await;
+    let checkpoints = chat.checkpoints()
+        .ok_or_else(|| ApiError::NotFound("Checkpoints are disabled".to_string()))?;
+    let checkpoint = checkpoints.restore(checkpoint_index)?
+        .ok_or_else(|| ApiError::NotFound(format!("Checkpoint {} not found", checkpoint_index)))?;
+    chat.files_changed();
+    Ok(HttpResponse::Ok().json(checkpoint))
+}
+
+#[get("/worktree")]
+async fn get_worktree(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let chat = data.chat.lock().await;
+    let worktree = chat.worktree()
+        .ok_or_else(|| ApiError::NotFound("Worktree isolation is disabled".to_string()))?;
+    Ok(HttpResponse::Ok().json(worktree.info()))
+}
+
+async fn finish_worktree_handler(
+    data: web::Data<AppState>,
+    path: web::Path<WorktreeAction>,
+    req: Option<web::Json<WorktreeRequest>>,
+) -> Result<HttpResponse, ApiError> {
+    let message = req.and_then(|req| req.into_inner().message);
+    let mut chat = data.chat.lock().await;
+    chat.finish_worktree(path.into_inner(), message)?;
+    // The next session's worktree, absent if it couldn't be created
+    Ok(HttpResponse::Ok().json(json!({"finished": true, "worktree": chat.worktree().map(|w| w.info())})))
 }
 
 /*
@@ -133,34 +347,70 @@ async fn get_diff() -> impl Responder {
 async fn chat_handler(
     data: web::Data<AppState>, 
     req: web::Json<ChatRequest>
-) -> impl Responder {
-    let mut chat = data.chat.lock().unwrap();
-
-    match chat.handle_message(&req.0.message).await {
-        Ok(m) => HttpResponse::Ok().json(ChatResponse {
-            message: m
-        }),
-        // TODO implement logic to parse error into JSON and handle specific errors
-        Err(e) => HttpResponse::InternalServerError().json(ErrorResponse {
-            error: e.to_string(),
-        }),
+) -> Result<HttpResponse, ApiError> {
+    // Pasted or uploaded images arrive base64 encoded in the message content
+    for content_item in &req.message.content {
+        if let ContentItem::Image { source } = content_item {
+            source.validate().map_err(|e| ApiError::BadRequest(e.to_string()))?;
+        }
     }
+
+    let mut chat = data.chat.lock().await;
+    let message = chat.handle_message(&req.0.message).await?;
+    Ok(HttpResponse::Ok().json(ChatResponse { message }))
 }
 
 async fn tool_handler(
+    data: web::Data<AppState>,
     req: web::Json<ToolRequest>
-) -> impl Responder {
-    match Tools::handle_tool_use(&req.name, &req.input) {
-        Ok(tool_result) => {
-            HttpResponse::Ok().json(ToolResponse {
-                tool_use_id: req.0.id,
-                content: tool_result,
-            })
-        },
-        Err(e) => HttpResponse::InternalServerError().json(ErrorResponse {
-            error: e.to_string(),
+) -> Result<HttpResponse, ApiError> {
+    let ToolRequest { id, name, input } = req.into_inner();
+    let root = {
+        let mut chat = data.chat.lock().await;
+        if !Tools::is_read_only(&name) {
+            chat.checkpoint_turn();
+            chat.files_changed();
+        }
+        chat.root().to_path_buf()
+    };
+    let tool_result = Tools::handle_tool_use(&root, &name, &input)?;
+    Ok(HttpResponse::Ok().json(ToolResponse {
+        tool_use_id: id,
+        content: tool_result.content,
+        image: tool_result.image,
+    }))
+}
+
+async fn tool_batch_handler(
+    data: web::Data<AppState>,
+    req: web::Json<ToolBatchRequest>
+) -> Result<HttpResponse, ApiError> {
+    let root = {
+        let mut chat = data.chat.lock().await;
+        if req.tool_uses.iter().any(|tool_use| !Tools::is_read_only(&tool_use.name)) {
+            chat.checkpoint_turn();
+            chat.files_changed();
+        }
+        chat.root().to_path_buf()
+    };
+    let (ids, tool_uses): (Vec<String>, Vec<(String, Value)>) = req.into_inner().tool_uses
+        .into_iter()
+        .map(|tool_use| (tool_use.id, (tool_use.name, tool_use.input)))
+        .unzip();
+    let outputs = Tools::handle_tool_uses(&root, tool_uses).await;
+
+    // Every call has run by now, a failed one reports its error to the model like any other result
+    let results = ids.into_iter().zip(outputs)
+        .map(|(tool_use_id, output)| match output {
+            Ok(tool_result) => ToolResponse {
+                tool_use_id,
+                content: tool_result.content,
+                image: tool_result.image,
+            },
+            Err(e) => ToolResponse { tool_use_id, content: e.to_string(), image: None },
         })
-    }
+        .collect();
+    Ok(HttpResponse::Ok().json(ToolBatchResponse { results }))
 }
 
 fn process_files(
@@ -196,7 +446,37 @@ fn process_files(
     }
 }
 
-pub async fn start_server(host: String, port: u16) -> std::io::Result<()> {
+impl AppState {
+    fn new(chat: Chat, static_files: HashMap<String, Vec<u8>>) -> Self {
+        AppState {
+            retry_status: chat.retry_status(),
+            chat: Mutex::new(chat),
+            static_files,
+        }
+    }
+}
+
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(web::JsonConfig::default().limit(MAX_JSON_PAYLOAD))
+        .route("/chat", web::post().to(chat_handler))
+        .route("/tools", web::post().to(tool_handler))
+        .route("/tools/batch", web::post().to(tool_batch_handler))
+        .route("/config", web::post().to(config_handler))
+        .route("/worktree/{action}", web::post().to(finish_worktree_handler))
+        .route("/diff/{action}", web::post().to(review_handler))
+        .service(clear_chat)
+        .service(get_messages)
+        .service(get_models)
+        .service(get_status)
+        .service(get_diff)
+        .service(get_checkpoints)
+        .service(get_checkpoint_diff)
+        .service(restore_checkpoint)
+        .service(get_worktree)
+        .service(index);
+}
+
+pub async fn start_server(host: String, port: u16, config: ProjectConfig) -> std::io::Result<()> {
     let server_url = format!("http://{}:{}", host, port);
     let template_data = json!({
         "server_url": server_url
@@ -207,10 +487,8 @@ pub async fn start_server(host: String, port: u16) -> std::io::Result<()> {
     
     process_files(&DIST_DIR, "", &mut static_files, &mut hbs, &template_data);
 
-    let app_state = web::Data::new(AppState {
-        chat: Mutex::new(Chat::new()),
-        static_files,
-    });
+    let chat = Chat::new(config).map_err(std::io::Error::other)?;
+    let app_state = web::Data::new(AppState::new(chat, static_files));
 
     println!("Starting server on {}:{}", host, port);
 
@@ -231,13 +509,7 @@ pub async fn start_server(host: String, port: u16) -> std::io::Result<()> {
         App::new()
             .wrap(cors)
             .app_data(app_state.clone())
-            .route("/chat", web::post().to(chat_handler))
-            .route("/tools", web::post().to(tool_handler))
-            .route("/config", web::post().to(config_handler)) // Add this line
-            .service(clear_chat)
-            .service(get_messages)
-            .service(get_diff)
-            .service(index)
+            .configure(routes)
     })
     .bind(format!("{}:{}", host, port))?
     .run()
@@ -280,3 +552,141 @@ async fn index(
 
     HttpResponse::NotFound().body(format!("File not found: {}", path))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test};
+    use crate::inference::mock::mock_config;
+    use crate::git;
+    use std::path::Path;
+
+    macro_rules! mock_app {
+        ($responses:expr) => {{
+            let (config, dir) = mock_config($responses);
+            let state = web::Data::new(AppState::new(Chat::new(config).unwrap(), HashMap::new()));
+            (test::init_service(App::new().app_data(state).configure(routes)).await, dir)
+        }};
+    }
+
+    #[actix_web::test]
+    async fn test_chat_endpoint_returns_scripted_response() {
+        let (app, _dir) = mock_app!(json!([
+            { "content": [{ "type": "text", "text": "Hi there." }] }
+        ]));
+
+        let req = test::TestRequest::post().uri("/chat").set_json(json!({
+            "message": { "role": "user", "content": [{ "type": "text", "text": "Hello" }] }
+        })).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["message"]["content"][0]["text"], "Hi there.");
+        assert_eq!(body["message"]["model"], "mock-model");
+
+        let req = test::TestRequest::get().uri("/messages").to_request();
+        let messages: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(messages.len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_tool_batch_reports_each_failure_as_its_result() {
+        let (app, _dir) = mock_app!(json!([]));
+
+        let req = test::TestRequest::post().uri("/tools/batch").set_json(json!({ "tool_uses": [
+            { "id": "call_1", "name": "read_file", "input": { "path": "does/not/exist.rs" } },
+            { "id": "call_2", "name": "read_file", "input": { "path": "Cargo.toml" } },
+        ] })).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let results = body["results"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["tool_use_id"], "call_1");
+        assert!(results[0]["content"].as_str().unwrap().starts_with("Tool failed"));
+        assert_eq!(results[1]["tool_use_id"], "call_2");
+        assert!(results[1]["content"].as_str().unwrap().contains("[package]"));
+    }
+
+    #[actix_web::test]
+    async fn test_errors_have_status_and_code() {
+        let (app, _dir) = mock_app!(json!([
+            { "error": { "status": 429, "message": "slow down" } }
+        ]));
+
+        let req = test::TestRequest::post().uri("/chat").set_json(json!({
+            "message": { "role": "user", "content": [{ "type": "text", "text": "Hello" }] }
+        })).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "rate_limited");
+        assert_eq!(body["retryable"], true);
+
+        let req = test::TestRequest::post().uri("/tools").set_json(json!({
+            "id": "call_1", "name": "delete_everything", "input": {}
+        })).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "unknown_tool");
+    }
+
+    #[actix_web::test]
+    async fn test_tools_leave_no_checkpoints_in_the_repository() {
+        let (app, _dir) = mock_app!(json!([
+            { "error": { "status": 429, "message": "slow down" } }
+        ]));
+        let refs = || git::run(Path::new("."), &["for-each-ref", "refs/pprog"]).unwrap_or_default();
+        let before = refs();
+
+        // The turn's checkpoint is taken by the first tool that writes
+        let req = test::TestRequest::post().uri("/chat").set_json(json!({
+            "message": { "role": "user", "content": [{ "type": "text", "text": "Hello" }] }
+        })).to_request();
+        test::call_service(&app, req).await;
+        let req = test::TestRequest::post().uri("/tools").set_json(json!({
+            "id": "call_1", "name": "delete_everything", "input": {}
+        })).to_request();
+        test::call_service(&app, req).await;
+        assert_eq!(refs(), before);
+    }
+
+    #[actix_web::test]
+    async fn test_hunks_that_moved_are_a_conflict() {
+        use crate::diff::{FileStatus, Hunk};
+
+        let hunk = |old_start, new_start| Hunk {
+            old_start,
+            old_lines: 1,
+            new_start,
+            new_lines: 1,
+            header: String::new(),
+            lines: Vec::new(),
+            patch: String::new(),
+        };
+        let file = FileDiff {
+            path: "main.rs".to_string(),
+            old_path: None,
+            status: FileStatus::Modified,
+            untracked: false,
+            binary: false,
+            hunks: vec![hunk(2, 2), hunk(19, 20)],
+            header: String::new(),
+        };
+        let shown = |hunk_index, old_start, new_start| ReviewHunk { index: hunk_index, old_start, new_start };
+
+        assert_eq!(hunk_indices(&file, &[shown(1, 19, 20)]).unwrap(), [1]);
+        assert_eq!(hunk_indices(&file, &[shown(1, 18, 18)]).unwrap_err().code(), "conflict");
+        assert_eq!(hunk_indices(&file, &[shown(2, 30, 30)]).unwrap_err().code(), "invalid_request");
+    }
+
+    #[actix_web::test]
+    async fn test_worktree_endpoints_need_isolation() {
+        let (app, _dir) = mock_app!(json!([]));
+
+        let req = test::TestRequest::post().uri("/worktree/squash").set_json(json!({
+            "message": "Add a feature"
+        })).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "not_found");
+    }
+}
@@ -1,15 +1,67 @@
 use std::fs;
-use std::path::Path;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
 use anyhow::Result;
+use futures::future::join_all;
+use serde_json::Value;
 
+use crate::chat::ImageSource;
 use crate::config::ProjectConfig;
+use crate::git_tools::GitTools;
+use crate::repo_map::RepoMap;
 
 pub struct Tools;
 
+pub struct ToolOutput {
+    pub content: String,
+    /// Image for the model to view, sent back alongside the tool result
+    pub image: Option<ImageSource>,
+}
+
+#[derive(Debug)]
+pub enum ToolError {
+    UnknownTool(String),
+    InvalidInput(String),
+    Failed(String),
+}
+
+impl std::fmt::Display for ToolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ToolError::UnknownTool(name) => write!(f, "Invalid tool name: {}", name),
+            ToolError::InvalidInput(msg) => write!(f, "Invalid tool input: {}", msg),
+            ToolError::Failed(msg) => write!(f, "Tool failed: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ToolError {}
+
+impl From<anyhow::Error> for ToolError {
+    fn from(error: anyhow::Error) -> Self {
+        ToolError::Failed(error.to_string())
+    }
+}
+
+impl From<String> for ToolOutput {
+    fn from(content: String) -> Self {
+        ToolOutput {
+            content,
+            image: None,
+        }
+    }
+}
+
 impl Tools {
-    fn read_file(path: &str) -> Result<String> {
-        let contents = fs::read_to_string(path)?;
+    /// Tools without side effects, consecutive calls to these are run concurrently
+    const READ_ONLY: [&'static str; 7] = ["read_file", "read_image", "git_status", "git_log", "git_blame", "git_show", "repo_map"];
+
+    pub fn is_read_only(name: &str) -> bool {
+        Self::READ_ONLY.contains(&name)
+    }
+
+    fn read_file(root: &Path, path: &str) -> Result<String> {
+        let contents = fs::read_to_string(root.join(path))?;
         let lines = contents.lines().enumerate();
         let result = lines
             .map(|(i, line)| format!("{:>4}: {}", i + 1, line))
@@ -18,8 +70,21 @@ impl Tools {
         Ok(result)
     }
 
-    fn write_file(path: &str, content: &str) -> Result<()> {
-        if let Some(parent) = Path::new(path).parent() {
+    fn read_image(root: &Path, path: &str) -> Result<ToolOutput> {
+        let media_type = mime_guess::from_path(path)
+            .first_raw()
+            .ok_or_else(|| anyhow::anyhow!("Unable to determine image type of {}", path))?;
+        let bytes = fs::read(root.join(path))?;
+        let image = ImageSource::from_bytes(media_type, &bytes)?;
+        Ok(ToolOutput {
+            content: format!("Image {} ({}, {} bytes) attached below.", path, media_type, bytes.len()),
+            image: Some(image),
+        })
+    }
+
+    fn write_file(root: &Path, path: &str, content: &str) -> Result<()> {
+        let path = root.join(path);
+        if let Some(parent) = path.parent() {
             if !parent.exists() {
                 fs::create_dir_all(parent)?;
             }
@@ -27,68 +92,166 @@ impl Tools {
         Ok(fs::write(path, content)?)
     }
 
-    fn execute(statement: &str) -> Result<String> {
+    fn execute(root: &Path, statement: &str) -> Result<String> {
         let output = Command::new("bash")
             .arg("-c")
             .arg(statement)
+            .current_dir(root)
             .output()?;
 
         Ok(String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?)
     }
 
-    fn compile_check() -> Result<String, anyhow::Error> {
+    fn compile_check(root: &Path) -> Result<String, anyhow::Error> {
         let config = ProjectConfig::load().map_err(|e| anyhow::anyhow!("{}", e))?;
-
-        let output = Command::new("bash")
-            .arg("-c")
-            .arg(config.check_cmd)
-            .output()?;
+        let output = Tools::run_check(root, &config.check_cmd)?;
 
         Ok(String::from_utf8(output.stdout)? + &String::from_utf8(output.stderr)?)
     }
 
-    pub fn handle_tool_use(name: &String, inputs: &serde_json::Value) -> Result<String, anyhow::Error> {
+    /// Runs the project's check command in `root`.
+    pub fn run_check(root: &Path, check_cmd: &str) -> Result<Output> {
+        Ok(Command::new("bash")
+            .arg("-c")
+            .arg(check_cmd)
+            .current_dir(root)
+            .output()?)
+    }
+
+    /// Runs a tool with relative paths and commands resolved against `root`, the project or its worktree.
+    pub fn handle_tool_use(root: &Path, name: &String, inputs: &serde_json::Value) -> Result<ToolOutput, ToolError> {
         match name.as_str() {
             "read_file" => {
                 let path = inputs.get("path")
                     .and_then(|v| v.as_str())
-                    .ok_or_else(|| anyhow::Error::msg("Missing or invalid 'path' input".to_string()))?;
+                    .ok_or_else(|| ToolError::InvalidInput("Missing or invalid 'path' input".to_string()))?;
 
-                Tools::read_file(path)
+                Ok(Tools::read_file(root, path).map(ToolOutput::from)?)
+            },
+            "read_image" => {
+                let path = inputs.get("path")
+                    .and_then(|v| v.as_str())
+                    .ok_or_else(|| ToolError::InvalidInput("Missing or invalid 'path' input".to_string()))?;
+
+                Ok(Tools::read_image(root, path)?)
             },
             "write_file" => {
                 let path = inputs
                     .get("path")
                     .and_then(|v| v.as_str())
-                    .ok_or_else(|| anyhow::Error::msg("Missing or invalid 'path' input".to_string()))?;
+                    .ok_or_else(|| ToolError::InvalidInput("Missing or invalid 'path' input".to_string()))?;
 
                 let content = inputs
                     .get("content")
                     .and_then(|v| v.as_str())
-                    .ok_or_else(|| anyhow::Error::msg("Missing or invalid 'content' input".to_string()))?;
+                    .ok_or_else(|| ToolError::InvalidInput("Missing or invalid 'content' input".to_string()))?;
 
-                Tools::write_file(path, content)?;
-                Ok("File written successfully".to_string())
+                Tools::write_file(root, path, content)?;
+                Ok(ToolOutput::from("File written successfully".to_string()))
             },
             "execute" => {
                 let statement = inputs
                     .get("statement")
                     .and_then(|v| v.as_str())
-                    .ok_or_else(|| anyhow::Error::msg("Missing or invalid 'statement' input".to_string()))?;
+                    .ok_or_else(|| ToolError::InvalidInput("Missing or invalid 'statement' input".to_string()))?;
 
-                Tools::execute(statement)
+                Ok(Tools::execute(root, statement).map(ToolOutput::from)?)
             },
             "compile_check" => {
-                match Tools::compile_check() {
-                    Ok(output) => {
-                        return Ok(output);
-                    },
-                    Err(e) => return Err(anyhow::Error::msg(format!("Error doing compile check: {}", e.to_string()))),
-                };
+                Tools::compile_check(root)
+                    .map(ToolOutput::from)
+                    .map_err(|e| ToolError::Failed(format!("Error doing compile check: {}", e)))
+            },
+            "git_status" => Ok(GitTools::status(root).map(ToolOutput::from)?),
+            "git_log" => {
+                let path = inputs.get("path").and_then(|v| v.as_str());
+                let max_count = Tools::optional_number(inputs, "max_count")?;
+
+                Ok(GitTools::log(root, path, max_count).map(ToolOutput::from)?)
+            },
+            "git_blame" => {
+                let path = inputs.get("path")
+                    .and_then(|v| v.as_str())
+                    .ok_or_else(|| ToolError::InvalidInput("Missing or invalid 'path' input".to_string()))?;
+                let start_line = Tools::optional_number(inputs, "start_line")?;
+                let end_line = Tools::optional_number(inputs, "end_line")?;
+
+                Ok(GitTools::blame(root, path, start_line, end_line).map(ToolOutput::from)?)
             },
-            _ => Err(anyhow::Error::msg(format!("Invalid tool name: {}", name))),
+            "git_show" => {
+                let revision = inputs.get("revision")
+                    .and_then(|v| v.as_str())
+                    .ok_or_else(|| ToolError::InvalidInput("Missing or invalid 'revision' input".to_string()))?;
+
+                Ok(GitTools::show(root, revision).map(ToolOutput::from)?)
+            },
+            "repo_map" => {
+                let path = inputs.get("path").and_then(|v| v.as_str());
+
+                Ok(RepoMap::directory(root, path).map(ToolOutput::from)?)
+            },
+            _ => Err(ToolError::UnknownTool(name.to_string())),
         }
     }
 
+    fn optional_number(inputs: &serde_json::Value, name: &str) -> Result<Option<usize>, ToolError> {
+        match inputs.get(name) {
+            None | Some(Value::Null) => Ok(None),
+            Some(value) => value.as_u64()
+                .map(|n| Some(n as usize))
+                .ok_or_else(|| ToolError::InvalidInput(format!("'{}' must be a positive integer", name))),
+        }
+    }
+
+    /// Runs the tool uses of one assistant message and returns their outputs in the same order.
+    /// Consecutive read-only tools run concurrently, any other tool waits for the calls before it.
+    pub async fn handle_tool_uses(root: &Path, tool_uses: Vec<(String, Value)>) -> Vec<Result<ToolOutput, ToolError>> {
+        let mut outputs = Vec::with_capacity(tool_uses.len());
+        let mut read_only = Vec::new();
+        for (name, input) in tool_uses {
+            if Tools::is_read_only(&name) {
+                read_only.push(Tools::spawn_tool_use(root.to_path_buf(), name, input));
+                continue;
+            }
+            outputs.extend(join_all(std::mem::take(&mut read_only)).await);
+            outputs.push(Tools::spawn_tool_use(root.to_path_buf(), name, input).await);
+        }
+        outputs.extend(join_all(read_only).await);
+        outputs
+    }
+
+    async fn spawn_tool_use(root: PathBuf, name: String, input: Value) -> Result<ToolOutput, ToolError> {
+        tokio::task::spawn_blocking(move || Tools::handle_tool_use(&root, &name, &input))
+            .await
+            .map_err(|e| ToolError::Failed(e.to_string()))?
+    }
+
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_tool_outputs_keep_call_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        fs::write(path("a.txt"), "first").unwrap();
+
+        let outputs = Tools::handle_tool_uses(dir.path(), vec![
+            ("read_file".to_string(), json!({ "path": path("a.txt") })),
+            ("write_file".to_string(), json!({ "path": path("b.txt"), "content": "second" })),
+            ("read_file".to_string(), json!({ "path": path("b.txt") })),
+            ("read_file".to_string(), json!({ "path": path("missing.txt") })),
+        ]).await;
+
+        assert_eq!(outputs.len(), 4);
+        assert_eq!(outputs[0].as_ref().unwrap().content, "   1: first");
+        assert_eq!(outputs[1].as_ref().unwrap().content, "File written successfully");
+        assert_eq!(outputs[2].as_ref().unwrap().content, "   1: second");
+        assert!(outputs[3].is_err());
+    }
+}
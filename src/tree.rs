@@ -1,17 +1,48 @@
-use std::collections::BTreeMap;
-use std::process::Command;
-use std::path::PathBuf;
-use gix;
-use anyhow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use log::warn;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::config::TreeConfig;
+use crate::repo_map::RepoMap;
 
 pub struct GitTree;
 
 // Recursive type for our tree structure
 enum TreeNode {
-    File,
-    Dir(BTreeMap<String, TreeNode>),
+    File { size: Option<u64> },
+    Submodule,
+    Dir(TreeDir),
+}
+
+#[derive(Default)]
+struct TreeDir {
+    entries: BTreeMap<String, TreeNode>,
+    // Files anywhere below, shown when the directory is collapsed
+    files: usize,
+    // Holds a changed or recently modified file
+    priority: bool,
+}
+
+struct Rendered {
+    tree: String,
+    files: Vec<String>,
+    dirs: Vec<String>,
+}
+
+enum Listed {
+    // `stat` is what the index recorded, None for untracked files
+    File { path: String, stat: Option<gix::index::entry::Stat> },
+    Submodule(String),
 }
 
+// Recently modified files whose directories are expanded like changed ones
+const RECENT_FILES: usize = 10;
+
 impl GitTree {
     pub fn get_git_root() -> anyhow::Result<PathBuf> {
         // Discover repository from current directory
@@ -25,83 +56,396 @@ impl GitTree {
         Ok(root_path)
     }
 
-    pub fn get_tree() -> Result<String, anyhow::Error> {
-        let root = Self::get_git_root()?;
-        let mut cmd = Command::new("git");
-        let cmd = cmd.arg("ls-files")
-            .arg("-o")
-            .arg("--exclude-standard")
-            .arg("-c")
-            .current_dir(&root);
-
-        let output = cmd.output()?;
-
-        if !output.status.success() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                String::from_utf8_lossy(&output.stderr).to_string()
-            ).into());
-        }
-
-        let files: Vec<String> = String::from_utf8_lossy(&output.stdout)
-            .lines()
-            .map(String::from)
-            .collect();
-
-        let mut tree = BTreeMap::new();
-        for path in files {
-            let parts: Vec<&str> = path.split('/').collect();
-            let file_name = parts.last().unwrap();
-            let dir_parts = &parts[..parts.len() - 1];
-
-            let mut current = &mut tree;
-            for &part in dir_parts {
-                current = current
-                    .entry(part.to_string())
-                    .or_insert(TreeNode::Dir(BTreeMap::new()))
-                    .as_dir_mut()
-                    .unwrap();
+    /// Tracked and untracked files that aren't ignored, relative to `root`.  Submodules are left out.
+    pub fn list_files(root: &Path) -> Result<Vec<String>, anyhow::Error> {
+        Ok(Self::list(root, false)?
+            .into_iter()
+            .filter_map(|entry| match entry {
+                Listed::File { path, .. } => Some(path),
+                Listed::Submodule(_) => None,
+            })
+            .collect())
+    }
+
+    /*
+        * Lists the repository at `root` without the git binary.  Tracked files come from the index,
+        * untracked ones from walking the directory, skipping what .gitignore, .git/info/exclude
+        * and core.excludesFile ignore along with nested repositories.  Submodules are listed as a
+        * single entry unless `descend` is set, in which case their files are listed too.
+    */
+    fn list(root: &Path, descend: bool) -> Result<Vec<Listed>, anyhow::Error> {
+        let repo = gix::open(root)
+            .map_err(|e| anyhow::anyhow!("{} is not the root of a git repository: {}", root.display(), e))?;
+        let index = repo.index_or_empty()?;
+
+        let mut files = BTreeMap::new();
+        let mut submodules = BTreeSet::new();
+        for entry in index.entries() {
+            let path = entry.path(&index).to_string();
+            if entry.mode == gix::index::entry::Mode::COMMIT {
+                submodules.insert(path);
+            } else {
+                // Conflicted files have an entry per stage
+                files.entry(path).or_insert(Some(entry.stat));
+            }
+        }
+
+        let mut excludes = repo.excludes(&index, None, Default::default())?;
+        let mut untracked = Vec::new();
+        Self::walk_untracked(root, "", &files, &submodules, &mut excludes, &mut untracked)?;
+        files.extend(untracked.into_iter().map(|path| (path, None)));
+
+        let mut listed: Vec<Listed> = files.into_iter().map(|(path, stat)| Listed::File { path, stat }).collect();
+        for submodule in submodules {
+            let path = root.join(&submodule);
+            if descend && path.join(".git").exists() {
+                listed.extend(Self::list(&path, true)?.into_iter().map(|entry| match entry {
+                    Listed::File { path, stat } => Listed::File { path: format!("{}/{}", submodule, path), stat },
+                    Listed::Submodule(nested) => Listed::Submodule(format!("{}/{}", submodule, nested)),
+                }));
+            } else {
+                listed.push(Listed::Submodule(submodule));
+            }
+        }
+        Ok(listed)
+    }
+
+    fn walk_untracked(
+        root: &Path,
+        dir: &str,
+        tracked: &BTreeMap<String, Option<gix::index::entry::Stat>>,
+        submodules: &BTreeSet<String>,
+        excludes: &mut gix::AttributeStack<'_>,
+        untracked: &mut Vec<String>,
+    ) -> Result<(), anyhow::Error> {
+        for entry in std::fs::read_dir(root.join(dir))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name == ".git" {
+                continue;
+            }
+            let path = if dir.is_empty() { name } else { format!("{}/{}", dir, name) };
+            // Symlinks are listed, not followed
+            let is_dir = entry.file_type()?.is_dir();
+            if excludes.at_path(&path, Some(is_dir))?.is_excluded() {
+                continue;
+            }
+            if is_dir {
+                let nested_repo = entry.path().join(".git").exists();
+                if !nested_repo && !submodules.contains(&path) {
+                    Self::walk_untracked(root, &path, tracked, submodules, excludes, untracked)?;
+                }
+            } else if !tracked.contains_key(&path) {
+                untracked.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    /*
+        * Renders the project tree for the system prompt within the budget from [tree] in pprog.toml.
+        *
+        * Directories are expanded breadth first as long as their entries fit in `max_entries` and
+        * they are no deeper than `max_depth`.  Those holding changed or recently modified files go
+        * first and are expanded at any depth.  Anything left is collapsed to a single
+        * "dir/ (1234 files)" line.  A file counts as changed when it is untracked or its size or
+        * modification time differ from what the index recorded, like git's own stat check.
+        *
+        * Also returns the files and every directory holding one, relative to `root`, for the repo
+        * map and for watching.
+    */
+    fn render(root: &Path, config: &TreeConfig) -> Result<Rendered, anyhow::Error> {
+        let include = Self::patterns(&config.include);
+        let exclude = Self::patterns(&config.exclude);
+
+        let mut tree = TreeDir::default();
+        let mut files = Vec::new();
+        for entry in Self::list(root, config.submodules)? {
+            match entry {
+                Listed::File { path, stat } => {
+                    if !Self::included(&include, &exclude, &path) {
+                        continue;
+                    }
+                    let metadata = std::fs::symlink_metadata(root.join(&path)).ok();
+                    let modified = metadata.as_ref()
+                        .and_then(|metadata| metadata.modified().ok())
+                        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                        .map(|modified| modified.as_secs())
+                        .unwrap_or_default();
+                    let size = metadata.as_ref().map(|metadata| metadata.len());
+                    let changed = match (stat, size) {
+                        (Some(stat), Some(size)) => size as u32 != stat.size || modified as u32 != stat.mtime.secs,
+                        _ => true,
+                    };
+                    files.push((path, size, modified, changed));
+                }
+                Listed::Submodule(path) => {
+                    if Self::included(&include, &exclude, &path) {
+                        tree.insert(&path, TreeNode::Submodule, false);
+                    }
+                }
             }
-            current.insert(file_name.to_string(), TreeNode::File);
         }
 
+        let mut by_modified: Vec<usize> = (0..files.len()).collect();
+        by_modified.sort_by_key(|&i| Reverse(files[i].2));
+        let recent: HashSet<usize> = by_modified.into_iter().take(RECENT_FILES).collect();
+        let mut paths = Vec::with_capacity(files.len());
+        for (i, (path, size, _, changed)) in files.into_iter().enumerate() {
+            tree.insert(&path, TreeNode::File { size }, changed || recent.contains(&i));
+            paths.push(path);
+        }
+
+        let expanded = Self::expand(&tree, config);
         let mut result = String::from(".\n");
-        Self::build_tree_string(&tree, "", &mut result);
-        Ok(result)
+        Self::build_tree_string(&tree, "", &expanded, config.sizes, "", &mut result);
+        let mut dirs = vec![String::new()];
+        tree.dirs("", &mut dirs);
+        Ok(Rendered { tree: result, files: paths, dirs })
+    }
+
+    // Paths of the directories to show in full, the root always is
+    fn expand(tree: &TreeDir, config: &TreeConfig) -> HashSet<String> {
+        let mut expanded = HashSet::from([String::new()]);
+        let mut budget = config.max_entries.saturating_sub(tree.entries.len());
+        let mut dirs = vec![tree];
+        let mut queue = BinaryHeap::new();
+        Self::queue_subdirs(tree, "", 1, &mut dirs, &mut queue);
+
+        while let Some(Reverse((_, depth, path, index))) = queue.pop() {
+            let dir = dirs[index];
+            if (depth > config.max_depth && !dir.priority) || dir.entries.len() > budget {
+                continue;
+            }
+            budget -= dir.entries.len();
+            Self::queue_subdirs(dir, &path, depth + 1, &mut dirs, &mut queue);
+            expanded.insert(path);
+        }
+        expanded
+    }
+
+    fn queue_subdirs<'a>(
+        dir: &'a TreeDir,
+        path: &str,
+        depth: usize,
+        dirs: &mut Vec<&'a TreeDir>,
+        queue: &mut BinaryHeap<Reverse<(bool, usize, String, usize)>>,
+    ) {
+        for (name, node) in &dir.entries {
+            if let TreeNode::Dir(subdir) = node {
+                let subpath = Self::join(path, name);
+                queue.push(Reverse((!subdir.priority, depth, subpath, dirs.len())));
+                dirs.push(subdir);
+            }
+        }
+    }
+
+    fn included(include: &[gix::glob::Pattern], exclude: &[gix::glob::Pattern], path: &str) -> bool {
+        (include.is_empty() || Self::matches(include, path)) && !Self::matches(exclude, path)
+    }
+
+    fn patterns(globs: &[String]) -> Vec<gix::glob::Pattern> {
+        globs.iter().filter_map(gix::glob::parse).collect()
+    }
+
+    // Gitignore style matching against the path or any of its directories
+    fn matches(patterns: &[gix::glob::Pattern], path: &str) -> bool {
+        let mut candidates = vec![(path, false)];
+        candidates.extend(path.match_indices('/').map(|(end, _)| (&path[..end], true)));
+        candidates.iter().any(|(candidate, is_dir)| {
+            let basename = candidate.rfind('/').map(|slash| slash + 1);
+            patterns.iter().any(|pattern| pattern.matches_repo_relative_path(
+                (*candidate).into(),
+                basename,
+                Some(*is_dir),
+                gix::glob::pattern::Case::Sensitive,
+                gix::glob::wildmatch::Mode::NO_MATCH_SLASH_LITERAL,
+            ))
+        })
+    }
+
+    fn join(dir: &str, name: &str) -> String {
+        if dir.is_empty() { name.to_string() } else { format!("{}/{}", dir, name) }
+    }
+
+    fn format_size(bytes: u64) -> String {
+        match bytes {
+            0..=1023 => format!("{} B", bytes),
+            1024..=1_048_575 => format!("{:.1} KB", bytes as f64 / 1024.0),
+            _ => format!("{:.1} MB", bytes as f64 / 1_048_576.0),
+        }
     }
 
     fn build_tree_string(
-        tree: &BTreeMap<String, TreeNode>,
+        dir: &TreeDir,
+        path: &str,
+        expanded: &HashSet<String>,
+        sizes: bool,
         prefix: &str,
         result: &mut String,
     ) {
-        for (i, (name, node)) in tree.iter().enumerate() {
-            let is_last_entry = i == tree.len() - 1;
+        for (i, (name, node)) in dir.entries.iter().enumerate() {
+            let is_last_entry = i == dir.entries.len() - 1;
             let connector = if is_last_entry { "└── " } else { "├── " };
             let next_prefix = if is_last_entry { "    " } else { "│   " };
+            let subpath = Self::join(path, name);
 
-            result.push_str(&format!("{}{}{}
-", prefix, connector, name));
+            let label = match node {
+                TreeNode::File { size: Some(size) } if sizes => format!("{} ({})", name, Self::format_size(*size)),
+                TreeNode::File { .. } => name.clone(),
+                TreeNode::Submodule => format!("{} (submodule)", name),
+                TreeNode::Dir(_) if expanded.contains(&subpath) => name.clone(),
+                TreeNode::Dir(subdir) => format!("{}/ ({} file{})", name, subdir.files, if subdir.files == 1 { "" } else { "s" }),
+            };
+            result.push_str(&format!("{}{}{}\n", prefix, connector, label));
 
-            if let TreeNode::Dir(subtree) = node {
-                Self::build_tree_string(subtree, &format!("{}{}", prefix, next_prefix), result);
+            if let TreeNode::Dir(subdir) = node {
+                if expanded.contains(&subpath) {
+                    Self::build_tree_string(subdir, &subpath, expanded, sizes, &format!("{}{}", prefix, next_prefix), result);
+                }
             }
         }
     }
 }
 
-impl TreeNode {
-    fn as_dir_mut(&mut self) -> Option<&mut BTreeMap<String, TreeNode>> {
-        match self {
-            TreeNode::Dir(map) => Some(map),
-            _ => None,
+impl TreeDir {
+    fn dirs(&self, path: &str, dirs: &mut Vec<String>) {
+        for (name, node) in &self.entries {
+            if let TreeNode::Dir(subdir) = node {
+                let subpath = GitTree::join(path, name);
+                subdir.dirs(&subpath, dirs);
+                dirs.push(subpath);
+            }
+        }
+    }
+
+    fn insert(&mut self, path: &str, node: TreeNode, priority: bool) {
+        let is_file = matches!(node, TreeNode::File { .. });
+        let (dirs, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let mut current = self;
+        for part in dirs.split('/').filter(|part| !part.is_empty()) {
+            current.files += is_file as usize;
+            current.priority |= priority;
+            current = match current.entries.entry(part.to_string()).or_insert(TreeNode::Dir(TreeDir::default())) {
+                TreeNode::Dir(dir) => dir,
+                // Git never lists a path below a file or submodule
+                _ => return,
+            };
+        }
+        current.files += is_file as usize;
+        current.priority |= priority;
+        current.entries.insert(name.to_string(), node);
+    }
+}
+
+/*
+    * Keeps the rendered tree and repo map between requests so the system prompt stays the same,
+    * and provider side prompt caching keeps hitting, until files actually change.
+    *
+    * Every directory in the tree is watched, not recursively, so ignored directories like build
+    * output don't cost a watch or trigger a refresh.  Watching can fail, for example when a large
+    * repository runs out of inotify watches, in which case the tree is only refreshed when the
+    * model runs a tool that can write.
+*/
+pub struct TreeCache {
+    root: PathBuf,
+    config: TreeConfig,
+    tree: Option<String>,
+    map: String,
+    stale: Arc<AtomicBool>,
+    watcher: Option<RecommendedWatcher>,
+    watched: HashSet<PathBuf>,
+}
+
+impl TreeCache {
+    pub fn new(root: &Path, config: &TreeConfig) -> Self {
+        let stale = Arc::new(AtomicBool::new(false));
+        let flag = stale.clone();
+        let watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            // Reads don't change the tree
+            if !matches!(event, Ok(Event { kind: EventKind::Access(_), .. })) {
+                flag.store(true, Ordering::Relaxed);
+            }
+        });
+        let watcher = watcher
+            .map_err(|e| warn!("Not watching {} for changes: {}", root.display(), e))
+            .ok();
+        TreeCache {
+            root: root.to_path_buf(),
+            config: config.clone(),
+            tree: None,
+            map: String::new(),
+            stale,
+            watcher,
+            watched: HashSet::new(),
+        }
+    }
+
+    /// The tree as last rendered, rendering it if it hasn't been yet.
+    pub fn get(&mut self) -> Result<&str, anyhow::Error> {
+        if self.tree.is_none() {
+            self.render()?;
+        }
+        Ok(self.tree.as_deref().unwrap_or_default())
+    }
+
+    /// Repo map rendered along with the tree, empty when it is turned off.
+    pub fn map(&self) -> &str {
+        &self.map
+    }
+
+    /// Renders the tree again if files changed since it was last rendered.
+    pub fn refresh(&mut self) -> Result<(), anyhow::Error> {
+        if self.tree.is_none() || self.stale.swap(false, Ordering::Relaxed) {
+            self.render()?;
+        }
+        Ok(())
+    }
+
+    pub fn invalidate(&self) {
+        self.stale.store(true, Ordering::Relaxed);
+    }
+
+    fn render(&mut self) -> Result<(), anyhow::Error> {
+        self.stale.store(false, Ordering::Relaxed);
+        let rendered = GitTree::render(&self.root, &self.config)?;
+        self.map = match self.config.repo_map {
+            0 => String::new(),
+            max_lines => RepoMap::build(&self.root, &rendered.files, max_lines).unwrap_or_else(|e| {
+                warn!("Unable to build repo map: {}", e);
+                String::new()
+            }),
+        };
+        self.tree = Some(rendered.tree);
+        self.watch(rendered.dirs);
+        Ok(())
+    }
+
+    fn watch(&mut self, dirs: Vec<String>) {
+        let Some(watcher) = &mut self.watcher else {
+            return;
+        };
+        let dirs: HashSet<PathBuf> = dirs.iter().map(|dir| self.root.join(dir)).collect();
+        for dir in self.watched.difference(&dirs) {
+            // Removed directories are already gone from the watch list
+            let _ = watcher.unwatch(dir);
         }
+        for dir in dirs.difference(&self.watched) {
+            if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                warn!("Not watching {} for changes, the tree will refresh after writes only: {}", self.root.display(), e);
+                self.watcher = None;
+                self.watched.clear();
+                return;
+            }
+        }
+        self.watched = dirs;
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::git;
     use std::env;
 
     #[test]
@@ -130,5 +474,90 @@ mod tests {
         env::set_current_dir(original_dir)?;
         Ok(())
     }
-}
 
+    #[test]
+    fn test_lists_without_ignored_files_or_submodule_contents() -> Result<(), anyhow::Error> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        git::run(root, &["init", "-q"])?;
+        std::fs::create_dir_all(root.join("src"))?;
+        std::fs::create_dir_all(root.join("target/debug"))?;
+        std::fs::write(root.join(".gitignore"), "target/\n*.log\n")?;
+        std::fs::write(root.join("src/main.rs"), "fn main() {}\n")?;
+        std::fs::write(root.join("src/new.rs"), "")?;
+        std::fs::write(root.join("debug.log"), "")?;
+        std::fs::write(root.join("target/debug/pprog"), "")?;
+        git::run(root, &["add", ".gitignore", "src/main.rs"])?;
+        // A gitlink entry is all the index holds for a submodule
+        git::run(root, &["update-index", "--add", "--cacheinfo",
+            "160000,1111111111111111111111111111111111111111,vendor/lib"])?;
+
+        assert_eq!(GitTree::list_files(root)?, [".gitignore", "src/main.rs", "src/new.rs"]);
+        let tree = GitTree::render(root, &TreeConfig::default())?.tree;
+        assert!(tree.contains("└── lib (submodule)"));
+        assert!(!tree.contains("pprog") && !tree.contains("debug.log"));
+
+        let outside = tempfile::tempdir()?;
+        let error = GitTree::list_files(outside.path()).unwrap_err().to_string();
+        assert!(error.contains("is not the root of a git repository"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_budget_collapses_directories() -> Result<(), anyhow::Error> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        git::run(root, &["init", "-q"])?;
+        std::fs::create_dir_all(root.join("src/a/b/c"))?;
+        std::fs::create_dir_all(root.join("vendor"))?;
+        std::fs::create_dir_all(root.join("docs"))?;
+        for i in 0..30 {
+            std::fs::write(root.join(format!("vendor/pkg{}.js", i)), "")?;
+        }
+        std::fs::write(root.join("docs/guide.md"), "")?;
+        std::fs::write(root.join("src/a/b/c/deep.rs"), "fn deep() {}\n")?;
+        git::run(root, &["add", "."])?;
+
+        // Changed since it was staged, so its directories are expanded past max_depth
+        std::fs::write(root.join("src/a/b/c/deep.rs"), "fn deep() { changed(); }\n")?;
+        let config = TreeConfig {
+            max_depth: 1,
+            max_entries: 8,
+            exclude: vec!["*.md".to_string()],
+            ..TreeConfig::default()
+        };
+        assert_eq!(GitTree::render(root, &config)?.tree, concat!(
+            ".\n",
+            "├── src\n",
+            "│   └── a\n",
+            "│       └── b\n",
+            "│           └── c\n",
+            "│               └── deep.rs (25 B)\n",
+            "└── vendor/ (30 files)\n",
+        ));
+
+        let config = TreeConfig { include: vec!["vendor/pkg1*".to_string()], sizes: false, ..TreeConfig::default() };
+        let tree = GitTree::render(root, &config)?.tree;
+        assert_eq!(tree.lines().count(), 13, "{}", tree);
+        assert!(tree.contains("└── pkg19.js\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cached_tree_only_changes_on_refresh() -> Result<(), anyhow::Error> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        git::run(root, &["init", "-q"])?;
+        std::fs::write(root.join("a.rs"), "")?;
+
+        let mut cache = TreeCache::new(root, &TreeConfig::default());
+        assert!(cache.get()?.contains("a.rs"));
+        std::fs::write(root.join("b.rs"), "")?;
+        assert!(!cache.get()?.contains("b.rs"));
+
+        cache.invalidate();
+        cache.refresh()?;
+        assert!(cache.get()?.contains("b.rs"));
+        Ok(())
+    }
+}
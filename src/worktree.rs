@@ -0,0 +1,251 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde::Serialize;
+
+use crate::chat::Chat;
+use crate::git;
+
+/*
+    * A git worktree and branch for one session, so the model works away from the checked out
+    * branch.
+    *
+    * The worktree is created under .pprog/worktrees/<session> on branch pprog/<session>, starting
+    * at HEAD.  When the session is done its changes are committed to that branch and then merged,
+    * squashed into a single commit on the checked out branch, or discarded.  Either way the
+    * worktree and its branch are removed afterwards.  .pprog/ is added to .git/info/exclude so the
+    * worktrees don't show up as untracked files.
+    *
+    * A run that exits before finishing its session leaves the worktree behind.  At startup the
+    * ones without changes are removed and the latest one with changes is picked up again.
+*/
+pub struct Worktree {
+    repo_root: PathBuf,
+    session: String,
+    path: PathBuf,
+    branch: String,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct WorktreeInfo {
+    pub path: PathBuf,
+    pub branch: String,
+}
+
+const WORKTREES_DIR: &str = ".pprog/worktrees";
+const EXCLUDE_PATTERN: &str = "/.pprog/";
+
+impl Worktree {
+    pub fn create(repo_root: &Path, session: &str) -> Result<Self> {
+        Self::exclude_pprog_dir(repo_root)?;
+        let path = Self::session_path(repo_root, session);
+        let branch = format!("pprog/{}", session);
+        git::run(repo_root, &["worktree", "add", "-b", &branch, &path.to_string_lossy(), "HEAD"])
+            .map_err(|e| anyhow!("Unable to create worktree {}: {}", path.display(), e))?;
+        Ok(Worktree {
+            repo_root: repo_root.to_path_buf(),
+            session: session.to_string(),
+            path,
+            branch,
+        })
+    }
+
+    /// Cleans up the worktrees of earlier runs and returns the latest one with changes to carry
+    /// on in.  Older ones with changes are kept for the user to merge or remove by hand.
+    pub fn recover(repo_root: &Path) -> Result<Option<Self>> {
+        // Forget worktrees whose directory was deleted, their branches are checked out again below
+        git::run(repo_root, &["worktree", "prune"])?;
+        let registered = git::run(repo_root, &["worktree", "list", "--porcelain"])?;
+        let registered: Vec<&Path> = registered.lines()
+            .filter_map(|line| line.strip_prefix("worktree "))
+            .map(Path::new)
+            .collect();
+        // Directories git doesn't know as worktrees can't be used anymore
+        if let Ok(entries) = fs::read_dir(repo_root.join(WORKTREES_DIR)) {
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                if !registered.contains(&entry.path().as_path()) {
+                    fs::remove_dir_all(entry.path())?;
+                }
+            }
+        }
+
+        let branches = git::run(repo_root, &["for-each-ref", "--format=%(refname:short)", "refs/heads/pprog/"])?;
+        let mut sessions: Vec<&str> = branches.lines().filter_map(|branch| branch.strip_prefix("pprog/")).collect();
+        sessions.sort_by_key(|session| Chat::session_start(session));
+
+        let mut latest = None;
+        for session in sessions.into_iter().rev() {
+            let worktree = Worktree {
+                repo_root: repo_root.to_path_buf(),
+                session: session.to_string(),
+                path: Self::session_path(repo_root, session),
+                branch: format!("pprog/{}", session),
+            };
+            if !worktree.path.exists() {
+                git::run(repo_root, &["worktree", "add", &worktree.path.to_string_lossy(), &worktree.branch])?;
+            }
+            if !worktree.has_changes()? {
+                worktree.remove()?;
+            } else if latest.is_none() {
+                latest = Some(worktree);
+            } else {
+                warn!("Keeping worktree {} of an earlier session, it has changes that weren't merged", worktree.path.display());
+            }
+        }
+        Ok(latest)
+    }
+
+    /// Where the worktree of a session lives, whether or not it exists
+    pub fn session_path(repo_root: &Path, session: &str) -> PathBuf {
+        repo_root.join(WORKTREES_DIR).join(session)
+    }
+
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn info(&self) -> WorktreeInfo {
+        WorktreeInfo {
+            path: self.path.clone(),
+            branch: self.branch.clone(),
+        }
+    }
+
+    /// Merges the session's branch into the checked out branch with a merge commit.
+    pub fn merge(&self, message: &str) -> Result<()> {
+        if self.commit_changes(message)? {
+            let result = git::run(&self.repo_root, &["merge", "--no-ff", "-m", message, &self.branch]);
+            if let Err(e) = result {
+                // Leave the user's branch as it was, the session's branch is kept for another try
+                let _ = git::run(&self.repo_root, &["merge", "--abort"]);
+                return Err(anyhow!("Unable to merge {}: {}", self.branch, e));
+            }
+        }
+        self.remove()
+    }
+
+    /// Applies the session's changes to the checked out branch as a single commit.
+    pub fn squash(&self, message: &str) -> Result<()> {
+        if self.commit_changes(message)? {
+            let result = git::run(&self.repo_root, &["merge", "--squash", &self.branch])
+                .and_then(|_| git::run(&self.repo_root, &["commit", "-m", message]));
+            if let Err(e) = result {
+                let _ = git::run(&self.repo_root, &["reset", "--merge"]);
+                return Err(anyhow!("Unable to squash {}: {}", self.branch, e));
+            }
+        }
+        self.remove()
+    }
+
+    pub fn discard(&self) -> Result<()> {
+        self.remove()
+    }
+
+    /// Commits whatever is left in the worktree, returns whether the branch is ahead of the checked out branch.
+    fn commit_changes(&self, message: &str) -> Result<bool> {
+        git::run(&self.path, &["add", "-A"])?;
+        if !git::run(&self.path, &["status", "--porcelain"])?.trim().is_empty() {
+            git::run(&self.path, &["commit", "-m", message])?;
+        }
+        self.ahead()
+    }
+
+    fn has_changes(&self) -> Result<bool> {
+        Ok(!git::run(&self.path, &["status", "--porcelain"])?.trim().is_empty() || self.ahead()?)
+    }
+
+    fn ahead(&self) -> Result<bool> {
+        let range = format!("HEAD..{}", self.branch);
+        let ahead = git::run(&self.repo_root, &["rev-list", "--count", &range])?;
+        Ok(ahead.trim() != "0")
+    }
+
+    fn remove(&self) -> Result<()> {
+        git::run(&self.repo_root, &["worktree", "remove", "--force", &self.path.to_string_lossy()])?;
+        git::run(&self.repo_root, &["branch", "-D", &self.branch])?;
+        Ok(())
+    }
+
+    fn exclude_pprog_dir(repo_root: &Path) -> Result<()> {
+        let repo = gix::open(repo_root)?;
+        let exclude = repo.common_dir().join("info").join("exclude");
+        let contents = fs::read_to_string(&exclude).unwrap_or_default();
+        if contents.lines().any(|line| line.trim() == EXCLUDE_PATTERN) {
+            return Ok(());
+        }
+        if let Some(parent) = exclude.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let separator = if contents.is_empty() || contents.ends_with('\n') { "" } else { "\n" };
+        fs::write(&exclude, format!("{}{}{}\n", contents, separator, EXCLUDE_PATTERN))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::GitTree;
+
+    fn repo() -> (tempfile::TempDir, PathBuf) {
+        let dir = git::test_repo(&[("main.rs", "fn main() {}\n")]);
+        let root = dir.path().canonicalize().unwrap();
+        (dir, root)
+    }
+
+    #[test]
+    fn test_squash_lands_session_as_one_commit() {
+        let (_dir, root) = repo();
+        let worktree = Worktree::create(&root, "1").unwrap();
+        fs::write(worktree.path().join("main.rs"), "fn main() { println!(\"hi\"); }\n").unwrap();
+        fs::write(worktree.path().join("util.rs"), "pub fn util() {}\n").unwrap();
+
+        // The checked out branch doesn't see the session's changes until they are squashed
+        assert_eq!(fs::read_to_string(root.join("main.rs")).unwrap(), "fn main() {}\n");
+        assert!(GitTree::list_files(&root).unwrap().iter().all(|f| !f.starts_with(".pprog")));
+
+        worktree.squash("Print a greeting").unwrap();
+        assert!(root.join("util.rs").exists());
+        let log = git::run(&root, &["log", "--format=%s"]).unwrap();
+        assert_eq!(log.lines().collect::<Vec<_>>(), ["Print a greeting", "Initial commit"]);
+        assert!(git::run(&root, &["branch", "--list", "pprog/*"]).unwrap().trim().is_empty());
+        assert!(!root.join(WORKTREES_DIR).join("1").exists());
+    }
+
+    #[test]
+    fn test_discard_leaves_branch_untouched() {
+        let (_dir, root) = repo();
+        let worktree = Worktree::create(&root, "2").unwrap();
+        fs::write(worktree.path().join("main.rs"), "broken").unwrap();
+
+        worktree.discard().unwrap();
+        assert_eq!(fs::read_to_string(root.join("main.rs")).unwrap(), "fn main() {}\n");
+        assert!(git::run(&root, &["status", "--porcelain"]).unwrap().trim().is_empty());
+    }
+
+    #[test]
+    fn test_recover_carries_on_in_the_latest_session_with_changes() {
+        let (_dir, root) = repo();
+        Worktree::create(&root, "1").unwrap();
+        for session in ["2", "3"] {
+            let worktree = Worktree::create(&root, session).unwrap();
+            fs::write(worktree.path().join("util.rs"), "pub fn util() {}\n").unwrap();
+        }
+        // Directory deleted by hand, and one git doesn't know
+        fs::remove_dir_all(Worktree::create(&root, "4").unwrap().path()).unwrap();
+        fs::create_dir_all(root.join(WORKTREES_DIR).join("5")).unwrap();
+
+        let worktree = Worktree::recover(&root).unwrap().unwrap();
+        assert_eq!(worktree.session(), "3");
+        assert!(worktree.path().join("util.rs").exists());
+        let branches = git::run(&root, &["branch", "--list", "--format=%(refname:short)", "pprog/*"]).unwrap();
+        assert_eq!(branches.lines().collect::<Vec<_>>(), ["pprog/2", "pprog/3"]);
+        assert!(!root.join(WORKTREES_DIR).join("1").exists());
+        assert!(!root.join(WORKTREES_DIR).join("5").exists());
+    }
+}